@@ -0,0 +1,109 @@
+//! Waiting on a child and its pipes from tokio, with the `tokio` feature.
+//!
+//! The child is watched through a pidfd registered with the reactor, so
+//! no thread blocks in `waitpid(2)`.
+
+use crate::{cgroup, Child, Error, ExitStatus};
+use std::os::fd::OwnedFd;
+use tokio::io::unix::AsyncFd;
+use tokio::net::unix::pipe;
+
+/// A `Child` to wait on asynchronously, from `Wrap::spawn_async`.
+pub struct AsyncChild {
+    child: Child,
+    pidfd: AsyncFd<OwnedFd>,
+    /// Pipe to stdin, with `Stdio::Piped`.
+    pub stdin: Option<pipe::Sender>,
+    /// Pipe from stdout, with `Stdio::Piped`.
+    pub stdout: Option<pipe::Receiver>,
+    /// Pipe from stderr, with `Stdio::Piped`.
+    pub stderr: Option<pipe::Receiver>,
+}
+
+impl AsyncChild {
+    /// Register `child` and its pipes with the reactor of the current
+    /// runtime, which must have I/O enabled.
+    pub fn new(mut child: Child) -> Result<Self, Error> {
+        let pidfd = rustix::process::pidfd_open(child.pid, rustix::process::PidfdFlags::empty())
+            .map_err(|e| Error::OsErrno(e.raw_os_error()))?;
+        Ok(Self {
+            pidfd: AsyncFd::new(pidfd)?,
+            stdin: child
+                .take_stdin()
+                .map(pipe::Sender::from_owned_fd)
+                .transpose()?,
+            stdout: child
+                .take_stdout()
+                .map(pipe::Receiver::from_owned_fd)
+                .transpose()?,
+            stderr: child
+                .take_stderr()
+                .map(pipe::Receiver::from_owned_fd)
+                .transpose()?,
+            child,
+        })
+    }
+
+    /// Process id of the child, in the pid namespace of the caller.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Cgroup of the child, set up by `Wrap::cgroup`.
+    pub fn cgroup(&self) -> Option<&cgroup::Cgroup> {
+        self.child.cgroup()
+    }
+
+    /// Wait for the child to exit, and collect its status.
+    ///
+    /// Pipes are left open, so close `stdin` first if the child reads it
+    /// until the end.
+    pub async fn wait(&mut self) -> Result<ExitStatus, Error> {
+        // A pidfd stays readable once the process has exited, so reaping
+        // it does not block
+        let _ready = self.pidfd.readable().await?;
+        self.child.wait()
+    }
+
+    /// The blocking handle of the child.
+    pub fn into_inner(self) -> Child {
+        self.child
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::Stdio, Wrap};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn wait_and_pipes() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut binding = Wrap::new_cmd("/bin/sh");
+            let mut child = binding
+                .args(["-c", "read a; echo $a-out; echo err >&2; exit 3"])
+                .stdin(Stdio::Piped)
+                .stdout(Stdio::Piped)
+                .stderr(Stdio::Piped)
+                .spawn_async()
+                .unwrap();
+
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"in\n").await.unwrap();
+            drop(stdin);
+            let mut out = String::new();
+            let mut err = String::new();
+            let mut stdout = child.stdout.take().unwrap();
+            let mut stderr = child.stderr.take().unwrap();
+            stdout.read_to_string(&mut out).await.unwrap();
+            stderr.read_to_string(&mut err).await.unwrap();
+            assert_eq!(out, "in-out\n");
+            assert_eq!(err, "err\n");
+            assert_eq!(child.wait().await.unwrap().code(), Some(3));
+        });
+    }
+}
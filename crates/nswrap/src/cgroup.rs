@@ -0,0 +1,416 @@
+//! Control of cgroup v2 membership and resources, see `cgroups(7)`.
+//!
+//! An unprivileged user can only manage a subtree delegated to it, like
+//! the cgroup of a systemd user service with `Delegate=yes`. `Wrap::cgroup`
+//! creates a child cgroup per container in such a subtree, and moves the
+//! child process into it before anything else.
+//!
+//! The processes of a container can be frozen, thawed and killed at once
+//! through its cgroup. Without one, the functions at the end of this
+//! module signal each process of the container instead.
+
+use crate::{config::Resources, error::Error};
+use getset::CopyGetters;
+use nix::sys::signal::Signal;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long `Cgroup::freeze` waits for the processes to stop.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A cgroup in the unified hierarchy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// Usage of a cgroup, `None` where the controller is not enabled.
+#[derive(CopyGetters, Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get_copy = "pub")]
+pub struct Stats {
+    /// Memory in use in bytes, from `memory.current`.
+    memory_current: Option<u64>,
+    /// Cpu time in microseconds, from `cpu.stat`.
+    cpu_usage_usec: Option<u64>,
+    /// Cpu time in user mode in microseconds, from `cpu.stat`.
+    cpu_user_usec: Option<u64>,
+    /// Cpu time in kernel mode in microseconds, from `cpu.stat`.
+    cpu_system_usec: Option<u64>,
+    /// Number of processes, from `pids.current`.
+    pids_current: Option<u64>,
+}
+
+impl Cgroup {
+    /// Refer to the cgroup directory at `path`, which may not exist yet.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The cgroup of the calling process, usually the root of the
+    /// subtree delegated to it.
+    ///
+    /// Fails if the unified hierarchy is not mounted.
+    pub fn current() -> Result<Self, Error> {
+        Self::of_process_path("/proc/self/cgroup")
+    }
+
+    /// The cgroup of the process `pid`.
+    pub fn of_process(pid: i32) -> Result<Self, Error> {
+        Self::of_process_path(&format!("/proc/{}/cgroup", pid))
+    }
+
+    fn of_process_path(proc_cgroup: &str) -> Result<Self, Error> {
+        let no_cgroup2 = || Error::Cgroup("cgroup v2 is not mounted".into());
+        let cgroup = std::fs::read_to_string(proc_cgroup)?;
+        let path = cgroup
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(no_cgroup2)?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let mount = crate::inspect::parse_mountinfo(&mountinfo)?
+            .into_iter()
+            .find(|m| m.fs_type() == "cgroup2")
+            .ok_or_else(no_cgroup2)?;
+        let relative = Path::new(path)
+            .strip_prefix(mount.root())
+            .map_err(|_| no_cgroup2())?;
+        Ok(Self::new(mount.mount_point().join(relative)))
+    }
+
+    /// Child cgroup `name`, not created yet.
+    pub fn child(&self, name: &str) -> Self {
+        Self::new(self.path.join(name))
+    }
+
+    /// Directory of the cgroup.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the cgroup directory exists.
+    pub fn exists(&self) -> bool {
+        self.path.join("cgroup.procs").exists()
+    }
+
+    /// Create the cgroup directory, if it does not exist.
+    pub fn create(&self) -> Result<(), Error> {
+        match std::fs::create_dir(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the cgroup, which must have no process and no child left.
+    pub fn remove(&self) -> Result<(), Error> {
+        Ok(std::fs::remove_dir(&self.path)?)
+    }
+
+    /// Enable the controllers `resources` needs in the parent, and write
+    /// its limits.
+    ///
+    /// Enabling a controller fails with `EBUSY` while the parent has
+    /// processes of its own, unless it is the root cgroup.
+    pub fn apply(&self, resources: &Resources) -> Result<(), Error> {
+        let parent = self
+            .path
+            .parent()
+            .ok_or_else(|| Error::Cgroup("the root cgroup has no limits".into()))?;
+        let available = std::fs::read_to_string(parent.join("cgroup.controllers"))?;
+        let enabled = std::fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+        for controller in resources.controllers() {
+            if enabled.split_whitespace().any(|c| c == controller) {
+                continue;
+            }
+            if !available.split_whitespace().any(|c| c == controller) {
+                return Err(Error::Cgroup(format!(
+                    "controller {} is not available in {}",
+                    controller,
+                    parent.display()
+                )));
+            }
+            Self::write_file(
+                &parent.join("cgroup.subtree_control"),
+                &format!("+{}", controller),
+            )?;
+        }
+        for (file, value) in resources.files() {
+            Self::write_file(&self.path.join(file), &value)?;
+        }
+        Ok(())
+    }
+
+    /// Move the process `pid` into the cgroup, 0 being the caller.
+    pub fn add_process(&self, pid: i32) -> Result<(), Error> {
+        Self::write_file(&self.path.join("cgroup.procs"), &pid.to_string())
+    }
+
+    /// Processes in the cgroup, in the pid namespace of the caller.
+    pub fn processes(&self) -> Result<Vec<i32>, Error> {
+        let procs = std::fs::read_to_string(self.path.join("cgroup.procs"))?;
+        Ok(procs.lines().filter_map(|pid| pid.parse().ok()).collect())
+    }
+
+    /// Freeze the processes of the cgroup and its children, and wait
+    /// until they are stopped.
+    pub fn freeze(&self) -> Result<(), Error> {
+        Self::write_file(&self.path.join("cgroup.freeze"), "1")?;
+        let deadline = Instant::now() + FREEZE_TIMEOUT;
+        while !self.is_frozen()? {
+            if Instant::now() >= deadline {
+                return Err(Error::Cgroup(format!(
+                    "{}: timed out freezing",
+                    self.path.display()
+                )));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Let the processes of a frozen cgroup run again.
+    pub fn thaw(&self) -> Result<(), Error> {
+        Self::write_file(&self.path.join("cgroup.freeze"), "0")
+    }
+
+    /// Whether the processes of the cgroup are frozen, from
+    /// `cgroup.events`.
+    pub fn is_frozen(&self) -> Result<bool, Error> {
+        let events = std::fs::read_to_string(self.path.join("cgroup.events"))?;
+        Ok(events.lines().any(|line| line == "frozen 1"))
+    }
+
+    /// Kill the processes of the cgroup and its children.
+    ///
+    /// Uses `cgroup.kill` since Linux 5.14, or else sends `SIGKILL` to
+    /// each process of the cgroup itself.
+    pub fn kill(&self) -> Result<(), Error> {
+        let kill = self.path.join("cgroup.kill");
+        if kill.exists() {
+            return Self::write_file(&kill, "1");
+        }
+        signal_all(&self.processes()?, Signal::SIGKILL)
+    }
+
+    /// Read the usage of the cgroup.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let read = |file: &str| match std::fs::read_to_string(self.path.join(file)) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let number = |content: Option<String>| content.and_then(|c| c.trim().parse().ok());
+        let cpu_stat = read("cpu.stat")?.unwrap_or_default();
+        let cpu = |key: &str| {
+            cpu_stat.lines().find_map(|line| {
+                let (k, v) = line.split_once(' ')?;
+                (k == key).then(|| v.parse().ok())?
+            })
+        };
+        Ok(Stats {
+            memory_current: number(read("memory.current")?),
+            cpu_usage_usec: cpu("usage_usec"),
+            cpu_user_usec: cpu("user_usec"),
+            cpu_system_usec: cpu("system_usec"),
+            pids_current: number(read("pids.current")?),
+        })
+    }
+
+    /// Write an interface file, which must exist.
+    fn write_file(path: &Path, value: &str) -> Result<(), Error> {
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+            std::io::Write::write_all(&mut file, value.as_bytes())
+        };
+        write().map_err(|e| Error::Cgroup(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Freeze the container whose first process is `pid`, through `cgroup`
+/// or else by stopping each of its processes with `SIGSTOP`.
+///
+/// The processes of the container are those of `inspect::container_processes`.
+pub fn freeze_container(pid: i32, cgroup: Option<&Cgroup>) -> Result<(), Error> {
+    match cgroup {
+        Some(cgroup) => cgroup.freeze(),
+        None => signal_all(&crate::inspect::container_processes(pid)?, Signal::SIGSTOP),
+    }
+}
+
+/// Undo `freeze_container`.
+pub fn thaw_container(pid: i32, cgroup: Option<&Cgroup>) -> Result<(), Error> {
+    match cgroup {
+        Some(cgroup) => cgroup.thaw(),
+        None => signal_all(&crate::inspect::container_processes(pid)?, Signal::SIGCONT),
+    }
+}
+
+/// Whether the container is frozen by `freeze_container`.
+///
+/// Without a cgroup, only the first process is checked.
+pub fn is_container_frozen(pid: i32, cgroup: Option<&Cgroup>) -> Result<bool, Error> {
+    match cgroup {
+        Some(cgroup) => cgroup.is_frozen(),
+        None => {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+            let state = stat
+                .rsplit_once(')')
+                .and_then(|(_, s)| s.split_whitespace().next());
+            Ok(state == Some("T"))
+        }
+    }
+}
+
+/// Kill every process of the container whose first process is `pid`,
+/// through `cgroup` or else with `SIGKILL` to each process.
+pub fn kill_container(pid: i32, cgroup: Option<&Cgroup>) -> Result<(), Error> {
+    match cgroup {
+        Some(cgroup) => cgroup.kill(),
+        None => signal_all(&crate::inspect::container_processes(pid)?, Signal::SIGKILL),
+    }
+}
+
+/// Send `signal` to `pids`, ignoring processes that are already gone.
+fn signal_all(pids: &[i32], signal: Signal) -> Result<(), Error> {
+    for pid in pids {
+        match nix::sys::signal::kill(nix::unistd::Pid::from_raw(*pid), signal) {
+            Ok(()) | Err(nix::errno::Errno::ESRCH) => (),
+            Err(e) => return Err(Error::OsErrno(e as i32)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, Wrap};
+
+    #[test]
+    fn resources() {
+        let mut resources = Resources::default();
+        assert!(resources.controllers().is_empty());
+        resources
+            .set_memory_max(Some(1 << 20))
+            .set_cpu_quota(Some(50000))
+            .set_pids_max(Some(10));
+        resources.io_max_mut().push(
+            config::IoLimitBuilder::default()
+                .major(8)
+                .minor(0)
+                .rbps(Some(1024))
+                .wbps(None)
+                .riops(None)
+                .wiops(Some(100))
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(resources.controllers(), ["memory", "cpu", "pids", "io"]);
+        assert_eq!(
+            resources.files(),
+            [
+                ("memory.max", "1048576".to_string()),
+                ("cpu.max", "50000 100000".to_string()),
+                ("pids.max", "10".to_string()),
+                (
+                    "io.max",
+                    "8:0 rbps=1024 wbps=max riops=max wiops=100".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrap_cgroup() {
+        // Needs a writable cgroup v2 hierarchy
+        let Ok(parent) = Cgroup::current() else {
+            return;
+        };
+        let cgroup = parent.child("nswrap.test.cgroup");
+        if cgroup.create().is_err() {
+            return;
+        }
+        cgroup.remove().unwrap();
+
+        let inner = cgroup.clone();
+        let cb = move || {
+            let procs = inner.processes().unwrap();
+            match procs == [nix::unistd::getpid().as_raw()] {
+                true => 0,
+                false => 1,
+            }
+        };
+        let mut wrap = Wrap::new();
+        unsafe { wrap.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .cgroup(cgroup.clone(), Resources::default());
+        let mut child = wrap.spawn().unwrap();
+        assert!(child.stats().unwrap().cpu_usage_usec().is_some());
+        assert_eq!(child.wait().unwrap().code().unwrap(), 0);
+        // Removed once the child is reaped
+        assert!(!cgroup.exists());
+
+        // And if the child could not be spawned
+        let mut wrap = Wrap::new();
+        unsafe { wrap.callback(|| 0) }
+            .unshare(config::NamespaceType::User)
+            .sysctl("kernel.pid_max", "1")
+            .cgroup(cgroup.clone(), Resources::default());
+        assert!(matches!(wrap.spawn(), Err(Error::Sysctl(_))));
+        assert!(!cgroup.exists());
+
+        // Controllers that are not available are reported
+        let mut resources = Resources::default();
+        resources.set_pids_max(Some(10));
+        let available = std::fs::read_to_string(parent.path().join("cgroup.controllers")).unwrap();
+        if !available.contains("pids") {
+            let mut wrap = Wrap::new();
+            unsafe { wrap.callback(|| 0) }.cgroup(cgroup.clone(), resources);
+            assert!(matches!(wrap.spawn(), Err(Error::Cgroup(_))));
+            let _ = cgroup.remove();
+        }
+    }
+
+    #[test]
+    fn freeze_and_kill() {
+        let wait_until = |cond: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + FREEZE_TIMEOUT;
+            while !cond() {
+                assert!(Instant::now() < deadline);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        let gone = |pid: i32| match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat.contains(") Z "),
+            Err(_) => true,
+        };
+        // With a cgroup if a writable cgroup v2 hierarchy is available
+        let cgroup = Cgroup::current()
+            .map(|c| c.child("nswrap.test.freeze"))
+            .ok()
+            .filter(|c| c.create().and_then(|_| c.remove()).is_ok());
+        for cgroup in [None, cgroup] {
+            let mut wrap = Wrap::new_cmd("/bin/sh");
+            wrap.args(["-c", "sleep 30 & wait"])
+                .unshare(config::NamespaceType::User);
+            if let Some(cgroup) = &cgroup {
+                wrap.cgroup(cgroup.clone(), Resources::default());
+            }
+            let mut child = wrap.spawn().unwrap();
+            let pid = child.id() as i32;
+            let frozen = || is_container_frozen(pid, child.cgroup()).unwrap();
+
+            child.freeze().unwrap();
+            wait_until(&frozen);
+            child.thaw().unwrap();
+            wait_until(&|| !frozen());
+
+            wait_until(&|| crate::inspect::container_processes(pid).unwrap().len() == 2);
+            let sleep = crate::inspect::container_processes(pid).unwrap()[1];
+            child.kill_all().unwrap();
+            assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+            wait_until(&|| gone(sleep));
+        }
+    }
+}
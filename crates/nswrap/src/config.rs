@@ -1,8 +1,18 @@
-use getset::{CopyGetters, Getters, Setters};
+use crate::error::Error;
+use crate::util::CloneFlags;
+use getset::{CopyGetters, Getters, MutGetters, Setters};
+use nix::mount::MsFlags;
+use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NamespaceType {
     Mount,
     Cgroup,
@@ -15,12 +25,91 @@ pub enum NamespaceType {
     Time,
 }
 
-#[derive(Default, Clone, Copy)]
+impl NamespaceType {
+    /// Order to join namespaces one at a time.
+    ///
+    /// The user namespace comes first, so that the capabilities gained in
+    /// it allow joining namespaces it owns, and the mount namespace comes
+    /// last, as joining it changes the root directory.
+    pub(crate) const JOIN_ORDER: [NamespaceType; 8] = [
+        NamespaceType::User,
+        NamespaceType::Cgroup,
+        NamespaceType::Ipc,
+        NamespaceType::Uts,
+        NamespaceType::Network,
+        NamespaceType::Pid,
+        NamespaceType::Time,
+        NamespaceType::Mount,
+    ];
+
+    /// `CLONE_NEW*` flag of the namespace type.
+    pub(crate) fn clone_flag(self) -> libc::c_int {
+        match self {
+            NamespaceType::Mount => libc::CLONE_NEWNS,
+            NamespaceType::Cgroup => libc::CLONE_NEWCGROUP,
+            NamespaceType::Uts => libc::CLONE_NEWUTS,
+            NamespaceType::Ipc => libc::CLONE_NEWIPC,
+            NamespaceType::User => libc::CLONE_NEWUSER,
+            NamespaceType::Pid => libc::CLONE_NEWPID,
+            NamespaceType::Network => libc::CLONE_NEWNET,
+            NamespaceType::Time => libc::CLONE_NEWTIME,
+        }
+    }
+
+    /// Namespace type of a `CLONE_NEW*` flag.
+    pub(crate) fn from_clone_flag(flag: libc::c_int) -> Option<Self> {
+        NamespaceType::JOIN_ORDER
+            .into_iter()
+            .find(|typ| typ.clone_flag() == flag)
+    }
+
+    /// Name of the namespace in `/proc/<pid>/ns`.
+    pub(crate) fn proc_name(self) -> &'static str {
+        match self {
+            NamespaceType::Mount => "mnt",
+            NamespaceType::Cgroup => "cgroup",
+            NamespaceType::Uts => "uts",
+            NamespaceType::Ipc => "ipc",
+            NamespaceType::User => "user",
+            NamespaceType::Pid => "pid",
+            NamespaceType::Network => "net",
+            NamespaceType::Time => "time",
+        }
+    }
+}
+
+/// Process to join the namespaces of, see `Wrap::join`.
+#[derive(Clone, Copy, Debug)]
+pub enum JoinTarget {
+    /// Process id in the pid namespace of the caller.
+    Pid(i32),
+    /// Process file descriptor from `pidfd_open(2)` or `clone(2)`.
+    Pidfd(std::os::fd::RawFd),
+}
+
+impl JoinTarget {
+    /// Process id of the target, read from `fdinfo` for a pidfd.
+    pub(crate) fn pid(self) -> Result<i32, Error> {
+        match self {
+            JoinTarget::Pid(pid) => Ok(pid),
+            JoinTarget::Pidfd(fd) => {
+                let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))?;
+                info.lines()
+                    .find_map(|l| l.strip_prefix("Pid:"))
+                    .and_then(|pid| pid.trim().parse().ok())
+                    .filter(|pid| *pid > 0)
+                    .ok_or(Error::OsErrno(libc::ESRCH))
+            }
+        }
+    }
+}
+
+#[derive(Default, Clone)]
 pub enum NamespaceItem {
     #[default]
     None,
     Unshare,
-    Enter(std::os::fd::RawFd),
+    Enter(std::sync::Arc<crate::ns::Namespace>),
 }
 
 #[derive(Getters, Setters, CopyGetters, Default, Clone)]
@@ -35,7 +124,37 @@ pub struct NamespaceSet {
     //pub(crate) time: NamespaceItem,
 }
 
-#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+impl NamespaceSet {
+    fn items(&self) -> [(&NamespaceItem, CloneFlags); 7] {
+        [
+            (&self.user, CloneFlags::NEWUSER),
+            (&self.mount, CloneFlags::NEWNS),
+            (&self.cgroup, CloneFlags::NWCGROUP),
+            (&self.uts, CloneFlags::NEWUTS),
+            (&self.ipc, CloneFlags::NEWIPC),
+            (&self.pid, CloneFlags::NEWPID),
+            (&self.network, CloneFlags::NEWNET),
+        ]
+    }
+
+    /// Whether no namespace is set.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items()
+            .iter()
+            .all(|(ns, _)| matches!(ns, NamespaceItem::None))
+    }
+
+    /// `CLONE_NEW*` flags of namespaces set to `NamespaceItem::Unshare`.
+    pub(crate) fn clone_flags(&self) -> CloneFlags {
+        self.items()
+            .iter()
+            .filter(|(ns, _)| matches!(ns, NamespaceItem::Unshare))
+            .fold(CloneFlags::empty(), |flags, (_, flag)| flags | *flag)
+    }
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Root {
     #[getset(get = "pub", set = "pub")]
     path: PathBuf,
@@ -44,47 +163,249 @@ pub struct Root {
     readonly: Option<bool>,
 }
 
-#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+#[derive(
+    Builder,
+    Getters,
+    Setters,
+    CopyGetters,
+    Default,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(default)]
 pub struct Mount {
     #[getset(get = "pub", set = "pub")]
     destination: PathBuf,
     // Path values for bind mounts are either absolute or relative to the
     // bundle. A mount is a bind mount if it has either bind or rbind in the options.
+    /// Type of the file system, like `tmpfs` or `proc`.
+    ///
+    /// Some values are not file system types, they are handled like
+    /// `bwrap` options of the same name:
+    /// - `dev` creates a minimal `/dev`.
+    /// - `dir` creates a directory.
+    /// - `symlink` creates a symbolic link to `source`.
     #[getset(get = "pub", set = "pub")]
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
     typ: Option<String>,
     #[getset(get = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     source: Option<PathBuf>,
+    /// Options are the same as `mount(8)`, with propagation options like
+    /// `rshared` or `private` applied after the mount is created.
     #[getset(get = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<Vec<String>>,
 }
 
-#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+/// Mount flags, propagation and filesystem data parsed from `Mount::options`.
+#[derive(Clone)]
+pub(crate) struct MountOptions {
+    pub(crate) flags: MsFlags,
+    pub(crate) propagation: Option<MsFlags>,
+    pub(crate) data: String,
+}
+
+impl Mount {
+    /// Whether this mount is a bind mount.
+    pub fn is_bind(&self) -> bool {
+        self.typ.as_deref() == Some("bind")
+            || self
+                .options
+                .iter()
+                .flatten()
+                .any(|o| o == "bind" || o == "rbind")
+    }
+
+    pub(crate) fn parse_options(&self) -> MountOptions {
+        let mut opts = MountOptions {
+            flags: MsFlags::empty(),
+            propagation: None,
+            data: String::new(),
+        };
+        let mut data = Vec::new();
+        for o in self.options.iter().flatten() {
+            let (set, flag) = match o.as_str() {
+                "defaults" => continue,
+                "ro" => (true, MsFlags::MS_RDONLY),
+                "rw" => (false, MsFlags::MS_RDONLY),
+                "nosuid" => (true, MsFlags::MS_NOSUID),
+                "suid" => (false, MsFlags::MS_NOSUID),
+                "nodev" => (true, MsFlags::MS_NODEV),
+                "dev" => (false, MsFlags::MS_NODEV),
+                "noexec" => (true, MsFlags::MS_NOEXEC),
+                "exec" => (false, MsFlags::MS_NOEXEC),
+                "sync" => (true, MsFlags::MS_SYNCHRONOUS),
+                "async" => (false, MsFlags::MS_SYNCHRONOUS),
+                "dirsync" => (true, MsFlags::MS_DIRSYNC),
+                "remount" => (true, MsFlags::MS_REMOUNT),
+                "mand" => (true, MsFlags::MS_MANDLOCK),
+                "nomand" => (false, MsFlags::MS_MANDLOCK),
+                "atime" => (false, MsFlags::MS_NOATIME),
+                "noatime" => (true, MsFlags::MS_NOATIME),
+                "diratime" => (false, MsFlags::MS_NODIRATIME),
+                "nodiratime" => (true, MsFlags::MS_NODIRATIME),
+                "relatime" => (true, MsFlags::MS_RELATIME),
+                "norelatime" => (false, MsFlags::MS_RELATIME),
+                "strictatime" => (true, MsFlags::MS_STRICTATIME),
+                "nostrictatime" => (false, MsFlags::MS_STRICTATIME),
+                "bind" => (true, MsFlags::MS_BIND),
+                "rbind" => (true, MsFlags::MS_BIND | MsFlags::MS_REC),
+                other => match other
+                    .strip_prefix('r')
+                    .unwrap_or(other)
+                    .parse::<MountPropagation>()
+                {
+                    Ok(p) => {
+                        opts.propagation = Some(p.flags(other.starts_with('r')));
+                        continue;
+                    }
+                    Err(_) => {
+                        data.push(other);
+                        continue;
+                    }
+                },
+            };
+            opts.flags.set(flag, set);
+        }
+        opts.data = data.join(",");
+        opts
+    }
+}
+
+/// Propagation type of a mount point, see `mount_namespaces(7)`.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountPropagation {
+    /// Mount and unmount events propagate in both directions between
+    /// the container and its peers.
+    ///
+    /// Note that the kernel turns shared mounts into slave mounts when
+    /// a mount namespace is created by an unprivileged user namespace,
+    /// so events only reach the host when it owns the peer group.
+    Shared,
+    /// Mount and unmount events propagate from the host into the
+    /// container, but not back.
+    #[default]
+    Slave,
+    /// No mount and unmount events propagate in either direction.
+    Private,
+    /// Like `Private`, and the mount can not be the source of a bind mount.
+    Unbindable,
+}
+
+impl MountPropagation {
+    /// `MS_*` flags for `mount(2)`, with `MS_REC` if `recursive`.
+    pub(crate) fn flags(self, recursive: bool) -> MsFlags {
+        let mut flags = match self {
+            MountPropagation::Shared => MsFlags::MS_SHARED,
+            MountPropagation::Slave => MsFlags::MS_SLAVE,
+            MountPropagation::Private => MsFlags::MS_PRIVATE,
+            MountPropagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        };
+        flags.set(MsFlags::MS_REC, recursive);
+        flags
+    }
+}
+
+impl FromStr for MountPropagation {
+    type Err = ();
+
+    /// Parse OCI `rootfsPropagation` values like `slave` or `private`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(MountPropagation::Shared),
+            "slave" => Ok(MountPropagation::Slave),
+            "private" => Ok(MountPropagation::Private),
+            "unbindable" => Ok(MountPropagation::Unbindable),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(
+    Builder, Getters, Setters, MutGetters, CopyGetters, Default, Clone, Serialize, Deserialize,
+)]
+#[serde(default)]
 /// Process contains information to start a specific application inside the
 /// container.
+///
+/// `bin` and the `prctl(2)` attributes other than `no_new_privileges` are
+/// not part of the OCI runtime specification, and are skipped by serde.
 pub struct Process {
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Terminal creates an interactive terminal for the process.
+    terminal: bool,
+
     #[getset(get = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     /// User specifies user information for the process.
     user: Option<User>,
 
     #[getset(get = "pub", set = "pub")]
+    #[serde(skip)]
     pub(crate) bin: OsString,
 
-    #[getset(get = "pub", set = "pub")]
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(with = "os_strings")]
     /// Args specifies the arguments for the application to
     /// execute.
-    args: Vec<String>,
+    args: Vec<OsString>,
 
-    #[getset(get = "pub", set = "pub")]
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none", with = "os_strings::option")]
     /// Env populates the process environment for the process.
-    env: Option<Vec<String>>,
+    ///
+    /// Each item is in the form of `KEY=VALUE`. If it is `None`, the
+    /// environment of the parent at the time of spawning is inherited.
+    env: Option<Vec<OsString>>,
 
     #[getset(get = "pub", set = "pub")]
     /// Cwd is the current working directory for the process and must be
     /// relative to the container's root.
     cwd: PathBuf,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Rlimits specifies rlimit options to apply to the process.
+    rlimits: Vec<Rlimit>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(rename = "noNewPrivileges")]
+    /// NoNewPrivileges sets `no_new_privs`, so `execve(2)` can not grant
+    /// privileges the process does not have, see `PR_SET_NO_NEW_PRIVS`.
+    no_new_privileges: bool,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip)]
+    /// Signal sent to the process when the thread which spawned it dies,
+    /// see `PR_SET_PDEATHSIG`. Not part of the OCI runtime specification.
+    ///
+    /// If the parent already died before the signal is set, it is raised
+    /// at once. This can not be detected from inside a new pid
+    /// namespace, where the parent is not visible.
+    parent_death_signal: Option<i32>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip)]
+    /// Whether the process can be core dumped and ptraced by the same
+    /// user, see `PR_SET_DUMPABLE`. Not part of the OCI runtime
+    /// specification.
+    dumpable: Option<bool>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip)]
+    /// Make the process reap orphaned descendants, like init does, see
+    /// `PR_SET_CHILD_SUBREAPER`. Not part of the OCI runtime
+    /// specification.
+    child_subreaper: bool,
 }
 
-#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct User {
     #[getset(get_copy = "pub", set = "pub")]
     /// UID is the user id.
@@ -93,16 +414,66 @@ pub struct User {
     #[getset(get_copy = "pub", set = "pub")]
     /// GID is the group id.
     gid: u32,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Umask is the umask for the init process.
+    umask: Option<u32>,
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Serialize, Deserialize)]
+/// Rlimit type and restrictions.
+pub struct Rlimit {
+    #[getset(get = "pub", set = "pub")]
+    #[serde(rename = "type")]
+    /// Type of the rlimit to set, like `RLIMIT_NOFILE`.
+    typ: String,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Hard is the hard limit for the specified type.
+    hard: u64,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Soft is the soft limit for the specified type.
+    soft: u64,
 }
 
-#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+impl Rlimit {
+    /// Resource number of `typ` for `setrlimit(2)`.
+    pub(crate) fn resource(&self) -> Option<libc::__rlimit_resource_t> {
+        let resource = match self.typ.as_str() {
+            "RLIMIT_AS" => libc::RLIMIT_AS,
+            "RLIMIT_CORE" => libc::RLIMIT_CORE,
+            "RLIMIT_CPU" => libc::RLIMIT_CPU,
+            "RLIMIT_DATA" => libc::RLIMIT_DATA,
+            "RLIMIT_FSIZE" => libc::RLIMIT_FSIZE,
+            "RLIMIT_LOCKS" => libc::RLIMIT_LOCKS,
+            "RLIMIT_MEMLOCK" => libc::RLIMIT_MEMLOCK,
+            "RLIMIT_MSGQUEUE" => libc::RLIMIT_MSGQUEUE,
+            "RLIMIT_NICE" => libc::RLIMIT_NICE,
+            "RLIMIT_NOFILE" => libc::RLIMIT_NOFILE,
+            "RLIMIT_NPROC" => libc::RLIMIT_NPROC,
+            "RLIMIT_RSS" => libc::RLIMIT_RSS,
+            "RLIMIT_RTPRIO" => libc::RLIMIT_RTPRIO,
+            "RLIMIT_RTTIME" => libc::RLIMIT_RTTIME,
+            "RLIMIT_SIGPENDING" => libc::RLIMIT_SIGPENDING,
+            "RLIMIT_STACK" => libc::RLIMIT_STACK,
+            _ => return None,
+        };
+        Some(resource)
+    }
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Debug, Serialize, Deserialize)]
 /// LinuxIDMapping specifies UID/GID mappings.
 pub struct IdMap {
     #[getset(get_copy = "pub", set = "pub")]
+    #[serde(rename = "hostID")]
     /// HostID is the starting UID/GID on the host to be mapped to
     /// `container_id`.
     pub(crate) host_id: u32,
     #[getset(get_copy = "pub", set = "pub")]
+    #[serde(rename = "containerID")]
     /// ContainerID is the starting UID/GID in the container.
     pub(crate) container_id: u32,
 
@@ -111,8 +482,530 @@ pub struct IdMap {
     pub(crate) size: u32,
 }
 
+#[derive(
+    Builder, Getters, Setters, MutGetters, CopyGetters, Default, Clone, Serialize, Deserialize,
+)]
+#[serde(default, rename_all = "camelCase")]
+/// Hooks specifies commands to run at points of the container lifecycle.
+///
+/// `prestart` and `create_runtime` run in the namespaces of the runtime
+/// after the namespaces of the container are created, `create_container`
+/// runs in the namespaces of the container before `pivot_root(2)`, and
+/// `start_container` runs in the container right before the process is
+/// executed. `poststart` and `poststop` are left to the runtime.
+pub struct Hooks {
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Prestart is a list of hooks to be run before the container process
+    /// is executed. Deprecated in favour of `create_runtime`.
+    prestart: Vec<Hook>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// CreateRuntime is a list of hooks to be run after the container has
+    /// been created but before `pivot_root(2)`.
+    create_runtime: Vec<Hook>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// CreateContainer is a list of hooks to be run in the container
+    /// before `pivot_root(2)`.
+    create_container: Vec<Hook>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// StartContainer is a list of hooks to be run in the container before
+    /// the process is executed.
+    start_container: Vec<Hook>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Poststart is a list of hooks to be run after the container process
+    /// is started.
+    poststart: Vec<Hook>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Poststop is a list of hooks to be run after the container is
+    /// deleted.
+    poststop: Vec<Hook>,
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Hook specifies a command that is run at a particular event in the
+/// lifecycle of a container.
+pub struct Hook {
+    #[getset(get = "pub", set = "pub")]
+    /// Path is the absolute path to the executable.
+    path: PathBuf,
+
+    #[getset(get = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Args are the arguments including `argv[0]`, like `execv(3)`.
+    args: Vec<String>,
+
+    #[getset(get = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Env is the whole environment of the hook, in the form of
+    /// `KEY=VALUE`.
+    env: Vec<String>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Timeout is the number of seconds before aborting the hook.
+    timeout: Option<u64>,
+}
+
+impl Hook {
+    /// Run the hook with `state` on its stdin, and wait for it to finish.
+    ///
+    /// It is an error if the hook exits with non-zero status or times out.
+    pub fn run(&self, state: &crate::oci::State) -> Result<(), Error> {
+        let failed = |msg: String| Error::HookFailed(format!("{}: {}", self.path.display(), msg));
+
+        let mut cmd = Command::new(&self.path);
+        if let Some((arg0, args)) = self.args.split_first() {
+            cmd.arg0(arg0).args(args);
+        }
+        cmd.env_clear()
+            .envs(self.env.iter().filter_map(|e| e.split_once('=')))
+            .stdin(std::process::Stdio::piped());
+        let mut child = cmd.spawn().map_err(|e| failed(e.to_string()))?;
+        // The hook may exit without reading its stdin
+        let _ = child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&serde_json::to_vec(state).unwrap());
+
+        let status = match self.timeout {
+            None => child.wait()?,
+            Some(timeout) => {
+                let deadline = Instant::now() + Duration::from_secs(timeout);
+                loop {
+                    if let Some(status) = child.try_wait()? {
+                        break status;
+                    }
+                    if Instant::now() >= deadline {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(failed("timed out".into()));
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+        match status.success() {
+            true => Ok(()),
+            false => Err(failed(status.to_string())),
+        }
+    }
+}
+
+#[derive(
+    Builder,
+    Getters,
+    Setters,
+    MutGetters,
+    CopyGetters,
+    Default,
+    Clone,
+    Debug,
+    Serialize,
+    Deserialize,
+)]
+#[serde(default, rename_all = "camelCase")]
+/// Resources limits the container through cgroup v2 controllers.
+///
+/// Unset limits are left to the defaults of the kernel.
+pub struct Resources {
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Hard memory limit in bytes, `memory.max`.
+    memory_max: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Memory usage in bytes above which the container is throttled,
+    /// `memory.high`.
+    memory_high: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Swap limit in bytes, `memory.swap.max`.
+    memory_swap_max: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Relative cpu share from 1 to 10000, `cpu.weight`.
+    cpu_weight: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Cpu time in microseconds the container may use every
+    /// `cpu_period`, `cpu.max`.
+    cpu_quota: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Period of `cpu_quota` in microseconds, 100000 by default.
+    cpu_period: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Maximum number of processes, `pids.max`.
+    pids_max: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Relative io share from 1 to 10000, `io.weight`.
+    io_weight: Option<u64>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Bandwidth and iops limits per device, `io.max`.
+    io_max: Vec<IoLimit>,
+}
+
+impl Resources {
+    /// Controllers the limits need, as listed in `cgroup.controllers`.
+    pub(crate) fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.memory_max.is_some() || self.memory_high.is_some() || self.memory_swap_max.is_some()
+        {
+            controllers.push("memory");
+        }
+        if self.cpu_weight.is_some() || self.cpu_quota.is_some() || self.cpu_period.is_some() {
+            controllers.push("cpu");
+        }
+        if self.pids_max.is_some() {
+            controllers.push("pids");
+        }
+        if self.io_weight.is_some() || !self.io_max.is_empty() {
+            controllers.push("io");
+        }
+        controllers
+    }
+
+    /// Interface files of the cgroup and the values to write to them.
+    pub(crate) fn files(&self) -> Vec<(&'static str, String)> {
+        let mut files = Vec::new();
+        let mut push = |file, value: Option<String>| {
+            if let Some(value) = value {
+                files.push((file, value));
+            }
+        };
+        push("memory.max", self.memory_max.map(|v| v.to_string()));
+        push("memory.high", self.memory_high.map(|v| v.to_string()));
+        push(
+            "memory.swap.max",
+            self.memory_swap_max.map(|v| v.to_string()),
+        );
+        push("cpu.weight", self.cpu_weight.map(|v| v.to_string()));
+        if self.cpu_quota.is_some() || self.cpu_period.is_some() {
+            let quota = self.cpu_quota.map_or("max".into(), |v| v.to_string());
+            push(
+                "cpu.max",
+                Some(format!("{} {}", quota, self.cpu_period.unwrap_or(100000))),
+            );
+        }
+        push("pids.max", self.pids_max.map(|v| v.to_string()));
+        push(
+            "io.weight",
+            self.io_weight.map(|v| format!("default {}", v)),
+        );
+        for limit in &self.io_max {
+            push("io.max", Some(limit.to_string()));
+        }
+        files
+    }
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+/// IoLimit limits the io of the container on a block device.
+pub struct IoLimit {
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Major number of the device.
+    major: u64,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Minor number of the device.
+    minor: u64,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Read bytes per second.
+    rbps: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Write bytes per second.
+    wbps: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Read operations per second.
+    riops: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Write operations per second.
+    wiops: Option<u64>,
+}
+
+impl std::fmt::Display for IoLimit {
+    /// A line of `io.max`, like `8:0 rbps=1048576 wiops=max`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)?;
+        for (key, value) in [
+            ("rbps", self.rbps),
+            ("wbps", self.wbps),
+            ("riops", self.riops),
+            ("wiops", self.wiops),
+        ] {
+            match value {
+                Some(value) => write!(f, " {}={}", key, value)?,
+                None => write!(f, " {}=max", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+bitflags::bitflags! {
+    /// Filesystem access rights of Landlock, `LANDLOCK_ACCESS_FS_*`.
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+    pub struct LandlockFs: u64 {
+        /// Execute a file.
+        const EXECUTE = 1 << 0;
+        /// Open a file with write access.
+        const WRITE_FILE = 1 << 1;
+        /// Open a file with read access.
+        const READ_FILE = 1 << 2;
+        /// Open a directory or list its content.
+        const READ_DIR = 1 << 3;
+        /// Remove an empty directory or rename one.
+        const REMOVE_DIR = 1 << 4;
+        /// Unlink or rename a file.
+        const REMOVE_FILE = 1 << 5;
+        /// Create a character device.
+        const MAKE_CHAR = 1 << 6;
+        /// Create a directory.
+        const MAKE_DIR = 1 << 7;
+        /// Create a regular file.
+        const MAKE_REG = 1 << 8;
+        /// Create a unix domain socket.
+        const MAKE_SOCK = 1 << 9;
+        /// Create a named pipe.
+        const MAKE_FIFO = 1 << 10;
+        /// Create a block device.
+        const MAKE_BLOCK = 1 << 11;
+        /// Create a symbolic link.
+        const MAKE_SYM = 1 << 12;
+        /// Link or rename a file from or to a different directory, ABI 2.
+        const REFER = 1 << 13;
+        /// Truncate a file, ABI 3.
+        const TRUNCATE = 1 << 14;
+        /// Use `ioctl(2)` on a device file, ABI 5.
+        const IOCTL_DEV = 1 << 15;
+
+        /// Read files and directories.
+        const READ = Self::READ_FILE.bits() | Self::READ_DIR.bits();
+        /// Create, write, remove and rename files and directories.
+        const WRITE = Self::WRITE_FILE.bits()
+            | Self::REMOVE_DIR.bits()
+            | Self::REMOVE_FILE.bits()
+            | Self::MAKE_CHAR.bits()
+            | Self::MAKE_DIR.bits()
+            | Self::MAKE_REG.bits()
+            | Self::MAKE_SOCK.bits()
+            | Self::MAKE_FIFO.bits()
+            | Self::MAKE_BLOCK.bits()
+            | Self::MAKE_SYM.bits()
+            | Self::REFER.bits()
+            | Self::TRUNCATE.bits();
+    }
+}
+
+impl LandlockFs {
+    /// Rights which apply to a file rather than to a directory.
+    pub const FILE: Self = Self::EXECUTE
+        .union(Self::WRITE_FILE)
+        .union(Self::READ_FILE)
+        .union(Self::TRUNCATE)
+        .union(Self::IOCTL_DEV);
+
+    /// Rights handled by the given Landlock ABI version.
+    pub fn for_abi(abi: u32) -> Self {
+        match abi {
+            0 => Self::empty(),
+            1 => Self::from_bits_truncate((1 << 13) - 1),
+            2 => Self::from_bits_truncate((1 << 14) - 1),
+            3 | 4 => Self::from_bits_truncate((1 << 15) - 1),
+            _ => Self::all(),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Network access rights of Landlock, `LANDLOCK_ACCESS_NET_*`, ABI 4.
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+    pub struct LandlockNet: u64 {
+        /// Bind a TCP socket to a port.
+        const BIND_TCP = 1 << 0;
+        /// Connect a TCP socket to a port.
+        const CONNECT_TCP = 1 << 1;
+    }
+}
+
+impl LandlockNet {
+    /// Rights handled by the given Landlock ABI version.
+    pub fn for_abi(abi: u32) -> Self {
+        match abi {
+            0..=3 => Self::empty(),
+            _ => Self::all(),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+/// What to do with access rights the running kernel does not know about.
+pub enum LandlockCompat {
+    #[default]
+    /// Enforce the rights the kernel supports and ignore others, running
+    /// unrestricted if Landlock is not available at all.
+    BestEffort,
+    /// Fail if the ruleset can not be enforced as a whole.
+    Strict,
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Clone, Debug)]
+/// Access allowed beneath a file or directory.
+pub struct LandlockPathRule {
+    #[getset(get = "pub", set = "pub")]
+    /// File or directory the rule applies to, opened in the container.
+    path: PathBuf,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Rights allowed beneath `path`.
+    access: LandlockFs,
+}
+
+#[derive(Builder, Getters, Setters, CopyGetters, Clone, Debug)]
+/// Access allowed to a TCP port.
+pub struct LandlockPortRule {
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Local port for binding, remote port for connecting.
+    port: u16,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Rights allowed on `port`.
+    access: LandlockNet,
+}
+
+#[derive(Builder, Getters, Setters, MutGetters, CopyGetters, Default, Clone, Debug)]
+#[builder(default)]
+/// Landlock ruleset restricting the process, see `landlock(7)`.
+///
+/// Every handled right is denied unless a rule allows it. Rights which
+/// are not handled stay allowed.
+pub struct Landlock {
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Filesystem rights to restrict, every right the kernel supports
+    /// if `None`.
+    handled_fs: Option<LandlockFs>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Network rights to restrict, none by default.
+    handled_net: LandlockNet,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    /// Rules allowing filesystem access.
+    paths: Vec<LandlockPathRule>,
+
+    #[getset(get = "pub", set = "pub", get_mut = "pub")]
+    /// Rules allowing network access.
+    ports: Vec<LandlockPortRule>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    /// Behaviour on kernels with an older or no Landlock ABI.
+    compatibility: LandlockCompat,
+}
+
+impl Landlock {
+    /// Allow `access` beneath `path`.
+    pub fn allow_path<P: Into<PathBuf>>(&mut self, path: P, access: LandlockFs) -> &mut Self {
+        self.paths.push(LandlockPathRule {
+            path: path.into(),
+            access,
+        });
+        self
+    }
+
+    /// Allow `access` to the TCP `port`.
+    pub fn allow_port(&mut self, port: u16, access: LandlockNet) -> &mut Self {
+        self.ports.push(LandlockPortRule { port, access });
+        self
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+/// Where a standard stream of the child is connected to, like
+/// `std::process::Stdio`.
+pub enum Stdio {
+    #[default]
+    /// The stream of the parent.
+    Inherit,
+    /// `/dev/null` of the parent.
+    Null,
+    /// A pipe to the parent, taken from `Child`.
+    Piped,
+}
+
 pub enum IdMapPreset {
     Root,
     Current,
     Auto,
 }
+
+/// Serde of `OsString` lists as the strings of the OCI runtime spec, which
+/// are UTF-8.
+mod os_strings {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::ffi::OsString;
+
+    pub(super) fn serialize<S: Serializer>(v: &[OsString], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(v.iter().map(|a| a.to_string_lossy()))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<OsString>, D::Error> {
+        let v = Vec::<String>::deserialize(d)?;
+        Ok(v.into_iter().map(OsString::from).collect())
+    }
+
+    pub(super) mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::ffi::OsString;
+
+        pub(in super::super) fn serialize<S: Serializer>(
+            v: &Option<Vec<OsString>>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match v {
+                Some(v) => super::serialize(v, s),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub(in super::super) fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<Vec<OsString>>, D::Error> {
+            let v = Option::<Vec<String>>::deserialize(d)?;
+            Ok(v.map(|v| v.into_iter().map(OsString::from).collect()))
+        }
+    }
+}
@@ -2,13 +2,25 @@
 
 use std::{
     collections::VecDeque,
-    ffi::{OsStr, OsString},
-    fs::OpenOptions,
-    os::{fd::IntoRawFd, unix::prelude::OsStrExt},
+    ffi::{CString, OsStr, OsString},
+    io::{Read, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+        unix::prelude::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    time::Instant,
 };
 
-use crate::{config, util, Child, Error};
-use getset::{CopyGetters, Getters, Setters};
+use crate::setup::{self, cstr, Context, Failure, Step};
+use crate::sys::Syscalls;
+use crate::trace::{self, Stage};
+use crate::{config, landlock, oci, plan, util, Child, Error};
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
 use nix::sched::CloneFlags;
 
 /// Default stack size
@@ -16,197 +28,2071 @@ use nix::sched::CloneFlags;
 /// https://wiki.musl-libc.org/functional-differences-from-glibc.html
 const STACK_SIZE: usize = 122880;
 
+/// Where the tmpfs holding the new root is mounted before `pivot_root(2)`
+const STAGING_PATH: &str = "/tmp";
+
+/// Device nodes bind mounted from the host by `dev` mounts, like `bwrap --dev`
+const DEV_NODES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
 // preventing running some function outside the child process
-static mut IS_CHILD: bool = false;
+static IS_CHILD: AtomicBool = AtomicBool::new(false);
+
+/// Signals the original child forwards to the process it forked into a
+/// joined pid namespace, see `WrapCore::fork_into_pid_namespace`.
+const FORWARDED_SIGNALS: [libc::c_int; 9] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGCONT,
+    libc::SIGTSTP,
+    libc::SIGWINCH,
+];
+
+/// Pid of the process forked into a joined pid namespace, for
+/// `forward_signal`.
+static FORKED_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    let pid = FORKED_PID.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
 
 /// Boxed closure to execute in child process
-pub type WrapCbBox<'a> = Box<dyn FnOnce() -> isize + 'a>;
+///
+/// It is called through `&mut`, as calling a boxed `FnOnce` frees the box
+/// in the child.
+pub type WrapCbBox<'a> = Box<dyn FnMut() -> isize + 'a>;
+
+/// Program of a `config::Process` in the form `execve(2)` takes.
+///
+/// It is prepared in the parent, so executing it does not allocate in the
+/// child.
+pub(crate) struct Exec {
+    bin: CString,
+    cwd: Option<CString>,
+    // Strings the pointers refer to
+    argv: Vec<CString>,
+    envp: Vec<CString>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>,
+    rlimits: Vec<(libc::__rlimit_resource_t, libc::rlimit)>,
+}
+
+impl Exec {
+    /// Prepare `process`, resolving an inherited environment to the one
+    /// of the caller.
+    pub(crate) fn new(process: &config::Process) -> Result<Self, Error> {
+        let cstr = |s: &[u8]| CString::new(s).map_err(std::io::Error::from);
+        let bin = cstr(process.bin().as_bytes())?;
+        let cwd = match process.cwd().as_os_str().is_empty() {
+            true => None,
+            false => Some(cstr(process.cwd().as_os_str().as_bytes())?),
+        };
+        let mut argv = vec![bin.clone()];
+        for arg in process.args() {
+            argv.push(cstr(arg.as_bytes())?);
+        }
+        let envp: Vec<CString> = match process.env() {
+            Some(env) => env
+                .iter()
+                .map(|e| cstr(e.as_bytes()))
+                .collect::<Result<_, _>>(),
+            None => std::env::vars_os()
+                .map(|(k, v)| {
+                    let mut e = k;
+                    e.push("=");
+                    e.push(v);
+                    cstr(e.as_bytes())
+                })
+                .collect::<Result<_, _>>(),
+        }?;
+        let rlimits = process
+            .rlimits()
+            .iter()
+            .map(|rlimit| {
+                let limit = libc::rlimit {
+                    rlim_cur: rlimit.soft(),
+                    rlim_max: rlimit.hard(),
+                };
+                Ok((
+                    rlimit.resource().ok_or(Error::OsErrno(libc::EINVAL))?,
+                    limit,
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+        let ptrs = |strs: &Vec<CString>| {
+            strs.iter()
+                .map(|s| s.as_ptr())
+                .chain([std::ptr::null()])
+                .collect()
+        };
+        Ok(Self {
+            argv_ptrs: ptrs(&argv),
+            envp_ptrs: ptrs(&envp),
+            bin,
+            cwd,
+            argv,
+            envp,
+            rlimits,
+        })
+    }
+}
 
 //#[derive(Getters, Setters, CopyGetters, Default)]
 pub(crate) struct WrapCore<'a> {
     pub(crate) process: Option<config::Process>,
+    pub(crate) exec: Option<Exec>,
     pub(crate) root: Option<config::Root>,
+    pub(crate) hostname: Option<OsString>,
+    pub(crate) sysctls: Vec<(String, String)>,
 
+    pub(crate) root_propagation: Option<config::MountPropagation>,
     pub(crate) mounts: Vec<config::Mount>,
     pub(crate) uid_maps: Vec<config::IdMap>,
     pub(crate) gid_maps: Vec<config::IdMap>,
     pub(crate) callbacks: VecDeque<WrapCbBox<'a>>,
+    pub(crate) hooks: config::Hooks,
+    pub(crate) hook_state: Option<oci::State>,
+    pub(crate) tty: bool,
+    pub(crate) console_socket: Option<PathBuf>,
+    pub(crate) join: Option<(config::JoinTarget, Vec<config::NamespaceType>)>,
+    pub(crate) cgroup_fd: Option<RawFd>,
+    pub(crate) landlock: Option<config::Landlock>,
+    /// Pid of the parent as seen by the child, 0 if it is outside the pid
+    /// namespace of the child. Set by `spwan`.
+    pub(crate) parent_pid: libc::pid_t,
+    pub(crate) preserve_fds: u32,
+    pub(crate) fd_maps: Vec<(RawFd, RawFd)>,
+    /// Ends of stdin, stdout and stderr for the child, if redirected.
+    pub(crate) stdio: [Option<RawFd>; 3],
+    /// Ends of the pipes of stdio kept by the parent, closed in the child.
+    pub(crate) parent_stdio: Vec<RawFd>,
 
     pub(crate) namespace_nsenter: config::NamespaceSet,
     pub(crate) namespace_unshare: config::NamespaceSet,
 
     pub(crate) sandbox_mnt: bool,
+
+    /// Prepared from the fields above by `spwan`.
+    pub(crate) plan: setup::Plan,
+    /// `sys::Kernel`, unless testing.
+    pub(crate) sys: &'a dyn Syscalls,
+    /// Write end of the `trace::Channel`, set by `spwan` when debug logs
+    /// are enabled.
+    pub(crate) trace: Option<RawFd>,
 }
 
 impl WrapCore<'_> {
-    fn run_child(&mut self) -> isize {
-        unsafe {
-            if IS_CHILD != true {
-                panic!()
+    /// Set up the child and execute the program, or return the value of
+    /// the last callback.
+    ///
+    /// Nothing here allocates or takes a lock, the parent may have other
+    /// threads. Callbacks, and the hooks of OCI containers, are the only
+    /// exceptions.
+    fn run_child(&mut self, sync: Option<UnixStream>) -> isize {
+        if !IS_CHILD.load(Ordering::Relaxed) {
+            panic!()
+        }
+        self.run_stages(sync)
+    }
+
+    /// Run `setup::Plan::stages` in order, tracing each of them, see
+    /// `run_child`.
+    ///
+    /// A failed stage is reported and ends the child with 1.
+    fn run_stages(&mut self, mut sync: Option<UnixStream>) -> isize {
+        let mut joined_pid = false;
+        let mut ret = 0;
+        for i in 0..self.plan.stages.len() {
+            let stage = self.plan.stages[i];
+            let start = Instant::now();
+            let result = match stage {
+                Stage::Process => self
+                    .traced(stage, 0, || self.set_up_process())
+                    .map(|joined| joined_pid = joined),
+                Stage::Fork if joined_pid => {
+                    match self.traced(stage, 0, || self.fork_into_pid_namespace()) {
+                        Ok(()) => {
+                            // The signal is not inherited, and the new
+                            // parent is outside the pid namespace
+                            self.parent_pid = 0;
+                            self.set_parent_death_signal()
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Stage::Fork => Ok(()),
+                Stage::Namespaces => self.traced(stage, 0, || {
+                    self.apply_nsenter()?;
+                    self.apply_unshare()
+                }),
+                Stage::IdMap => self.run_steps(stage, &self.plan.id_maps),
+                Stage::Hostname => match &self.hostname {
+                    Some(hostname) => self.traced(stage, 0, || {
+                        self.sys.sethostname(hostname).context("sethostname")
+                    }),
+                    None => Ok(()),
+                },
+                Stage::CreateHooks => {
+                    let ret = self.sync_create_hooks(sync.as_mut().unwrap());
+                    let outcome = ret.as_ref().map(drop).map_err(|e| Some(e.errno));
+                    self.trace(stage, 0, start, outcome);
+                    match ret {
+                        Ok(state) => {
+                            self.hook_state = Some(state);
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Stage::Mount => self.run_steps(stage, &self.plan.mounts),
+                Stage::Pty => match &sync {
+                    Some(sync) => self.traced(stage, 0, || Self::set_up_tty(sync)),
+                    None => Ok(()),
+                },
+                Stage::Callbacks => {
+                    ret = self.execute_callbacks();
+                    if !self.callbacks.is_empty() {
+                        self.trace(stage, 0, start, Ok(()));
+                    }
+                    Ok(())
+                }
+                Stage::StartHooks => {
+                    let Some(state) = &mut self.hook_state else {
+                        continue;
+                    };
+                    state.set_status(oci::Status::Created);
+                    let ret = run_hooks(
+                        "run startContainer hook",
+                        self.hooks.start_container(),
+                        &self.plan.start_hooks,
+                        state,
+                    );
+                    let outcome = ret.as_ref().map_err(|e| Some(e.errno)).copied();
+                    self.trace(stage, 0, start, outcome);
+                    ret
+                }
+                Stage::Fds => {
+                    drop(sync.take());
+                    self.traced(stage, 0, || self.set_up_fds().context("set up fds"))
+                }
+                Stage::Landlock => match &self.plan.landlock {
+                    Some(ruleset) => self.traced(stage, 0, || landlock::restrict_self(ruleset)),
+                    None => Ok(()),
+                },
+                Stage::Attributes => match (&self.exec, &self.process) {
+                    (Some(exec), Some(process)) => self.traced(stage, 0, || {
+                        if let Some(cwd) = &exec.cwd {
+                            self.sys.chdir(cwd).context_at("chdir", cwd)?;
+                        }
+                        Self::set_process_attrs(exec, process, self.parent_pid)
+                    }),
+                    _ => Ok(()),
+                },
+                Stage::Exec => return self.execute(),
+            };
+            if let Err(e) = result {
+                e.report();
+                return 1;
             }
         }
+        ret
+    }
 
-        self.apply_nsenter();
-        self.apply_unshare();
+    /// Run `steps` of `stage`, tracing each of them.
+    fn run_steps<'a>(&self, stage: Stage, steps: &'a [Step]) -> Result<(), Failure<'a>> {
+        for (i, step) in steps.iter().enumerate() {
+            self.traced(stage, i, || step.run(self.sys))?;
+        }
+        Ok(())
+    }
 
-        // Drop mmap and fd?
+    /// Stages of the child in the order `run_stages` runs them, which is
+    /// also the order `plan` describes them in.
+    fn stages(&self) -> Vec<Stage> {
+        let joins_pid = self.plan.join.as_ref().is_some_and(|join| {
+            join.namespaces
+                .iter()
+                .any(|(ns, _, _)| *ns == config::NamespaceType::Pid)
+        });
+        let hooks = self.hook_state.is_some();
+        let exec = self.exec.is_some() && self.process.is_some();
+        [
+            (Stage::Process, true),
+            (Stage::Fork, joins_pid),
+            (
+                Stage::Namespaces,
+                !self.namespace_nsenter.is_empty() || self.join.is_some(),
+            ),
+            (Stage::IdMap, !self.plan.id_maps.is_empty()),
+            (Stage::Hostname, self.hostname.is_some()),
+            (Stage::CreateHooks, hooks),
+            (Stage::Mount, !self.plan.mounts.is_empty()),
+            (Stage::Pty, self.tty),
+            (Stage::Callbacks, true),
+            (Stage::StartHooks, hooks),
+            (Stage::Fds, exec),
+            (Stage::Landlock, exec && self.plan.landlock.is_some()),
+            (Stage::Attributes, exec),
+            (Stage::Exec, exec),
+        ]
+        .into_iter()
+        .filter_map(|(stage, runs)| runs.then_some(stage))
+        .collect()
+    }
 
-        if (self.uid_maps.len() + self.gid_maps.len()) > 0 {
-            self.set_id_map();
+    /// Build the `setup::Plan` of the child.
+    fn prepare(&mut self) -> Result<(), Error> {
+        let mut plan = setup::Plan::default();
+        if !self.uid_maps.is_empty() || !self.gid_maps.is_empty() {
+            plan.id_maps = self.plan_id_maps()?;
         }
 
+        let mounts_proc = self
+            .mounts
+            .iter()
+            .any(|m| m.typ().as_deref() == Some("proc"));
+        if !mounts_proc {
+            plan.mounts = self.plan_sysctls(Path::new("/proc"))?;
+        }
+        if let Some(propagation) = self.root_propagation {
+            plan.mounts
+                .push(Step::propagation(cstr("/")?, propagation.flags(true)));
+        }
         if self.sandbox_mnt {
-            self.set_up_tmpfs_cwd();
+            self.plan_tmpfs_cwd(&mut plan.mounts)?;
+        } else {
+            self.plan_mounts(Path::new("/"), Path::new("/"), &mut plan.mounts)?;
+        }
+
+        if let Some((target, namespaces)) = &self.join {
+            plan.join = Some(Self::plan_join(*target, namespaces)?);
+        }
+
+        // The last preserved fd, or stderr
+        let preserved = 2 + self.preserve_fds as RawFd;
+        plan.fds_above = self
+            .fd_maps
+            .iter()
+            .flat_map(|&(src, dst)| [src, dst])
+            .fold(preserved, RawFd::max)
+            + 1;
+        plan.keep_fds = (3..3 + self.preserve_fds as RawFd)
+            .chain(self.fd_maps.iter().map(|&(_, dst)| dst))
+            .filter(|&fd| fd >= 3)
+            .collect();
+        plan.keep_fds.sort_unstable();
+        plan.keep_fds.dedup();
+
+        if let Some(ruleset) = &self.landlock {
+            plan.landlock = landlock::prepare(ruleset)?;
+        }
+        let paths = |hooks: &[config::Hook]| -> Result<Vec<CString>, Error> {
+            Ok(hooks
+                .iter()
+                .map(|h| cstr(h.path()))
+                .collect::<Result<_, _>>()?)
+        };
+        plan.create_hooks = paths(self.hooks.create_container())?;
+        plan.start_hooks = paths(self.hooks.start_container())?;
+        self.plan = plan;
+        self.plan.stages = self.stages();
+        Ok(())
+    }
+
+    /// Operations `spwan` would perform, in order, for `Wrap::plan`.
+    ///
+    /// They are listed by the `setup::Plan::stages` the child runs.
+    /// `stdio`, `cgroup` and the number of `callbacks` are the ones of
+    /// `Wrap`, as they are only opened or moved here by `Wrap::spawn`.
+    pub(crate) fn plan(
+        mut self,
+        stdio: &[config::Stdio; 3],
+        cgroup: Option<&Path>,
+        callbacks: usize,
+    ) -> Result<plan::Plan, Error> {
+        use config::{NamespaceItem, NamespaceType};
+        use plan::Operation;
+
+        self.prepare()?;
+        let prctl = |option: &str, value| Operation::Prctl {
+            option: option.into(),
+            value,
+        };
+        let hook = |stage: &str, hook: &config::Hook| Operation::Hook {
+            stage: stage.into(),
+            path: hook.path().clone(),
+            args: hook.args().clone(),
+        };
+        let pdeathsig = self.process.as_ref().and_then(|p| p.parent_death_signal());
+        let mut ops = vec![Operation::Clone {
+            flags: plan::clone_flag_names(self.clone_flags(cgroup.is_some())),
+        }];
+        for &stage in &self.plan.stages {
+            match stage {
+                Stage::Process => {
+                    if let Some(sig) = pdeathsig {
+                        ops.push(prctl("PR_SET_PDEATHSIG", sig as u64));
+                    }
+                    for (fd, stdio) in stdio.iter().enumerate() {
+                        let to = match stdio {
+                            config::Stdio::Inherit => continue,
+                            config::Stdio::Null => "/dev/null",
+                            config::Stdio::Piped => "a pipe",
+                        };
+                        ops.push(Operation::Stdio {
+                            fd: fd as RawFd,
+                            to: to.into(),
+                        });
+                    }
+                    if let Some(path) = cgroup {
+                        ops.push(Operation::EnterCgroup { path: path.into() });
+                        if let NamespaceItem::Unshare = self.namespace_unshare.cgroup {
+                            if self.namespace_nsenter.is_empty() && self.join.is_none() {
+                                ops.push(Operation::Unshare {
+                                    namespaces: vec![NamespaceType::Cgroup],
+                                });
+                            }
+                        }
+                    }
+                    if let Some(join) = &self.plan.join {
+                        ops.push(Operation::JoinProcess {
+                            pid: join.target.pid()?,
+                            namespaces: join.namespaces.iter().map(|(ns, _, _)| *ns).collect(),
+                        });
+                    }
+                }
+                // The signal is set again in the process forked by
+                // `JoinProcess`
+                Stage::Fork => {
+                    if let Some(sig) = pdeathsig {
+                        ops.push(prctl("PR_SET_PDEATHSIG", sig as u64));
+                    }
+                }
+                Stage::Namespaces => {
+                    let enter = &self.namespace_nsenter;
+                    for (item, namespace) in [
+                        (&enter.user, NamespaceType::User),
+                        (&enter.cgroup, NamespaceType::Cgroup),
+                        (&enter.ipc, NamespaceType::Ipc),
+                        (&enter.uts, NamespaceType::Uts),
+                        (&enter.network, NamespaceType::Network),
+                        (&enter.pid, NamespaceType::Pid),
+                        (&enter.mount, NamespaceType::Mount),
+                    ] {
+                        if let NamespaceItem::Enter(ns) = item {
+                            ops.push(Operation::Setns {
+                                namespace,
+                                fd: ns.as_raw_fd(),
+                            });
+                        }
+                    }
+                    let unshare = &self.namespace_unshare;
+                    let namespaces: Vec<_> = [
+                        (&unshare.user, NamespaceType::User),
+                        (&unshare.mount, NamespaceType::Mount),
+                        (&unshare.cgroup, NamespaceType::Cgroup),
+                        (&unshare.uts, NamespaceType::Uts),
+                        (&unshare.ipc, NamespaceType::Ipc),
+                        (&unshare.pid, NamespaceType::Pid),
+                        (&unshare.network, NamespaceType::Network),
+                    ]
+                    .into_iter()
+                    .filter(|(item, _)| matches!(item, NamespaceItem::Unshare))
+                    .map(|(_, ns)| ns)
+                    .collect();
+                    if !namespaces.is_empty() {
+                        ops.push(Operation::Unshare { namespaces });
+                    }
+                }
+                Stage::IdMap => ops.extend(self.plan.id_maps.iter().map(Step::operation)),
+                Stage::Hostname => {
+                    if let Some(hostname) = &self.hostname {
+                        ops.push(Operation::Sethostname {
+                            hostname: hostname.to_string_lossy().into_owned(),
+                        });
+                    }
+                }
+                Stage::CreateHooks => {
+                    ops.extend(self.hooks.prestart().iter().map(|h| hook("prestart", h)));
+                    let create_runtime = self.hooks.create_runtime().iter();
+                    ops.extend(create_runtime.map(|h| hook("createRuntime", h)));
+                    let create_container = self.hooks.create_container().iter();
+                    ops.extend(create_container.map(|h| hook("createContainer", h)));
+                }
+                Stage::Mount => ops.extend(self.plan.mounts.iter().map(Step::operation)),
+                Stage::Pty => ops.push(Operation::SetUpPty),
+                Stage::Callbacks => {
+                    if callbacks > 0 {
+                        ops.push(Operation::Callbacks { count: callbacks });
+                    }
+                }
+                Stage::StartHooks => {
+                    let start_container = self.hooks.start_container().iter();
+                    ops.extend(start_container.map(|h| hook("startContainer", h)));
+                }
+                Stage::Fds => {
+                    for &(from, to) in &self.fd_maps {
+                        ops.push(Operation::MapFd { from, to });
+                    }
+                    ops.push(Operation::CloseFds {
+                        keep: self.plan.keep_fds.clone(),
+                    });
+                }
+                Stage::Landlock => {
+                    if let Some(ruleset) = &self.plan.landlock {
+                        ops.push(prctl("PR_SET_NO_NEW_PRIVS", 1));
+                        ops.push(ruleset.operation());
+                    }
+                }
+                Stage::Attributes => {
+                    let (Some(exec), Some(process)) = (&self.exec, &self.process) else {
+                        continue;
+                    };
+                    if let Some(cwd) = &exec.cwd {
+                        ops.push(Operation::Chdir {
+                            path: setup::path(cwd),
+                        });
+                    }
+                    for rlimit in process.rlimits() {
+                        ops.push(Operation::Setrlimit {
+                            resource: rlimit.typ().clone(),
+                            soft: rlimit.soft(),
+                            hard: rlimit.hard(),
+                        });
+                    }
+                    if let Some(user) = process.user() {
+                        if let Some(mask) = user.umask() {
+                            ops.push(Operation::Umask { mask });
+                        }
+                        ops.push(Operation::SetUser {
+                            uid: user.uid(),
+                            gid: user.gid(),
+                        });
+                        if let Some(sig) = pdeathsig {
+                            ops.push(prctl("PR_SET_PDEATHSIG", sig as u64));
+                        }
+                    }
+                    if let Some(dumpable) = process.dumpable() {
+                        ops.push(prctl("PR_SET_DUMPABLE", dumpable as u64));
+                    }
+                    if process.child_subreaper() {
+                        ops.push(prctl("PR_SET_CHILD_SUBREAPER", 1));
+                    }
+                    if process.no_new_privileges() {
+                        ops.push(prctl("PR_SET_NO_NEW_PRIVS", 1));
+                    }
+                }
+                Stage::Exec => {
+                    if let Some(exec) = &self.exec {
+                        let strings =
+                            |strs: &[CString]| strs.iter().map(|s| setup::string(s)).collect();
+                        ops.push(Operation::Exec {
+                            argv: strings(&exec.argv),
+                            env: strings(&exec.envp),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(plan::Plan::new(ops))
+    }
+
+    /// Send how long `stage` took since `start` to the parent, if it
+    /// traces the setup.
+    fn trace(
+        &self,
+        stage: Stage,
+        index: usize,
+        start: Instant,
+        outcome: Result<(), Option<Errno>>,
+    ) {
+        if let Some(fd) = self.trace {
+            trace::send(fd, stage, index, start.elapsed(), outcome);
+        }
+    }
+
+    /// Run `f` as `stage` of the setup, see `trace`.
+    fn traced<'f, T>(
+        &self,
+        stage: Stage,
+        index: usize,
+        f: impl FnOnce() -> Result<T, Failure<'f>>,
+    ) -> Result<T, Failure<'f>> {
+        let start = Instant::now();
+        let ret = f();
+        let outcome = ret.as_ref().map(drop).map_err(|e| Some(e.errno));
+        self.trace(stage, index, start, outcome);
+        ret
+    }
+
+    /// Set up stdio and the parent death signal, enter the cgroup, and
+    /// join the namespaces of `Wrap::join`.
+    ///
+    /// Returns whether the pid namespace was joined, which only takes
+    /// effect for new children.
+    fn set_up_process(&self) -> Result<bool, Failure<'_>> {
+        self.set_parent_death_signal()?;
+        for fd in &self.parent_stdio {
+            let _ = self.sys.close(*fd);
+        }
+        for (i, fd) in self.stdio.iter().enumerate() {
+            if let Some(fd) = fd {
+                self.sys.dup2(*fd, i as RawFd).context("dup2")?;
+            }
+        }
+
+        if let Some(fd) = self.cgroup_fd {
+            Self::enter_cgroup(fd).context("enter cgroup")?;
+            if let config::NamespaceItem::Unshare = self.namespace_unshare.cgroup {
+                if self.namespace_nsenter.is_empty() && self.join.is_none() {
+                    self.sys
+                        .unshare(CloneFlags::CLONE_NEWCGROUP)
+                        .context("unshare")?;
+                }
+            }
+        }
+
+        match &self.plan.join {
+            Some(join) => Self::join_process(self.sys, join),
+            None => Ok(false),
+        }
+    }
+
+    /// Move the fds of `fd_maps` to their targets, and close every fd
+    /// other than stdio, the `preserve_fds` ones following it, and the
+    /// targets.
+    ///
+    /// Sources are first duplicated above every fd involved, so a target
+    /// can be the source of another mapping.
+    fn set_up_fds(&self) -> nix::Result<()> {
+        let above = self.plan.fds_above;
+        for (i, &(src, _)) in self.fd_maps.iter().enumerate() {
+            self.sys.dup2(src, above + i as RawFd)?;
+        }
+        for (i, &(_, dst)) in self.fd_maps.iter().enumerate() {
+            // The copy at `dst` is not close-on-exec
+            self.sys.dup2(above + i as RawFd, dst)?;
+            self.sys.close(above + i as RawFd)?;
+        }
+
+        for fd in 3..3 + self.preserve_fds as RawFd {
+            // Not every preserved fd has to be open
+            let _ = self.sys.inherit(fd);
+        }
+        let mut first = 3;
+        for &fd in &self.plan.keep_fds {
+            if fd > first {
+                self.sys.close_range(first as u32, fd as u32 - 1)?;
+            }
+            first = fd + 1;
         }
+        self.sys.close_range(first as u32, u32::MAX)
+    }
+
+    /// Tell the parent that namespaces are set up, wait for it to run the
+    /// runtime hooks, and then run `create_container` hooks.
+    ///
+    /// The parent replies with the state to pass to the hooks, as the
+    /// child does not know its own pid outside the pid namespace.
+    fn sync_create_hooks(&self, sync: &mut UnixStream) -> Result<oci::State, Failure<'_>> {
+        let sync_failed = |e: std::io::Error| Failure {
+            what: "sync with the parent",
+            path: None,
+            errno: e.raw_os_error().map_or(Errno::EIO, Errno::from_i32),
+        };
+        sync.write_all(&[0]).map_err(sync_failed)?;
+        let mut buf = Vec::new();
+        sync.read_to_end(&mut buf).map_err(sync_failed)?;
+        let state: oci::State = serde_json::from_slice(&buf).map_err(|_| Failure {
+            what: "wait for runtime hooks",
+            path: None,
+            errno: Errno::ECANCELED,
+        })?;
+        run_hooks(
+            "run createContainer hook",
+            self.hooks.create_container(),
+            &self.plan.create_hooks,
+            &state,
+        )?;
+        Ok(state)
+    }
+
+    /// Parent side of `sync_create_hooks`, run `prestart` and
+    /// `create_runtime` hooks once the child is ready.
+    fn sync_runtime_hooks(
+        sync: &mut UnixStream,
+        hooks: &config::Hooks,
+        state: &oci::State,
+    ) -> Result<(), Error> {
+        if sync.read(&mut [0])? == 0 {
+            return Err(Error::HookFailed(
+                "child exited before running hooks".into(),
+            ));
+        }
+        for hook in hooks.prestart().iter().chain(hooks.create_runtime()) {
+            let start = Instant::now();
+            hook.run(state)?;
+            log::debug!(
+                "ran hook {} in {:?}",
+                hook.path().display(),
+                start.elapsed()
+            );
+        }
+        sync.write_all(&serde_json::to_vec(state).unwrap())?;
+        // The child holds a copy of this end too, shut down the socket
+        // rather than the fd so that it sees the end of the state.
+        sync.shutdown(Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Parent side of the sync socket, run the runtime hooks and receive
+    /// the pty master, which is sent on to `console_socket` if it is set.
+    fn sync_parent(
+        mut sync: UnixStream,
+        hooks: &config::Hooks,
+        hook_state: Option<oci::State>,
+        tty: bool,
+        console_socket: Option<&Path>,
+        child: &mut Child,
+    ) -> Result<(), Error> {
+        if let Some(mut state) = hook_state {
+            state.set_pid(Some(child.id() as i32));
+            Self::sync_runtime_hooks(&mut sync, hooks, &state)?;
+        }
+        if tty {
+            match util::recv_fd(sync.as_raw_fd(), &mut [0])? {
+                Some(master) => match console_socket {
+                    Some(path) => Self::send_console(path, master)?,
+                    None => child.pty_master = Some(master),
+                },
+                None => {
+                    return Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "child exited before sending the pty master",
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a pseudo-terminal from the `devpts` instance mounted in
+    /// the child, make it the controlling terminal and stdio of the child,
+    /// and send the master to the parent.
+    fn set_up_tty(sync: &UnixStream) -> Result<(), Failure<'static>> {
+        use nix::fcntl::OFlag;
+        use nix::pty::{grantpt, posix_openpt, unlockpt};
+
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)
+            .context("posix_openpt")?;
+        grantpt(&master).context("grantpt")?;
+        unlockpt(&master).context("unlockpt")?;
+        let mut name = [0 as libc::c_char; 64];
+        match unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } {
+            0 => (),
+            e => return Err(Errno::from_i32(e)).context("ptsname_r"),
+        }
+        let slave = unsafe { libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        let slave = Errno::result(slave).context("open pty")?;
+
+        nix::unistd::setsid().context("setsid")?;
+        Errno::result(unsafe { libc::ioctl(slave, libc::TIOCSCTTY, 0) }).context("ioctl")?;
+        for fd in 0..3 {
+            nix::unistd::dup2(slave, fd).context("dup2")?;
+        }
+        if slave > 2 {
+            nix::unistd::close(slave).context("close")?;
+        }
+        util::send_fd(sync.as_raw_fd(), master.as_raw_fd(), &[0])
+            .map_err(|_| Errno::last())
+            .context("send pty")
+    }
 
-        self.execute_callbacks()
+    /// Send the pty `master` to the unix socket at `path`, like the
+    /// `--console-socket` option of OCI runtimes.
+    ///
+    /// The path of the pty in the container is sent along with it.
+    fn send_console(path: &Path, master: OwnedFd) -> Result<(), Error> {
+        let mut index: libc::c_uint = 0;
+        if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCGPTN, &mut index) } == -1 {
+            return Err(Error::OsErrno(nix::errno::errno()));
+        }
+        let socket = UnixStream::connect(path)?;
+        let name = format!("/dev/pts/{}", index);
+        util::send_fd(socket.as_raw_fd(), master.as_raw_fd(), name.as_bytes())
+    }
+
+    /// Namespaces for `clone(2)` to create.
+    ///
+    /// New namespaces are created with `clone(2)` when possible, so the
+    /// child itself is the first process in a new pid namespace.
+    /// Namespaces to enter must be joined before unsharing, so that case
+    /// is left to the child, and the cgroup namespace is created after
+    /// entering the cgroup.
+    fn clone_flags(&self, enters_cgroup: bool) -> util::CloneFlags {
+        let mut flags = match self.namespace_nsenter.is_empty() && self.join.is_none() {
+            true => self.namespace_unshare.clone_flags(),
+            false => util::CloneFlags::empty(),
+        };
+        if enters_cgroup {
+            flags.remove(util::CloneFlags::NWCGROUP);
+        }
+        flags
     }
 
     pub(crate) fn spwan(mut self) -> Result<Child, Error> {
+        let start = Instant::now();
+        self.prepare()?;
+        log::debug!(
+            "prepared {} id map and {} mount steps in {:?}",
+            self.plan.id_maps.len(),
+            self.plan.mounts.len(),
+            start.elapsed()
+        );
+        let trace = match trace::enabled() {
+            true => {
+                // Clear of the fds the child moves or keeps open
+                let lowest = self.plan.fds_above + self.fd_maps.len() as RawFd;
+                let exec = self
+                    .exec
+                    .as_ref()
+                    .map(|exec| format!("execute {}", exec.bin.to_string_lossy()));
+                let channel = trace::Channel::new(&self.plan, lowest, exec)?;
+                self.trace = Some(channel.fd());
+                self.plan.keep_fds.push(channel.fd());
+                self.plan.keep_fds.sort_unstable();
+                Some(channel)
+            }
+            false => None,
+        };
         let mut p: Box<[u8; STACK_SIZE]> = Box::new([0; STACK_SIZE]);
+        let flags = self.clone_flags(self.cgroup_fd.is_some());
+
+        // Hooks and the pty need the parent and the child to take turns,
+        // over a socket pair.
+        let (sync_parent, mut sync_child) = match self.hook_state.is_some() || self.tty {
+            true => {
+                let (parent, child) = UnixStream::pair()?;
+                (Some(parent), Some(child))
+            }
+            false => (None, None),
+        };
+        self.parent_pid = match flags.contains(util::CloneFlags::NEWPID) {
+            true => 0,
+            false => nix::unistd::getpid().as_raw(),
+        };
+        let hooks = self.hooks.clone();
+        let hook_state = self.hook_state.clone();
+        let tty = self.tty;
+        let console_socket = self.console_socket.clone();
+
+        let sys = self.sys;
+        let started = Instant::now();
+        let pid = unsafe {
+            sys.clone(
+                Box::new(move || -> isize {
+                    IS_CHILD.store(true, Ordering::Relaxed);
+
+                    self.run_child(sync_child.take())
+                }),
+                &mut *p,
+                flags,
+                Some(libc::SIGCHLD),
+            )
+        }?;
 
-        let pid = match unsafe { crate::util::clone(
-            Box::new(move || -> isize {
-                unsafe { IS_CHILD = true };
+        log::debug!(
+            "cloned child {} with {:?} in {:?}",
+            pid,
+            plan::clone_flag_names(flags),
+            started.elapsed()
+        );
+        let trace = trace.and_then(|channel| channel.forward(pid, started));
 
-                self.run_child()
-            }),
-            &mut *p,
-            util::CloneFlags::empty(),
-            Some(libc::SIGCHLD),
-        ) } {
-            Ok(it) => it,
-            Err(e) => return Err(e),
+        let mut child = Child {
+            pid: unsafe { rustix::process::Pid::from_raw_unchecked(pid as i32) },
+            pty_master: None,
+            cgroup: None,
+            stdio: Default::default(),
+            started,
+            pid_namespace_init: flags.contains(util::CloneFlags::NEWPID),
+            trace,
         };
+        if let Some(sync) = sync_parent {
+            if let Err(e) = Self::sync_parent(
+                sync,
+                &hooks,
+                hook_state,
+                tty,
+                console_socket.as_deref(),
+                &mut child,
+            ) {
+                let _ = rustix::process::kill_process(child.pid, rustix::process::Signal::Kill);
+                let _ = child.wait();
+                return Err(e);
+            }
+        }
+        Ok(child)
+    }
+
+    /// Move the child into the cgroup directory `fd`.
+    fn enter_cgroup(fd: RawFd) -> nix::Result<()> {
+        use nix::fcntl::{openat, OFlag};
+        let procs = openat(
+            fd,
+            "cgroup.procs",
+            OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+            nix::sys::stat::Mode::empty(),
+        )?;
+        let written = nix::unistd::write(procs, b"0");
+        let _ = nix::unistd::close(procs);
+        written.map(drop)
+    }
 
-        Ok(Child {
-            pid: unsafe { rustix::process::Pid::from_raw_unchecked(pid.try_into().unwrap()) },
+    /// Enter namespaces in `config::NamespaceType::JOIN_ORDER`.
+    pub(crate) fn apply_nsenter(&self) -> Result<(), Failure<'static>> {
+        self.apply_namespace_item(&self.namespace_nsenter.user, CloneFlags::CLONE_NEWUSER)?;
+        self.apply_namespace_item(&self.namespace_nsenter.cgroup, CloneFlags::CLONE_NEWCGROUP)?;
+        self.apply_namespace_item(&self.namespace_nsenter.ipc, CloneFlags::CLONE_NEWIPC)?;
+        self.apply_namespace_item(&self.namespace_nsenter.uts, CloneFlags::CLONE_NEWUTS)?;
+        self.apply_namespace_item(&self.namespace_nsenter.network, CloneFlags::CLONE_NEWNET)?;
+        self.apply_namespace_item(&self.namespace_nsenter.pid, CloneFlags::CLONE_NEWPID)?;
+        self.apply_namespace_item(&self.namespace_nsenter.mount, CloneFlags::CLONE_NEWNS)
+    }
+
+    /// Paths to join `namespaces` of `target` with, the ones the child is
+    /// already in are skipped at that time.
+    fn plan_join(
+        target: config::JoinTarget,
+        namespaces: &[config::NamespaceType],
+    ) -> Result<setup::Join, Error> {
+        let proc = PathBuf::from(format!("/proc/{}", target.pid()?));
+        let namespaces = config::NamespaceType::JOIN_ORDER
+            .into_iter()
+            .filter(|ns| namespaces.contains(ns))
+            .map(|ns| {
+                let ours = Path::new("/proc/self/ns").join(ns.proc_name());
+                let theirs = proc.join("ns").join(ns.proc_name());
+                Ok((ns, cstr(&ours)?, cstr(&theirs)?))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(setup::Join {
+            target,
+            namespaces,
+            root: cstr(&proc.join("root"))?,
+            cwd: cstr(&proc.join("cwd"))?,
+            proc: cstr(&proc)?,
         })
     }
 
-    pub(crate) fn apply_nsenter(&mut self) {
-        Self::apply_namespace_item(self.namespace_nsenter.user, CloneFlags::CLONE_NEWUSER);
-        Self::apply_namespace_item(self.namespace_nsenter.mount, CloneFlags::CLONE_NEWNS);
-        Self::apply_namespace_item(self.namespace_nsenter.cgroup, CloneFlags::CLONE_NEWCGROUP);
-        Self::apply_namespace_item(self.namespace_nsenter.uts, CloneFlags::CLONE_NEWUTS);
-        Self::apply_namespace_item(self.namespace_nsenter.ipc, CloneFlags::CLONE_NEWIPC);
-        Self::apply_namespace_item(self.namespace_nsenter.pid, CloneFlags::CLONE_NEWPID);
-        Self::apply_namespace_item(self.namespace_nsenter.network, CloneFlags::CLONE_NEWNET);
+    /// Join the namespaces of `join`, and its root and working directory
+    /// if the mount namespace is joined.
+    ///
+    /// All namespaces are joined at once with `setns(2)` on a pidfd, or
+    /// one at a time through `/proc/<pid>/ns` before Linux 5.8. The ones
+    /// the child is already in are skipped. Returns whether the pid
+    /// namespace was joined, which only takes effect for new children.
+    pub(crate) fn join_process<'a>(
+        sys: &dyn Syscalls,
+        join: &'a setup::Join,
+    ) -> Result<bool, Failure<'a>> {
+        use config::NamespaceType;
+        use nix::fcntl::OFlag;
+
+        let open_path = |path, flags| sys.open(path, flags).context_at("open", path);
+
+        sys.file_id(&join.proc).context_at("join", &join.proc)?;
+        let mut joining = [None; NamespaceType::JOIN_ORDER.len()];
+        for (slot, (ns, ours, theirs)) in joining.iter_mut().zip(&join.namespaces) {
+            let differs = match (sys.file_id(ours), sys.file_id(theirs)) {
+                (Ok(a), Ok(b)) => a != b,
+                // Not supported by the kernel
+                _ => false,
+            };
+            if differs {
+                *slot = Some((*ns, theirs.as_c_str()));
+            }
+        }
+        let joins = |typ| joining.iter().flatten().any(|(ns, _)| *ns == typ);
+
+        // Opened before joining, as `/proc` may be another one afterwards
+        let dirs = match joins(NamespaceType::Mount) {
+            true => Some((
+                open_path(&join.root, OFlag::O_PATH | OFlag::O_DIRECTORY)?,
+                open_path(&join.cwd, OFlag::O_PATH | OFlag::O_DIRECTORY)?,
+            )),
+            false => None,
+        };
+        let mut ns_fds = [-1; NamespaceType::JOIN_ORDER.len()];
+        for (fd, (_, path)) in ns_fds
+            .iter_mut()
+            .zip(&joining)
+            .filter_map(|(fd, slot)| Some((fd, (*slot)?)))
+        {
+            *fd = open_path(path, OFlag::O_RDONLY)?;
+        }
+
+        let flags = joining
+            .iter()
+            .flatten()
+            .fold(0, |f, (ns, _)| f | ns.clone_flag());
+        let pidfd = match join.target {
+            config::JoinTarget::Pidfd(fd) => Ok(fd),
+            config::JoinTarget::Pid(pid) => sys.pidfd_open(pid),
+        };
+        let joined = flags != 0 && pidfd.is_ok_and(|fd| sys.setns(fd, flags).is_ok());
+        if let (config::JoinTarget::Pid(_), Ok(fd)) = (&join.target, pidfd) {
+            let _ = sys.close(fd);
+        }
+        for (slot, fd) in joining.iter().zip(ns_fds) {
+            if let Some((ns, path)) = slot {
+                if !joined {
+                    sys.setns(fd, ns.clone_flag()).context_at("setns", path)?;
+                }
+                let _ = sys.close(fd);
+            }
+        }
+
+        if let Some((root, cwd)) = dirs {
+            sys.fchdir(root).context_at("fchdir", &join.root)?;
+            sys.chroot(c".").context_at("chroot", &join.root)?;
+            sys.fchdir(cwd).context_at("fchdir", &join.cwd)?;
+            let _ = sys.close(root);
+            let _ = sys.close(cwd);
+        }
+        Ok(joins(NamespaceType::Pid))
+    }
+
+    /// Fork so that the rest runs in the joined pid namespace, and wait
+    /// for it in the original child.
+    ///
+    /// Returns in the new process, the original child exits with its
+    /// status, or 128 plus the signal that killed it. Until then, it
+    /// forwards `FORWARDED_SIGNALS` to the new process and stops whenever
+    /// it stops. SIGSTOP and SIGKILL cannot be forwarded: the new process
+    /// is killed by its parent death signal when the original child dies,
+    /// unless `Process::parent_death_signal` sets another one.
+    fn fork_into_pid_namespace(&self) -> Result<(), Failure<'static>> {
+        use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+
+        // Blocked until the handlers are installed in the original child
+        let mut forwarded: libc::sigset_t = unsafe { std::mem::zeroed() };
+        let mut old: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut forwarded);
+            for sig in FORWARDED_SIGNALS {
+                libc::sigaddset(&mut forwarded, sig);
+            }
+        }
+        Errno::result(unsafe { libc::sigprocmask(libc::SIG_BLOCK, &forwarded, &mut old) })
+            .context("sigprocmask")?;
+        let restore =
+            || unsafe { libc::sigprocmask(libc::SIG_SETMASK, &old, std::ptr::null_mut()) };
+
+        let child = match self.sys.fork().context("fork") {
+            Ok(0) => {
+                restore();
+                return Self::set_parent_death_signal_to(libc::SIGKILL, 0);
+            }
+            Ok(child) => child as libc::pid_t,
+            Err(e) => {
+                restore();
+                return Err(e);
+            }
+        };
+        FORKED_PID.store(child, Ordering::Relaxed);
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction =
+                forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            for sig in FORWARDED_SIGNALS {
+                libc::sigaction(sig, &action, std::ptr::null_mut());
+            }
+        }
+        restore();
+        loop {
+            match waitpid(
+                nix::unistd::Pid::from_raw(child),
+                Some(WaitPidFlag::WUNTRACED),
+            ) {
+                Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+                Ok(WaitStatus::Signaled(_, sig, _)) => unsafe { libc::_exit(128 + sig as i32) },
+                // The forwarded SIGCONT resumes both
+                Ok(WaitStatus::Stopped(..)) => unsafe {
+                    libc::raise(libc::SIGSTOP);
+                },
+                Err(Errno::EINTR) | Ok(_) => (),
+                Err(_) => unsafe { libc::_exit(1) },
+            }
+        }
     }
 
-    pub(crate) fn apply_unshare(&mut self) {
-        Self::apply_namespace_item(self.namespace_unshare.user, CloneFlags::CLONE_NEWUSER);
-        Self::apply_namespace_item(self.namespace_unshare.mount, CloneFlags::CLONE_NEWNS);
-        Self::apply_namespace_item(self.namespace_unshare.cgroup, CloneFlags::CLONE_NEWCGROUP);
-        Self::apply_namespace_item(self.namespace_unshare.uts, CloneFlags::CLONE_NEWUTS);
-        Self::apply_namespace_item(self.namespace_unshare.ipc, CloneFlags::CLONE_NEWIPC);
-        Self::apply_namespace_item(self.namespace_unshare.pid, CloneFlags::CLONE_NEWPID);
-        Self::apply_namespace_item(self.namespace_unshare.network, CloneFlags::CLONE_NEWNET);
+    pub(crate) fn apply_unshare(&self) -> Result<(), Failure<'static>> {
+        self.apply_namespace_item(&self.namespace_unshare.user, CloneFlags::CLONE_NEWUSER)?;
+        self.apply_namespace_item(&self.namespace_unshare.mount, CloneFlags::CLONE_NEWNS)?;
+        self.apply_namespace_item(&self.namespace_unshare.cgroup, CloneFlags::CLONE_NEWCGROUP)?;
+        self.apply_namespace_item(&self.namespace_unshare.uts, CloneFlags::CLONE_NEWUTS)?;
+        self.apply_namespace_item(&self.namespace_unshare.ipc, CloneFlags::CLONE_NEWIPC)?;
+        self.apply_namespace_item(&self.namespace_unshare.pid, CloneFlags::CLONE_NEWPID)?;
+        self.apply_namespace_item(&self.namespace_unshare.network, CloneFlags::CLONE_NEWNET)
     }
 
-    fn apply_namespace_item(ns: config::NamespaceItem, flag: CloneFlags) {
+    fn apply_namespace_item(
+        &self,
+        ns: &config::NamespaceItem,
+        flag: CloneFlags,
+    ) -> Result<(), Failure<'static>> {
         match ns {
-            config::NamespaceItem::None => (),
-            config::NamespaceItem::Unshare => {
-                nix::sched::unshare(flag).unwrap();
-            }
-            config::NamespaceItem::Enter(fd) => {
-                nix::sched::setns(fd, flag).unwrap();
+            config::NamespaceItem::None => Ok(()),
+            config::NamespaceItem::Unshare => self.sys.unshare(flag).context("unshare"),
+            config::NamespaceItem::Enter(ns) => {
+                self.sys.setns(ns.as_raw_fd(), flag.bits()).context("setns")
             }
         }
     }
 
-    pub(crate) fn write_id_map<S: AsRef<OsStr>>(file: S, map: &Vec<config::IdMap>) {
-        let file = OpenOptions::new().write(true).open(file.as_ref()).unwrap();
-        let mut content = OsString::new();
+    /// Content of an id map file for `map`.
+    pub(crate) fn id_map_content(map: &[config::IdMap]) -> Vec<u8> {
+        let mut content = String::new();
         for i in map {
-            content.push(format!("{}", i.container_id()));
-            content.push(" ");
-            content.push(format!("{}", i.host_id()));
-            content.push(" ");
-            content.push(format!("{}\n", i.size()));
+            content.push_str(&format!(
+                "{} {} {}\n",
+                i.container_id(),
+                i.host_id(),
+                i.size()
+            ));
         }
-        nix::unistd::write(file.into_raw_fd(), content.as_bytes()).unwrap();
+        content.into_bytes()
     }
 
-    pub(crate) fn set_id_map(&self) {
-        let pid = util::get_pid();
-        Self::write_id_map(format!("/proc/{}/uid_map", pid), &self.uid_maps);
+    /// Writes of the id mappings of the child itself.
+    ///
+    /// `/proc/self` is used rather than the pid, which is different
+    /// inside a new pid namespace.
+    fn plan_id_maps(&self) -> Result<Vec<Step>, Error> {
+        let write = |path: &str, data: Vec<u8>| {
+            Ok::<_, Error>(Step::WriteFile {
+                path: cstr(path)?,
+                data,
+            })
+        };
+        let mut steps = Vec::new();
+        if !self.uid_maps.is_empty() {
+            steps.push(write(
+                "/proc/self/uid_map",
+                Self::id_map_content(&self.uid_maps),
+            )?);
+        }
 
         // Write /proc/pid/setgroups before wite /proc/pid/gid_map, or it will fail.
         // See https://manpages.opensuse.org/Tumbleweed/man-pages/user_namespaces.7.en.html
-        let file = OpenOptions::new()
-            .write(true)
-            .open(format!("/proc/{}/setgroups", pid))
-            .unwrap();
-        nix::unistd::write(file.into_raw_fd(), b"deny").unwrap();
+        steps.push(write("/proc/self/setgroups", b"deny".to_vec())?);
+
+        if !self.gid_maps.is_empty() {
+            steps.push(write(
+                "/proc/self/gid_map",
+                Self::id_map_content(&self.gid_maps),
+            )?);
+        }
+        Ok(steps)
+    }
+
+    /// Writes of the sysctls under `proc`, checking that the namespace
+    /// each one belongs to is unshared.
+    fn plan_sysctls(&self, proc: &Path) -> Result<Vec<Step>, Error> {
+        use config::{NamespaceItem, NamespaceType};
+
+        let mut steps = Vec::new();
+        for (key, value) in &self.sysctls {
+            let path = match key.contains('/') {
+                true => key.clone(),
+                false => key.replace('.', "/"),
+            };
+            if path
+                .split('/')
+                .any(|part| part.is_empty() || part == "." || part == "..")
+            {
+                return Err(Error::Sysctl(format!("malformed key {:?}", key)));
+            }
+            // Interface names may contain dots, which a dotted key cannot
+            // tell apart from separators
+            let parts: Vec<_> = path.split('/').collect();
+            if !key.contains('/')
+                && parts.len() > 5
+                && parts[0] == "net"
+                && matches!(parts[2], "conf" | "neigh")
+            {
+                return Err(Error::Sysctl(format!(
+                    "ambiguous key {:?}, separate it by slashes if the interface name contains a dot",
+                    key
+                )));
+            }
+            let namespace = sysctl_namespace(&path.replace('/', "."))
+                .ok_or_else(|| Error::Sysctl(format!("{} is not namespaced", key)))?;
+            let unshare = match namespace {
+                NamespaceType::Network => &self.namespace_unshare.network,
+                NamespaceType::Ipc => &self.namespace_unshare.ipc,
+                _ => &self.namespace_unshare.uts,
+            };
+            if !matches!(unshare, NamespaceItem::Unshare) {
+                return Err(Error::Sysctl(format!(
+                    "{} needs a new {:?} namespace",
+                    key, namespace
+                )));
+            }
+            steps.push(Step::WriteFile {
+                path: cstr(&proc.join("sys").join(path))?,
+                data: value.clone().into_bytes(),
+            });
+        }
+        Ok(steps)
+    }
 
-        Self::write_id_map(format!("/proc/{}/gid_map", pid), &self.uid_maps);
+    /// Replace the child with the program, which only returns if
+    /// `execve(2)` fails, with 127 like a shell does.
+    fn execute(&self) -> isize {
+        let Some(exec) = &self.exec else {
+            return 1;
+        };
+        let start = Instant::now();
+        self.trace(Stage::Exec, 0, start, Ok(()));
+        let errno = self
+            .sys
+            .execvpe(&exec.bin, &exec.argv_ptrs, &exec.envp_ptrs);
+        self.trace(Stage::Exec, 0, start, Err(Some(errno)));
+        Failure {
+            what: "execute",
+            path: Some(&exec.bin),
+            errno,
+        }
+        .report();
+        127
+    }
+
+    /// Apply rlimits, user and `prctl(2)` attributes of `process`.
+    ///
+    /// `parent_pid` is checked again for the parent death signal, which
+    /// is cleared by changing the user.
+    fn set_process_attrs(
+        exec: &Exec,
+        process: &config::Process,
+        parent_pid: libc::pid_t,
+    ) -> Result<(), Failure<'static>> {
+        use nix::unistd::{setresgid, setresuid, Gid, Uid};
+
+        for (resource, limit) in &exec.rlimits {
+            let ret = unsafe { libc::setrlimit(*resource, limit) };
+            Errno::result(ret).context("setrlimit")?;
+        }
+        if let Some(user) = process.user() {
+            if let Some(umask) = user.umask() {
+                nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(umask));
+            }
+            let gid = Gid::from_raw(user.gid());
+            setresgid(gid, gid, gid).context("setresgid")?;
+            let uid = Uid::from_raw(user.uid());
+            setresuid(uid, uid, uid).context("setresuid")?;
+            if let Some(sig) = process.parent_death_signal() {
+                Self::set_parent_death_signal_to(sig, parent_pid)?;
+            }
+        }
+        if let Some(dumpable) = process.dumpable() {
+            Self::prctl(libc::PR_SET_DUMPABLE, dumpable as libc::c_ulong)
+                .context("prctl(PR_SET_DUMPABLE)")?;
+        }
+        if process.child_subreaper() {
+            Self::prctl(libc::PR_SET_CHILD_SUBREAPER, 1)
+                .context("prctl(PR_SET_CHILD_SUBREAPER)")?;
+        }
+        if process.no_new_privileges() {
+            Self::prctl(libc::PR_SET_NO_NEW_PRIVS, 1).context("prctl(PR_SET_NO_NEW_PRIVS)")?;
+        }
+        Ok(())
+    }
+
+    /// Set the parent death signal of the process, if any.
+    fn set_parent_death_signal(&self) -> Result<(), Failure<'static>> {
+        match self.process.as_ref().and_then(|p| p.parent_death_signal()) {
+            Some(sig) => Self::set_parent_death_signal_to(sig, self.parent_pid),
+            None => Ok(()),
+        }
+    }
+
+    /// Set the parent death signal to `sig`, and raise it if the parent
+    /// `parent_pid` died before, as the child has been reparented then.
+    fn set_parent_death_signal_to(
+        sig: libc::c_int,
+        parent_pid: libc::pid_t,
+    ) -> Result<(), Failure<'static>> {
+        Self::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong)
+            .context("prctl(PR_SET_PDEATHSIG)")?;
+        if nix::unistd::getppid().as_raw() != parent_pid {
+            unsafe { libc::raise(sig) };
+        }
+        Ok(())
+    }
+
+    fn prctl(option: libc::c_int, arg: libc::c_ulong) -> nix::Result<()> {
+        let ret = unsafe { libc::prctl(option, arg, 0, 0, 0) };
+        Errno::result(ret).map(drop)
     }
 
     pub(crate) fn execute_callbacks(&mut self) -> isize {
         let mut ret = 0;
-        for _i in 0..self.callbacks.len() {
-            ret = self.callbacks.pop_front().unwrap()();
+        for cb in self.callbacks.iter_mut() {
+            ret = cb();
+        }
+        ret
+    }
+
+    /// Mounts of all `config::Mount` entries in the order they were added.
+    ///
+    /// Sources of bind mounts are looked up under `old_root`, and
+    /// destinations are placed under `new_root`.
+    fn plan_mounts(
+        &self,
+        old_root: &Path,
+        new_root: &Path,
+        steps: &mut Vec<Step>,
+    ) -> Result<(), Error> {
+        for mnt in &self.mounts {
+            let dest = new_root.join(strip_root(mnt.destination()));
+            match mnt.typ().as_deref() {
+                Some("dev") => Self::plan_dev(old_root, &dest, steps)?,
+                Some("dir") => steps.push(Step::MountPoint {
+                    path: cstr(&dest)?,
+                    like: None,
+                }),
+                Some("symlink") => steps.push(Step::Symlink {
+                    target: cstr(mnt.source().as_deref().unwrap_or(Path::new("")))?,
+                    link: cstr(&dest)?,
+                }),
+                Some("proc") => self.plan_proc(mnt, &dest, steps)?,
+                _ if mnt.is_bind() => {
+                    let source = mnt.source().as_deref().unwrap_or(Path::new(""));
+                    let source = old_root.join(strip_root(source));
+                    Self::plan_mount_entry(mnt, Some(&source), &dest, steps)?
+                }
+                _ => Self::plan_mount_entry(mnt, mnt.source().as_deref(), &dest, steps)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Mount `source` on `dest` following options of `mnt`, then apply
+    /// its propagation type if there is one.
+    fn plan_mount_entry(
+        mnt: &config::Mount,
+        source: Option<&Path>,
+        dest: &Path,
+        steps: &mut Vec<Step>,
+    ) -> Result<(), Error> {
+        let opts = mnt.parse_options();
+        let data = Some(opts.data.as_str())
+            .filter(|d| !d.is_empty())
+            .map(cstr)
+            .transpose()?;
+        let target = cstr(dest)?;
+        if mnt.is_bind() {
+            let source = source.map(cstr).transpose()?;
+            steps.push(Step::MountPoint {
+                path: target.clone(),
+                like: source.clone(),
+            });
+            steps.push(Step::Mount {
+                source,
+                target: target.clone(),
+                fstype: None,
+                flags: MsFlags::MS_BIND | (opts.flags & MsFlags::MS_REC),
+                data: None,
+            });
+            // Flags other than MS_REC are ignored while creating a bind mount,
+            // they only take effect on remount.
+            let remount_flags = opts.flags - MsFlags::MS_BIND - MsFlags::MS_REC;
+            if !remount_flags.is_empty() {
+                steps.push(Step::RemountBind {
+                    target: target.clone(),
+                    flags: remount_flags,
+                    data,
+                });
+            }
+        } else {
+            steps.push(Step::MountPoint {
+                path: target.clone(),
+                like: None,
+            });
+            let typ = mnt.typ().as_deref();
+            let source = source.map(Path::as_os_str).or(typ.map(OsStr::new));
+            steps.push(Step::Mount {
+                source: source.map(cstr).transpose()?,
+                target: target.clone(),
+                fstype: typ.map(cstr).transpose()?,
+                flags: opts.flags,
+                data,
+            });
         }
-        return ret;
+        if let Some(propagation) = opts.propagation {
+            steps.push(Step::propagation(target, propagation));
+        }
+        Ok(())
     }
 
-    /// Crate tmpfs as root, simulate brwrap's behaviour
+    /// Mount a new `proc(5)` on `dest`, with the parts that can change
+    /// the host covered by read-only bind mounts, like `bwrap --proc`.
     ///
-    /// Due to kernel bug#183461 ,this can only be called after setup uid
+    /// The sysctls are written in between.
+    fn plan_proc(
+        &self,
+        mnt: &config::Mount,
+        dest: &Path,
+        steps: &mut Vec<Step>,
+    ) -> Result<(), Error> {
+        Self::plan_mount_entry(mnt, Some(Path::new("proc")), dest, steps)?;
+        steps.extend(self.plan_sysctls(dest)?);
+        for sub in ["sys", "sysrq-trigger", "irq", "bus"] {
+            steps.push(Step::ProtectIfExists(cstr(&dest.join(sub))?));
+        }
+        Ok(())
+    }
+
+    /// Create a minimal `/dev` on `dest`, like `bwrap --dev`.
+    ///
+    /// Device nodes are bind mounted from `old_root`, and a new
+    /// `devpts` instance is mounted for pseudo-terminals.
+    fn plan_dev(old_root: &Path, dest: &Path, steps: &mut Vec<Step>) -> Result<(), Error> {
+        let mount = |source: &str, target: &Path, fstype, flags, data: &str| {
+            Ok::<_, Error>(Step::Mount {
+                source: Some(cstr(source)?),
+                target: cstr(target)?,
+                fstype: Some(cstr(fstype)?),
+                flags,
+                data: Some(cstr(data)?),
+            })
+        };
+        let dir = |path: &Path| {
+            Ok::<_, Error>(Step::MountPoint {
+                path: cstr(path)?,
+                like: None,
+            })
+        };
+        let symlink = |target: &str, link: &str| {
+            Ok::<_, Error>(Step::Symlink {
+                target: cstr(target)?,
+                link: cstr(&dest.join(link))?,
+            })
+        };
+
+        steps.push(dir(dest)?);
+        steps.push(mount(
+            "tmpfs",
+            dest,
+            "tmpfs",
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            "mode=0755",
+        )?);
+
+        for node in DEV_NODES {
+            let source = cstr(&old_root.join("dev").join(node))?;
+            let node = cstr(&dest.join(node))?;
+            steps.push(Step::MountPoint {
+                path: node.clone(),
+                like: Some(source.clone()),
+            });
+            steps.push(Step::Mount {
+                source: Some(source),
+                target: node,
+                fstype: None,
+                flags: MsFlags::MS_BIND,
+                data: None,
+            });
+        }
+
+        let pts = dest.join("pts");
+        steps.push(dir(&pts)?);
+        steps.push(mount(
+            "devpts",
+            &pts,
+            "devpts",
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            "newinstance,ptmxmode=0666,mode=620",
+        )?);
+        steps.push(symlink("pts/ptmx", "ptmx")?);
+
+        steps.push(dir(&dest.join("shm"))?);
+        steps.push(symlink("/proc/self/fd", "fd")?);
+        steps.push(symlink("/proc/self/fd/0", "stdin")?);
+        steps.push(symlink("/proc/self/fd/1", "stdout")?);
+        steps.push(symlink("/proc/self/fd/2", "stderr")?);
+        steps.push(symlink("/proc/kcore", "core")?);
+        Ok(())
+    }
+
+    /// Create an empty tmpfs as root, simulate brwrap's behaviour
+    ///
+    /// The new root is prepared in a private tmpfs mounted on
+    /// `STAGING_PATH`, with the old root available under `/oldroot`
+    /// so that `config::Mount` entries can be placed into it.
+    /// If `root` is set, the new root is a bind mount of its path
+    /// rather than an empty directory.
+    /// The old root is detached before switching to the new root, so the
+    /// host file system is only reachable through these mounts.
+    ///
+    /// Due to kernel bug#183461 ,this can only be run after setup uid
     /// and gid mapping.
-    pub(crate) fn set_up_tmpfs_cwd(&self) {
-        use nix::mount::{mount, MsFlags};
-        use nix::unistd::pivot_root;
-        use std::env::set_current_dir;
-        use std::fs::DirBuilder;
-        use std::os::unix::fs::DirBuilderExt;
-
-        let tmp_path = "/tmp";
-        //
-        mount(
-            Some(""),
-            "/",
-            Some(""),
-            MsFlags::MS_SILENT | MsFlags::MS_SLAVE | MsFlags::MS_REC,
-            Some(""),
-        )
-        .unwrap();
-
-        mount(
-            Some("tmpfs"),
-            tmp_path,
-            Some("tmpfs"),
-            MsFlags::MS_NODEV | MsFlags::MS_NOSUID,
-            Some(""),
-        )
-        .unwrap();
-
-        set_current_dir(tmp_path).unwrap();
-
-        let mut dir = DirBuilder::new();
-        dir.mode(0o755);
-        dir.create("/tmp/newroot").unwrap();
-        dir.create("oldroot").unwrap();
-        mount(
-            Some("newroot"),
-            "newroot",
-            Some(""),
-            MsFlags::MS_SILENT | MsFlags::MS_MGC_VAL | MsFlags::MS_BIND | MsFlags::MS_REC,
-            Some(""),
-        )
-        .unwrap();
-
-        pivot_root(tmp_path, "oldroot").unwrap();
+    fn plan_tmpfs_cwd(&self, steps: &mut Vec<Step>) -> Result<(), Error> {
+        let chdir = |path: &str| Ok::<_, Error>(Step::Chdir(cstr(path)?));
+        let pivot_root = |new_root: &str, put_old: &str| {
+            Ok::<_, Error>(Step::PivotRoot {
+                new_root: cstr(new_root)?,
+                put_old: cstr(put_old)?,
+            })
+        };
+        let umount = |target: &str| {
+            Ok::<_, Error>(Step::Umount {
+                target: cstr(target)?,
+                flags: MntFlags::MNT_DETACH,
+            })
+        };
+
+        if self.root_propagation.is_none() {
+            steps.push(Step::propagation(
+                cstr("/")?,
+                config::MountPropagation::default().flags(true),
+            ));
+        }
+
+        steps.push(Step::Mount {
+            source: Some(cstr("tmpfs")?),
+            target: cstr(STAGING_PATH)?,
+            fstype: Some(cstr("tmpfs")?),
+            flags: MsFlags::MS_NODEV | MsFlags::MS_NOSUID,
+            data: Some(cstr("mode=0755")?),
+        });
+        steps.push(Step::propagation(cstr(STAGING_PATH)?, MsFlags::MS_PRIVATE));
+
+        steps.push(chdir(STAGING_PATH)?);
+        for dir in ["newroot", "oldroot"] {
+            steps.push(Step::MountPoint {
+                path: cstr(dir)?,
+                like: None,
+            });
+        }
+
+        steps.push(pivot_root(".", "oldroot")?);
+        steps.push(chdir("/")?);
+
+        let newroot_source = match &self.root {
+            Some(root) => Path::new("/oldroot").join(strip_root(root.path())),
+            None => Path::new("/newroot").to_path_buf(),
+        };
+        steps.push(Step::Mount {
+            source: Some(cstr(&newroot_source)?),
+            target: cstr("/newroot")?,
+            fstype: None,
+            flags: MsFlags::MS_BIND | MsFlags::MS_REC,
+            data: None,
+        });
+
+        self.plan_mounts(Path::new("/oldroot"), Path::new("/newroot"), steps)?;
+
+        if let Some(true) = self.root.as_ref().and_then(|r| *r.readonly()) {
+            steps.push(Step::RemountBind {
+                target: cstr("/newroot")?,
+                flags: MsFlags::MS_RDONLY,
+                data: None,
+            });
+        }
+
+        // Unmounting must not propagate back to the host.
+        steps.push(Step::propagation(
+            cstr("/oldroot")?,
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+        ));
+        steps.push(umount("/oldroot")?);
+
+        // Stack the staging tmpfs under the new root, then detach it.
+        steps.push(chdir("/newroot")?);
+        steps.push(pivot_root(".", ".")?);
+        steps.push(umount(".")?);
+        steps.push(chdir("/")?);
+        Ok(())
     }
 }
 
+/// Run OCI `hooks` in the child with `state`, stopping at the first
+/// failure, which is `what` at the path of the hook from `paths`.
+///
+/// Failures other than of a system call, like a non-zero exit status,
+/// are reported as `ECANCELED`.
+fn run_hooks<'a>(
+    what: &'static str,
+    hooks: &[config::Hook],
+    paths: &'a [CString],
+    state: &oci::State,
+) -> Result<(), Failure<'a>> {
+    for (hook, path) in hooks.iter().zip(paths) {
+        hook.run(state).map_err(|e| Failure {
+            what,
+            path: Some(path),
+            errno: match e {
+                Error::Io(e) => e.raw_os_error().map_or(Errno::ECANCELED, Errno::from_i32),
+                _ => Errno::ECANCELED,
+            },
+        })?;
+    }
+    Ok(())
+}
+
+/// Namespace type the sysctl `key` belongs to, with parts separated by
+/// dots, `None` if it is not namespaced.
+///
+/// These are the ones `runc(8)` accepts in `linux.sysctl`.
+fn sysctl_namespace(key: &str) -> Option<config::NamespaceType> {
+    use config::NamespaceType;
+    const IPC: [&str; 8] = [
+        "kernel.msgmax",
+        "kernel.msgmnb",
+        "kernel.msgmni",
+        "kernel.sem",
+        "kernel.shmall",
+        "kernel.shmmax",
+        "kernel.shmmni",
+        "kernel.shm_rmid_forced",
+    ];
+    match key {
+        _ if IPC.contains(&key) || key.starts_with("fs.mqueue.") => Some(NamespaceType::Ipc),
+        _ if key.starts_with("net.") => Some(NamespaceType::Network),
+        "kernel.hostname" | "kernel.domainname" => Some(NamespaceType::Uts),
+        _ => None,
+    }
+}
+
+/// Make an absolute path relative, so it can be joined to another root.
+fn strip_root(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::sys::Recorder;
+    use crate::Wrap;
 
     #[test]
     fn test() {
         crate::Wrap::new_cmd("/bin/sh");
     }
+
+    /// Prepared `WrapCore` of `wrap` making its calls to `sys`.
+    fn core<'a>(wrap: &Wrap<'static>, sys: &'a Recorder) -> WrapCore<'a> {
+        let mut core: WrapCore<'a> = wrap.core().unwrap();
+        core.sys = sys;
+        core.prepare().unwrap();
+        core
+    }
+
+    #[test]
+    fn id_maps() {
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Uts)
+            .uid_map(1000, 0, 1)
+            .uid_map(100000, 1, 65536)
+            .gid_map(1000, 0, 1)
+            .hostname("pet");
+        let sys = Recorder::default();
+        let mut core = core(&wrap, &sys);
+        core.plan.stages = vec![Stage::IdMap, Stage::Hostname];
+        assert_eq!(core.run_stages(None), 0);
+        assert_eq!(
+            sys.calls(),
+            [
+                r#"write /proc/self/uid_map "0 1000 1\n1 100000 65536\n""#,
+                r#"write /proc/self/setgroups "deny""#,
+                r#"write /proc/self/gid_map "0 1000 1\n""#,
+                "sethostname pet",
+            ]
+        );
+    }
+
+    #[test]
+    fn sandbox_mounts() {
+        let mut usr = config::Mount::default();
+        usr.set_destination("/usr".into())
+            .set_source(Some("/usr".into()))
+            .set_options(Some(vec!["rbind".into(), "ro".into()]));
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .mount(usr);
+        let sys = Recorder::default().with_file("/oldroot/usr", libc::S_IFDIR);
+        let mounts = core(&wrap, &sys);
+        assert!(mounts.run_steps(Stage::Mount, &mounts.plan.mounts).is_ok());
+        assert_eq!(
+            sys.calls(),
+            [
+                "mount - / - MS_REC|MS_SLAVE -",
+                "mount tmpfs /tmp tmpfs MS_NOSUID|MS_NODEV mode=0755",
+                "mount - /tmp - MS_PRIVATE -",
+                "chdir /tmp",
+                "mkdir newroot",
+                "mkdir oldroot",
+                "pivot_root . oldroot",
+                "chdir /",
+                "mount /newroot /newroot - MS_BIND|MS_REC -",
+                "mkdir /newroot",
+                "mkdir /newroot/usr",
+                "mount /oldroot/usr /newroot/usr - MS_BIND|MS_REC -",
+                "mount - /newroot/usr - MS_RDONLY|MS_REMOUNT|MS_BIND -",
+                "mount - /oldroot - MS_REC|MS_PRIVATE -",
+                "umount2 /oldroot MNT_DETACH",
+                "chdir /newroot",
+                "pivot_root . .",
+                "umount2 . MNT_DETACH",
+                "chdir /",
+            ]
+        );
+
+        // Nothing is done after a failure
+        let sys = Recorder::default().failing("mount", Some("/oldroot/usr"), Errno::ENOENT);
+        let core = core(&wrap, &sys);
+        let e = core
+            .run_steps(Stage::Mount, &core.plan.mounts)
+            .err()
+            .unwrap();
+        assert_eq!((e.what, e.errno), ("mount", Errno::ENOENT));
+        assert_eq!(e.path.unwrap().to_bytes(), b"/newroot/usr");
+        assert_eq!(
+            sys.calls().last().unwrap(),
+            "mount /oldroot/usr /newroot/usr - MS_BIND|MS_REC -"
+        );
+    }
+
+    #[test]
+    fn sysctls() {
+        let mut proc = config::Mount::default();
+        proc.set_destination("/proc".into())
+            .set_typ(Some("proc".into()));
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .unshare(config::NamespaceType::Pid)
+            .unshare(config::NamespaceType::Network)
+            .sysctl("net.ipv4.ping_group_range", "0 2147483647")
+            .sysctl("net/ipv4/conf/lo/forwarding", "1")
+            .mount(proc);
+        let sys = Recorder::default().with_file("/proc/sys", libc::S_IFDIR);
+        let mounts = core(&wrap, &sys);
+        assert!(mounts.run_steps(Stage::Mount, &mounts.plan.mounts).is_ok());
+        assert_eq!(
+            sys.calls(),
+            [
+                "mkdir /proc",
+                "mount proc /proc proc 0 -",
+                "write /proc/sys/net/ipv4/ping_group_range \"0 2147483647\"",
+                "write /proc/sys/net/ipv4/conf/lo/forwarding \"1\"",
+                "mount /proc/sys /proc/sys - MS_BIND -",
+                "mount - /proc/sys - MS_RDONLY|MS_REMOUNT|MS_BIND -",
+            ]
+        );
+
+        // Without a new proc, through the one of the caller
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Ipc)
+            .sysctl("kernel.msgmax", "16384");
+        let sys = Recorder::default();
+        let core = core(&wrap, &sys);
+        assert!(core.run_steps(Stage::Mount, &core.plan.mounts).is_ok());
+        assert_eq!(sys.calls(), ["write /proc/sys/kernel/msgmax \"16384\""]);
+
+        for (key, namespace) in [
+            ("kernel.pid_max", None),
+            ("kernel.shmmax", Some(config::NamespaceType::Ipc)),
+            ("kernel.domainname", Some(config::NamespaceType::Uts)),
+            ("net/../kernel/pid_max", None),
+            ("net.ipv4.conf.eth0.1.forwarding", None),
+            ("net.ipv6.neigh.eth0.1.retrans_time_ms", None),
+            (
+                "net.ipv4.conf.eth0.forwarding",
+                Some(config::NamespaceType::Network),
+            ),
+            (
+                "net/ipv4/conf/eth0.1/forwarding",
+                Some(config::NamespaceType::Network),
+            ),
+        ] {
+            let mut wrap = Wrap::new_cmd("/bin/true");
+            wrap.unshare(config::NamespaceType::User)
+                .unshare(namespace.unwrap_or(config::NamespaceType::Network))
+                .sysctl(key, "1");
+            let ret = wrap.core().unwrap().prepare();
+            match (namespace, ret) {
+                (Some(_), ret) => assert!(ret.is_ok(), "{}", key),
+                (None, Err(Error::Sysctl(e))) => assert!(e.contains(key), "{}", e),
+                (None, _) => panic!("{}", key),
+            }
+            let mut wrap = Wrap::new_cmd("/bin/true");
+            wrap.unshare(config::NamespaceType::User).sysctl(key, "1");
+            let ret = wrap.core().unwrap().prepare();
+            assert!(
+                matches!(ret, Err(Error::Sysctl(e)) if e.contains(key)),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn fd_maps() {
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        // Swap fds 3 and 4 through their copies above 5
+        wrap.fd_map(4, 3).fd_map(3, 4).fd_map(5, 7);
+        let sys = Recorder::default();
+        assert!(core(&wrap, &sys).set_up_fds().is_ok());
+        assert_eq!(
+            sys.calls(),
+            [
+                "dup2 4 8",
+                "dup2 3 9",
+                "dup2 5 10",
+                "dup2 8 3",
+                "close 8",
+                "dup2 9 4",
+                "close 9",
+                "dup2 10 7",
+                "close 10",
+                "close_range 5 6",
+                "close_range 8 4294967295",
+            ]
+        );
+    }
+
+    #[test]
+    fn fd_map_into_preserved_fds() {
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        // Fds 3 to 7 are preserved, 3 is also copied over 4
+        wrap.preserve_fds(5).fd_map(3, 4);
+        let sys = Recorder::default();
+        assert!(core(&wrap, &sys).set_up_fds().is_ok());
+        let calls = sys.calls();
+        assert_eq!(
+            calls,
+            [
+                "dup2 3 8",
+                "dup2 8 4",
+                "close 8",
+                "inherit 3",
+                "inherit 4",
+                "inherit 5",
+                "inherit 6",
+                "inherit 7",
+                "close_range 8 4294967295",
+            ]
+        );
+        // Only 4, the target of the map, is replaced
+        for fd in [3, 5, 6, 7] {
+            let replaces = |call: &String| {
+                (call.starts_with("dup2 ") || call.starts_with("close "))
+                    && call.ends_with(&format!(" {}", fd))
+            };
+            assert!(!calls.iter().any(replaces), "{}", fd);
+        }
+    }
+
+    #[test]
+    fn exec() {
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.arg("x");
+        let sys = Recorder::default().failing("execvpe", None, Errno::ENOENT);
+        let mut core = core(&wrap, &sys);
+        assert_eq!(core.plan.stages.last(), Some(&Stage::Exec));
+        core.plan.stages = vec![Stage::Fds, Stage::Exec];
+        assert_eq!(core.run_stages(None), 127);
+        assert_eq!(
+            sys.calls(),
+            ["close_range 3 4294967295", "execvpe /bin/true /bin/true x"]
+        );
+    }
+
+    /// Key of a recorded call comparable with `operation_keys`, if the
+    /// call is one an operation describes.
+    fn call_key(call: &str) -> Option<String> {
+        let args: Vec<_> = call.split(' ').collect();
+        let arg = match args[0] {
+            "mount" | "symlink" | "setns" => args[2],
+            "umount2" | "pivot_root" | "chdir" | "write" | "sethostname" | "unshare"
+            | "execvpe" => args[1],
+            _ => return None,
+        };
+        Some(format!("{} {}", args[0], arg))
+    }
+
+    /// Keys of the calls `operation` describes, see `call_key`.
+    fn operation_keys(operation: &plan::Operation) -> Vec<String> {
+        use plan::Operation;
+
+        let flag = |ns: &config::NamespaceType| {
+            let flags = util::CloneFlags::from_bits_retain(ns.clone_flag() as u32);
+            plan::clone_flag_names(flags).join("|")
+        };
+        match operation {
+            Operation::Mount { target, .. } | Operation::RemountBind { target, .. } => {
+                vec![format!("mount {}", target.display())]
+            }
+            Operation::Umount { target, .. } => vec![format!("umount2 {}", target.display())],
+            Operation::PivotRoot { new_root, .. } => {
+                vec![format!("pivot_root {}", new_root.display())]
+            }
+            Operation::Chdir { path } => vec![format!("chdir {}", path.display())],
+            Operation::WriteFile { path, .. } => vec![format!("write {}", path.display())],
+            Operation::Symlink { link, .. } => vec![format!("symlink {}", link.display())],
+            Operation::Sethostname { hostname } => vec![format!("sethostname {}", hostname)],
+            Operation::Setns { namespace, .. } => vec![format!("setns {}", flag(namespace))],
+            Operation::Unshare { namespaces } => namespaces
+                .iter()
+                .map(|ns| format!("unshare {}", flag(ns)))
+                .collect(),
+            Operation::Exec { argv, .. } => vec![format!("execvpe {}", argv[0])],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn plan_matches_calls() {
+        let mut usr = config::Mount::default();
+        usr.set_destination("/usr".into())
+            .set_source(Some("/usr".into()))
+            .set_options(Some(vec!["rbind".into(), "ro".into()]));
+        let mut lib = config::Mount::default();
+        lib.set_destination("/lib".into())
+            .set_source(Some("usr/lib".into()))
+            .set_typ(Some("symlink".into()));
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.arg("x")
+            .nsenter(crate::ns::Namespace::open("/proc/self/ns/uts").unwrap())
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .uid_map(1000, 0, 1)
+            .gid_map(1000, 0, 1)
+            .hostname("pet")
+            .sandbox_mnt(true)
+            .mount(usr)
+            .mount(lib)
+            .current_dir("/usr");
+        let sys = Recorder::default()
+            .with_file("/oldroot/usr", libc::S_IFDIR)
+            .failing("execvpe", None, Errno::ENOENT);
+        let mut core = core(&wrap, &sys);
+        // `Stage::Process` changes the test process itself
+        assert_eq!(core.plan.stages[0], Stage::Process);
+        core.plan.stages.remove(0);
+        assert_eq!(core.run_stages(None), 127);
+
+        let calls: Vec<_> = sys.calls().iter().filter_map(|c| call_key(c)).collect();
+        let plan = wrap.plan().unwrap();
+        let ops: Vec<_> = plan.operations().iter().flat_map(operation_keys).collect();
+        assert_eq!(calls, ops);
+        assert!(calls.contains(&"setns CLONE_NEWUTS".to_string()));
+        assert!(calls.contains(&"symlink /newroot/lib".to_string()));
+        assert_eq!(calls.last().unwrap(), "execvpe /bin/true");
+    }
+
+    #[test]
+    fn join_process() {
+        use config::NamespaceType;
+
+        let namespaces = [NamespaceType::Uts, NamespaceType::Mount];
+        let join = WrapCore::plan_join(config::JoinTarget::Pid(42), &namespaces).unwrap();
+        let files = [
+            "/proc/42",
+            "/proc/42/root",
+            "/proc/42/cwd",
+            "/proc/42/ns/uts",
+            "/proc/42/ns/mnt",
+            "/proc/self/ns/uts",
+            "/proc/self/ns/mnt",
+        ];
+        let recorder = || {
+            files.iter().fold(Recorder::default(), |sys, file| {
+                sys.with_file(file, libc::S_IFDIR)
+            })
+        };
+        let sys = recorder();
+        assert_eq!(WrapCore::join_process(&sys, &join).ok(), Some(false));
+        assert_eq!(
+            sys.calls(),
+            [
+                "open /proc/42/root",
+                "open /proc/42/cwd",
+                "open /proc/42/ns/uts",
+                "open /proc/42/ns/mnt",
+                "pidfd_open 42",
+                "setns 1004 CLONE_NEWNS|CLONE_NEWUTS",
+                "close 1004",
+                "close 1002",
+                "close 1003",
+                "fchdir 1000",
+                "chroot .",
+                "fchdir 1001",
+                "close 1000",
+                "close 1001",
+            ]
+        );
+
+        // One namespace at a time without pidfds
+        let sys = recorder().failing("pidfd_open", None, Errno::ENOSYS);
+        assert!(WrapCore::join_process(&sys, &join).is_ok());
+        let calls = sys.calls();
+        assert_eq!(
+            calls[4..8],
+            [
+                "pidfd_open 42",
+                "setns 1002 CLONE_NEWUTS",
+                "close 1002",
+                "setns 1003 CLONE_NEWNS",
+            ]
+        );
+
+        // Namespaces the kernel does not have are skipped, and so are the
+        // root and working directory without the mount namespace
+        let sys = files[..6].iter().fold(Recorder::default(), |sys, file| {
+            sys.with_file(file, libc::S_IFDIR)
+        });
+        assert_eq!(WrapCore::join_process(&sys, &join).ok(), Some(false));
+        assert_eq!(
+            sys.calls(),
+            [
+                "open /proc/42/ns/uts",
+                "pidfd_open 42",
+                "setns 1001 CLONE_NEWUTS",
+                "close 1001",
+                "close 1000",
+            ]
+        );
+
+        // The target has to exist
+        let sys = Recorder::default();
+        let e = WrapCore::join_process(&sys, &join).err().unwrap();
+        assert_eq!((e.what, e.errno), ("join", Errno::ENOENT));
+    }
+
+    #[test]
+    fn clone_failure() {
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Pid);
+        let sys = Recorder::default().failing("clone", None, Errno::EPERM);
+        let ret = core(&wrap, &sys).spwan();
+        assert!(matches!(ret, Err(Error::CloneFailed(Errno::EPERM))));
+        assert_eq!(sys.calls(), ["clone CLONE_NEWUSER|CLONE_NEWPID"]);
+    }
 }
@@ -8,6 +8,20 @@ pub enum Error {
     CloneFailed(nix::errno::Errno),
     #[error("Unix API lib failed: `{0}`")]
     OsErrno(i32),
+    #[error("I/O failed: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid OCI runtime spec: {0}")]
+    InvalidOciSpec(String),
+    #[error("Unsupported fields in OCI runtime spec: {0:?}")]
+    UnsupportedOciFields(Vec<String>),
+    #[error("Hook failed: {0}")]
+    HookFailed(String),
+    #[error("Cgroup failed: {0}")]
+    Cgroup(String),
+    #[error("Landlock failed: {0}")]
+    Landlock(String),
+    #[error("Invalid sysctl: {0}")]
+    Sysctl(String),
     #[error("unknown data store error")]
     Unknown,
-}
\ No newline at end of file
+}
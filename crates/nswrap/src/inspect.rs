@@ -0,0 +1,335 @@
+//! Introspection of the namespaces and container setup of a running
+//! process, as found in `/proc/<pid>`.
+//!
+//! The reports serialize to JSON, for tools like `petbox inspect`.
+
+use crate::{
+    config::{IdMap, JoinTarget, NamespaceType},
+    error::Error,
+};
+use getset::Getters;
+use serde::Serialize;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Container setup of a process.
+#[derive(Getters, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub")]
+pub struct ProcessInfo {
+    /// Process id, in the pid namespace of the caller.
+    pid: i32,
+    /// Namespaces of the process, those the kernel does not support are
+    /// left out.
+    namespaces: Vec<NamespaceInfo>,
+    /// Parsed `/proc/<pid>/uid_map`.
+    uid_map: Vec<IdMap>,
+    /// Parsed `/proc/<pid>/gid_map`.
+    gid_map: Vec<IdMap>,
+    /// Content of `/proc/<pid>/setgroups`, `allow` or `deny`.
+    setgroups: String,
+    /// Root directory, as seen from the caller.
+    root: PathBuf,
+    /// Working directory, as seen from the caller.
+    cwd: PathBuf,
+    /// Parsed `/proc/<pid>/mountinfo`.
+    mounts: Vec<MountInfo>,
+    /// Path of the cgroup in the unified hierarchy, or of the first
+    /// hierarchy listed without cgroup v2.
+    cgroup: Option<String>,
+}
+
+/// A namespace of a process.
+#[derive(Getters, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub")]
+pub struct NamespaceInfo {
+    #[serde(rename = "type")]
+    typ: NamespaceType,
+    /// Inode number identifying the namespace.
+    inode: u64,
+    /// Whether the caller is in another namespace of this type.
+    differs: bool,
+}
+
+/// A line of `mountinfo`, see `proc(5)`.
+#[derive(Getters, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub")]
+pub struct MountInfo {
+    mount_id: u32,
+    parent_id: u32,
+    /// Device as `major:minor`.
+    dev: String,
+    /// Path in the filesystem forming the root of the mount.
+    root: PathBuf,
+    /// Mount point, relative to the root of the process.
+    mount_point: PathBuf,
+    /// Per-mount options, like `rw,nosuid`.
+    options: String,
+    /// Optional fields, like `shared:1` or `master:2`.
+    optional_fields: Vec<String>,
+    fs_type: String,
+    source: String,
+    /// Per-superblock options.
+    super_options: String,
+}
+
+/// Report the container setup of `target`.
+///
+/// Requires the same permissions as reading `/proc/<pid>/ns` of the
+/// process, usually being its owner.
+pub fn inspect(target: JoinTarget) -> Result<ProcessInfo, Error> {
+    let pid = target.pid()?;
+    let proc = PathBuf::from(format!("/proc/{}", pid));
+    let self_ns = Path::new("/proc/self/ns");
+
+    let mut namespaces = Vec::new();
+    for typ in NamespaceType::JOIN_ORDER {
+        let theirs = match std::fs::metadata(proc.join("ns").join(typ.proc_name())) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let ours = std::fs::metadata(self_ns.join(typ.proc_name()))?;
+        namespaces.push(NamespaceInfo {
+            typ,
+            inode: theirs.ino(),
+            differs: (theirs.dev(), theirs.ino()) != (ours.dev(), ours.ino()),
+        });
+    }
+
+    let cgroup = parse_cgroup(&std::fs::read_to_string(proc.join("cgroup"))?);
+    Ok(ProcessInfo {
+        pid,
+        namespaces,
+        uid_map: parse_id_map(&std::fs::read_to_string(proc.join("uid_map"))?)?,
+        gid_map: parse_id_map(&std::fs::read_to_string(proc.join("gid_map"))?)?,
+        setgroups: std::fs::read_to_string(proc.join("setgroups"))?
+            .trim()
+            .to_string(),
+        root: std::fs::read_link(proc.join("root"))?,
+        cwd: std::fs::read_link(proc.join("cwd"))?,
+        mounts: parse_mountinfo(&std::fs::read_to_string(proc.join("mountinfo"))?)?,
+        cgroup,
+    })
+}
+
+/// Processes of the container whose first process is `pid`.
+///
+/// These are the processes in the pid namespace of `pid` if it is not the
+/// one of the caller, or else `pid` and its descendants. Processes the
+/// caller may not inspect are left out.
+pub fn container_processes(pid: i32) -> Result<Vec<i32>, Error> {
+    let pid_ns = |proc: &str| {
+        std::fs::metadata(format!("/proc/{}/ns/pid", proc)).map(|m| (m.dev(), m.ino()))
+    };
+    let target = pid_ns(&pid.to_string())?;
+    let own_ns = target != pid_ns("self")?;
+
+    let mut members = Vec::new();
+    let mut parents = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let name = entry?.file_name();
+        let Some(proc) = name.to_str().filter(|n| n.parse::<i32>().is_ok()) else {
+            continue;
+        };
+        if own_ns {
+            if pid_ns(proc).is_ok_and(|ns| ns == target) {
+                members.push(proc.parse().unwrap());
+            }
+        } else if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", proc)) {
+            // The command name in parentheses may contain spaces
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, s)| s.split_whitespace().nth(1)?.parse::<i32>().ok());
+            if let Some(ppid) = ppid {
+                parents.push((proc.parse::<i32>().unwrap(), ppid));
+            }
+        }
+    }
+    if !own_ns {
+        members.push(pid);
+        let mut i = 0;
+        while i < members.len() {
+            let parent = members[i];
+            members.extend(
+                parents
+                    .iter()
+                    .filter(|(_, p)| *p == parent)
+                    .map(|(c, _)| *c),
+            );
+            i += 1;
+        }
+    }
+    Ok(members)
+}
+
+/// Parse the content of `uid_map` or `gid_map`.
+pub fn parse_id_map(content: &str) -> Result<Vec<IdMap>, Error> {
+    content
+        .lines()
+        .map(|line| {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(|f| f.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| Error::OsErrno(libc::EINVAL))?;
+            match fields[..] {
+                [container_id, host_id, size] => Ok(IdMap {
+                    host_id,
+                    container_id,
+                    size,
+                }),
+                _ => Err(Error::OsErrno(libc::EINVAL)),
+            }
+        })
+        .collect()
+}
+
+/// Parse the content of `mountinfo`.
+pub fn parse_mountinfo(content: &str) -> Result<Vec<MountInfo>, Error> {
+    let invalid = || Error::OsErrno(libc::EINVAL);
+    content
+        .lines()
+        .map(|line| {
+            let (head, tail) = line.split_once(" - ").ok_or_else(invalid)?;
+            let mut head = head.split(' ');
+            let mut tail = tail.split(' ');
+            let next = |fields: &mut std::str::Split<'_, char>| {
+                fields.next().map(unescape).ok_or_else(invalid)
+            };
+            Ok(MountInfo {
+                mount_id: next(&mut head)?.parse().map_err(|_| invalid())?,
+                parent_id: next(&mut head)?.parse().map_err(|_| invalid())?,
+                dev: next(&mut head)?,
+                root: next(&mut head)?.into(),
+                mount_point: next(&mut head)?.into(),
+                options: next(&mut head)?,
+                optional_fields: head.map(unescape).collect(),
+                fs_type: next(&mut tail)?,
+                source: next(&mut tail)?,
+                super_options: next(&mut tail)?,
+            })
+        })
+        .collect()
+}
+
+/// Path of the cgroup in the content of `/proc/<pid>/cgroup`.
+fn parse_cgroup(content: &str) -> Option<String> {
+    let entries: Vec<_> = content
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2).map(|path| (line, path)))
+        .collect();
+    entries
+        .iter()
+        .find(|(line, _)| line.starts_with("0::"))
+        .or(entries.first())
+        .map(|(_, path)| path.to_string())
+}
+
+/// Undo the octal escapes of space, tab, newline and backslash in
+/// `mountinfo`.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|o| o.iter().all(|b| (b'0'..=b'7').contains(b)));
+        match (bytes[i], octal) {
+            (b'\\', Some(o)) => {
+                out.push(o.iter().fold(0u8, |n, b| n.wrapping_mul(8) + (b - b'0')));
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, Wrap};
+
+    #[test]
+    fn parse() {
+        let maps =
+            parse_id_map("         0       1000          1\n         1     100000      65536\n")
+                .unwrap();
+        assert_eq!(maps.len(), 2);
+        assert_eq!(
+            (maps[1].container_id(), maps[1].host_id(), maps[1].size()),
+            (1, 100000, 65536)
+        );
+        assert!(parse_id_map("0 1000\n").is_err());
+
+        let mounts = parse_mountinfo(
+            "36 35 98:0 /mnt1 /mnt\\040dir rw,noatime master:1 shared:2 - ext3 /dev/root rw,errors=continue\n\
+             40 36 0:5 / /proc rw - proc proc rw\n",
+        )
+        .unwrap();
+        assert_eq!(mounts[0].mount_point(), Path::new("/mnt dir"));
+        assert_eq!(mounts[0].optional_fields(), &["master:1", "shared:2"]);
+        assert_eq!(mounts[0].super_options(), "rw,errors=continue");
+        assert_eq!(mounts[1].parent_id(), &36);
+        assert!(mounts[1].optional_fields().is_empty());
+        assert!(parse_mountinfo("36 35 98:0 / /\n").is_err());
+
+        assert_eq!(parse_cgroup("1:cpu:/a\n0::/b/c\n").unwrap(), "/b/c");
+        assert_eq!(parse_cgroup("1:cpu:/a\n").unwrap(), "/a");
+    }
+
+    #[test]
+    fn inspect_child() {
+        let (hold_rd, hold_wr) = nix::unistd::pipe().unwrap();
+        let (ready_rd, ready_wr) = nix::unistd::pipe().unwrap();
+        let mut wrap = Wrap::new();
+        unsafe {
+            wrap.callback(move || {
+                let _ = nix::unistd::close(hold_wr);
+                let _ = nix::unistd::write(ready_wr, &[0]);
+                // Stay alive until the test closes its end of the pipe
+                let _ = nix::unistd::read(hold_rd, &mut [0]);
+                0
+            })
+        }
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Uts)
+        .id_map_preset(config::IdMapPreset::Root);
+        let mut child = wrap.spawn().unwrap();
+        let pid = child.id() as i32;
+        // The id maps are written by the child
+        nix::unistd::read(ready_rd, &mut [0]).unwrap();
+
+        let info = inspect(JoinTarget::Pid(pid)).unwrap();
+        let differs = |typ| {
+            info.namespaces()
+                .iter()
+                .find(|ns| *ns.typ() == typ)
+                .unwrap()
+                .differs
+        };
+        assert!(differs(NamespaceType::User));
+        assert!(differs(NamespaceType::Uts));
+        assert!(!differs(NamespaceType::Network));
+        assert_eq!(info.uid_map().len(), 1);
+        assert_eq!(info.uid_map()[0].container_id(), 0);
+        assert_eq!(info.uid_map()[0].host_id(), nix::unistd::getuid().as_raw());
+        assert_eq!(info.setgroups(), "deny");
+        assert_eq!(info.root(), Path::new("/"));
+        assert!(info
+            .mounts()
+            .iter()
+            .any(|m| m.mount_point() == Path::new("/")));
+        assert!(serde_json::to_value(&info).unwrap()["namespaces"][0]["type"].is_string());
+
+        nix::unistd::close(hold_wr).unwrap();
+        assert_eq!(child.wait().unwrap().code().unwrap(), 0);
+    }
+}
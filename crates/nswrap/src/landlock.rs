@@ -0,0 +1,253 @@
+//! Landlock access restrictions, see `landlock(7)`.
+//!
+//! A ruleset from `config::Landlock` is enforced by the child and
+//! inherited by everything it executes. Unlike the mount based sandbox,
+//! it needs neither namespaces nor privileges.
+
+use crate::{
+    config::{Landlock, LandlockCompat, LandlockFs, LandlockNet},
+    error::Error,
+    plan::{self, Operation},
+    setup::{self, Context, Failure},
+};
+use nix::errno::Errno;
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const LANDLOCK_RULE_NET_PORT: libc::c_int = 2;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct NetPortAttr {
+    allowed_access: u64,
+    port: u64,
+}
+
+/// Landlock ABI version of the running kernel, `None` if Landlock is not
+/// built in or disabled at boot.
+pub fn abi_version() -> Option<u32> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    match ret {
+        -1 => None,
+        abi => Some(abi as u32),
+    }
+}
+
+/// A `config::Landlock` checked against the running kernel, which the
+/// child enforces with `restrict_self`.
+pub(crate) struct Ruleset {
+    attr: RulesetAttr,
+    paths: Vec<(CString, LandlockFs)>,
+    ports: Vec<NetPortAttr>,
+}
+
+/// Check `ruleset` against the ABI of the kernel, dropping the rights it
+/// does not support unless the compatibility is strict.
+///
+/// Returns `None` if Landlock is not supported and the ruleset is best
+/// effort.
+pub(crate) fn prepare(ruleset: &Landlock) -> Result<Option<Ruleset>, Error> {
+    let strict = ruleset.compatibility() == LandlockCompat::Strict;
+    let abi = match abi_version() {
+        Some(abi) => abi,
+        None if strict => return Err(Error::Landlock("not supported by the kernel".into())),
+        None => return Ok(None),
+    };
+    let supported_fs = LandlockFs::for_abi(abi);
+    let supported_net = LandlockNet::for_abi(abi);
+    let handled_fs = ruleset.handled_fs().unwrap_or(supported_fs);
+    let handled_net = ruleset.handled_net();
+    if strict && (!supported_fs.contains(handled_fs) || !supported_net.contains(handled_net)) {
+        return Err(Error::Landlock(format!(
+            "access rights not supported by ABI {}: {:?} {:?}",
+            abi,
+            handled_fs - supported_fs,
+            handled_net - supported_net
+        )));
+    }
+    let handled_fs = handled_fs & supported_fs;
+    let handled_net = handled_net & supported_net;
+
+    let mut paths = Vec::new();
+    for rule in ruleset.paths() {
+        if strict && !handled_fs.contains(rule.access()) {
+            return Err(Error::Landlock(format!(
+                "rule of {} allows rights which are not handled",
+                rule.path().display()
+            )));
+        }
+        paths.push((setup::cstr(rule.path())?, rule.access() & handled_fs));
+    }
+    let mut ports = Vec::new();
+    for rule in ruleset.ports() {
+        if strict && !handled_net.contains(rule.access()) {
+            return Err(Error::Landlock(format!(
+                "rule of port {} allows rights which are not handled",
+                rule.port()
+            )));
+        }
+        let access = rule.access() & handled_net;
+        if !access.is_empty() {
+            ports.push(NetPortAttr {
+                allowed_access: access.bits(),
+                port: rule.port().into(),
+            });
+        }
+    }
+    Ok(Some(Ruleset {
+        attr: RulesetAttr {
+            handled_access_fs: handled_fs.bits(),
+            handled_access_net: handled_net.bits(),
+        },
+        paths,
+        ports,
+    }))
+}
+
+impl Ruleset {
+    /// Describe the ruleset for `Wrap::plan`.
+    pub(crate) fn operation(&self) -> Operation {
+        Operation::Landlock {
+            handled_fs: plan::bitflag_names(LandlockFs::from_bits_retain(
+                self.attr.handled_access_fs,
+            )),
+            handled_net: plan::bitflag_names(LandlockNet::from_bits_retain(
+                self.attr.handled_access_net,
+            )),
+            paths: self
+                .paths
+                .iter()
+                .map(|(path, access)| (setup::path(path), plan::bitflag_names(*access)))
+                .collect(),
+            ports: self
+                .ports
+                .iter()
+                .map(|attr| {
+                    let access = LandlockNet::from_bits_retain(attr.allowed_access);
+                    (attr.port as u16, plan::bitflag_names(access))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Enforce `ruleset` on the calling thread.
+///
+/// Paths of the rules are opened here, as they are looked up in the file
+/// system of the child. This sets `no_new_privs`, as Landlock requires it
+/// without `CAP_SYS_ADMIN`.
+pub(crate) fn restrict_self(ruleset: &Ruleset) -> Result<(), Failure<'_>> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &ruleset.attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    let fd = Errno::result(fd).context("landlock_create_ruleset")? as RawFd;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    for (path, access) in &ruleset.paths {
+        let file = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        let file = Errno::result(file).context_at("landlock open", path)?;
+        let file = unsafe { OwnedFd::from_raw_fd(file) };
+        // Directory rights on a file are invalid, they are dropped as they
+        // have nothing to apply to.
+        let mut access = *access;
+        let st = nix::sys::stat::fstat(file.as_raw_fd()).context_at("landlock stat", path)?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            access &= LandlockFs::FILE;
+        }
+        if access.is_empty() {
+            continue;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access: access.bits(),
+            parent_fd: file.as_raw_fd(),
+        };
+        add_rule(&fd, LANDLOCK_RULE_PATH_BENEATH, &attr as *const _ as _)
+            .context_at("landlock_add_rule", path)?;
+    }
+
+    for attr in &ruleset.ports {
+        add_rule(&fd, LANDLOCK_RULE_NET_PORT, attr as *const _ as _)
+            .context("landlock_add_rule")?;
+    }
+
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(ret).context("prctl(PR_SET_NO_NEW_PRIVS)")?;
+    let ret = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, fd.as_raw_fd(), 0) };
+    Errno::result(ret).context("landlock_restrict_self")?;
+    Ok(())
+}
+
+fn add_rule(fd: &OwnedFd, typ: libc::c_int, attr: *const libc::c_void) -> nix::Result<()> {
+    let ret = unsafe { libc::syscall(libc::SYS_landlock_add_rule, fd.as_raw_fd(), typ, attr, 0) };
+    Errno::result(ret).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Wrap;
+
+    #[test]
+    fn abi() {
+        assert!(LandlockFs::for_abi(1).contains(LandlockFs::MAKE_SYM));
+        assert!(!LandlockFs::for_abi(1).contains(LandlockFs::REFER));
+        assert!(!LandlockFs::for_abi(4).contains(LandlockFs::IOCTL_DEV));
+        assert_eq!(LandlockFs::for_abi(5), LandlockFs::all());
+        assert!(LandlockNet::for_abi(3).is_empty());
+        assert_eq!(LandlockNet::for_abi(4), LandlockNet::all());
+    }
+
+    #[test]
+    fn restrict() {
+        const DIR: &str = "/tmp/nswrap.test.landlock";
+        let Some(abi) = abi_version() else {
+            eprintln!("Landlock is not available, skipping");
+            return;
+        };
+        let _ = std::fs::create_dir(DIR);
+        let _ = std::fs::remove_file("/tmp/nswrap.test.landlock.denied");
+
+        let mut ruleset = Landlock::default();
+        ruleset
+            .allow_path("/", LandlockFs::READ | LandlockFs::EXECUTE)
+            .allow_path("/dev/null", LandlockFs::WRITE_FILE | LandlockFs::READ_DIR)
+            .allow_path(DIR, LandlockFs::for_abi(abi))
+            .set_compatibility(LandlockCompat::Strict);
+        let mut wrap = Wrap::new_cmd("/bin/sh");
+        wrap.args([
+            "-c",
+            "echo ok > /tmp/nswrap.test.landlock/file \
+             && ! echo denied 2> /dev/null > /tmp/nswrap.test.landlock.denied",
+        ])
+        .landlock(ruleset);
+        let ret = wrap.spawn().unwrap().wait().unwrap();
+        assert_eq!(ret.code().unwrap(), 0);
+        assert!(!std::path::Path::new("/tmp/nswrap.test.landlock.denied").exists());
+        std::fs::remove_dir_all(DIR).unwrap();
+    }
+}
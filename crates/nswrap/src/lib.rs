@@ -14,28 +14,67 @@ use getset::{CopyGetters, Getters, Setters};
 use std::{
     collections::VecDeque,
     ffi::{OsStr, OsString},
-    os::{fd::RawFd, unix::process::ExitStatusExt},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, process::ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
+#[cfg(feature = "tokio")]
+pub mod async_child;
+pub mod cgroup;
 pub mod config;
 pub mod core;
 pub mod error;
+pub mod inspect;
+pub mod landlock;
+pub mod ns;
+pub mod oci;
+pub mod plan;
+pub mod probe;
+mod setup;
+mod sys;
+mod trace;
 pub mod util;
 extern crate xdg;
 
 use crate::error::Error;
 
 pub use crate::core::WrapCbBox;
+pub use crate::probe::probe;
+
+/// Environment left by `Wrap::env_clear`, other than `TERM`.
+const DEFAULT_ENV: [&str; 3] = [
+    "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    "HOME=/",
+    "container=petbox",
+];
 
 /// Main class of spawn process and execute functions.
 #[derive(Getters, Setters, CopyGetters, Default)]
 pub struct Wrap<'a> {
     process: Option<config::Process>,
     root: Option<config::Root>,
+    hostname: Option<OsString>,
+    sysctls: Vec<(String, String)>,
 
+    root_propagation: Option<config::MountPropagation>,
     mounts: Vec<config::Mount>,
     uid_maps: Vec<config::IdMap>,
     gid_maps: Vec<config::IdMap>,
     callbacks: VecDeque<WrapCbBox<'a>>,
+    hooks: config::Hooks,
+    hook_state: Option<oci::State>,
+    tty: bool,
+    console_socket: Option<PathBuf>,
+    join: Option<(config::JoinTarget, Vec<config::NamespaceType>)>,
+    cgroup: Option<(cgroup::Cgroup, config::Resources)>,
+    landlock: Option<config::Landlock>,
+    preserve_fds: u32,
+    fd_maps: Vec<(RawFd, RawFd)>,
+    stdio: [config::Stdio; 3],
 
     namespace_nsenter: config::NamespaceSet,
     namespace_unshare: config::NamespaceSet,
@@ -46,12 +85,48 @@ pub struct Wrap<'a> {
 /// The reference to the running child.
 pub struct Child {
     pid: rustix::process::Pid,
+    pty_master: Option<OwnedFd>,
+    cgroup: Option<cgroup::Cgroup>,
+    stdio: [Option<OwnedFd>; 3],
+    /// When `Wrap::spawn` cloned the child.
+    started: Instant,
+    /// Whether the child is pid 1 of a new pid namespace.
+    pid_namespace_init: bool,
+    /// Thread logging the setup of the child, see `trace::Channel`.
+    trace: Option<std::thread::JoinHandle<()>>,
 }
 
 /// Exit status of the child.
+///
+/// Its `Display` reads like the report of a shell, for example
+/// `killed by signal 9 (SIGKILL) in 1.204s (user 0.930s, sys 0.051s, ...)`.
+#[derive(Debug)]
 pub struct ExitStatus {
-    wait_status: rustix::process::WaitStatus,
     std_exit_status: std::process::ExitStatus,
+    rusage: Option<ResourceUsage>,
+    duration: Option<Duration>,
+    pid_namespace_init: bool,
+}
+
+/// Resources used by the child and the descendants it waited for, from
+/// `wait4(2)`.
+#[derive(CopyGetters, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[getset(get_copy = "pub")]
+pub struct ResourceUsage {
+    /// Cpu time in user mode.
+    user_time: Duration,
+    /// Cpu time in kernel mode.
+    system_time: Duration,
+    /// Largest resident set size in bytes.
+    max_rss: u64,
+    /// Page faults served without I/O.
+    minor_faults: u64,
+    /// Page faults that needed I/O.
+    major_faults: u64,
+    /// Context switches from waiting for a resource.
+    voluntary_context_switches: u64,
+    /// Context switches from being preempted.
+    involuntary_context_switches: u64,
 }
 
 /// Core implementation
@@ -70,25 +145,316 @@ impl<'a> Wrap<'a> {
         s
     }
 
+    /// Adds an argument to pass to the program.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.process_mut()
+            .args_mut()
+            .push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds multiple arguments to pass to the program.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Sets the working directory for the program, relative to the
+    /// root inside the container.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.process_mut().set_cwd(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Inserts or updates an environment variable of the program.
+    ///
+    /// The environment of the parent is copied on first use, so other
+    /// variables are still inherited.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        let key = key.as_ref();
+        self.env_remove(key);
+        let mut var = key.to_os_string();
+        var.push("=");
+        var.push(val);
+        self.process_env_mut().push(var);
+        self
+    }
+
+    /// Removes an environment variable of the program.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        let prefix = [key.as_ref().as_bytes(), b"="].concat();
+        self.process_env_mut()
+            .retain(|e| !e.as_bytes().starts_with(&prefix));
+        self
+    }
+
+    /// Clears the environment of the program, except for defaults a
+    /// container expects: `PATH`, `HOME`, `TERM` of the parent if it is
+    /// set, and `container=petbox`.
+    pub fn env_clear(&mut self) -> &mut Self {
+        let env = self.process_mut().env_mut().insert(Vec::new());
+        env.extend(DEFAULT_ENV.iter().map(OsString::from));
+        if let Some(term) = std::env::var_os("TERM") {
+            let mut var = OsString::from("TERM=");
+            var.push(term);
+            env.push(var);
+        }
+        self
+    }
+
+    /// Copies the variables `keys` from the environment of the parent, if
+    /// they are set, to pass only some of them after `env_clear`.
+    pub fn inherit_env<K: AsRef<OsStr>>(&mut self, keys: &[K]) -> &mut Self {
+        for key in keys {
+            if let Some(val) = std::env::var_os(key.as_ref()) {
+                self.env(key, val);
+            }
+        }
+        self
+    }
+
+    /// Sets `no_new_privs` for the program, so it can not gain privileges
+    /// by executing set-user-ID or file capability binaries.
+    pub fn no_new_privs(&mut self, no_new_privs: bool) -> &mut Self {
+        self.process_mut().set_no_new_privileges(no_new_privs);
+        self
+    }
+
+    /// Sends `signal` to the child when the thread calling `spawn` exits.
+    pub fn parent_death_signal(&mut self, signal: i32) -> &mut Self {
+        self.process_mut().set_parent_death_signal(Some(signal));
+        self
+    }
+
+    /// Kill the child with `SIGKILL` when the thread calling `spawn`
+    /// exits, like `bwrap --die-with-parent`.
+    ///
+    /// Threads other than the main one exit before the process does, so
+    /// spawn from a thread which lives as long as the child should. The
+    /// exit of such a thread before the child has set the signal is not
+    /// detected, as the parent process is still alive.
+    pub fn die_with_parent(&mut self) -> &mut Self {
+        self.parent_death_signal(libc::SIGKILL)
+    }
+
+    /// Sets whether the program can be core dumped and ptraced.
+    pub fn dumpable(&mut self, dumpable: bool) -> &mut Self {
+        self.process_mut().set_dumpable(Some(dumpable));
+        self
+    }
+
+    /// Makes the program reap orphaned descendants, like an init.
+    pub fn child_subreaper(&mut self, child_subreaper: bool) -> &mut Self {
+        self.process_mut().set_child_subreaper(child_subreaper);
+        self
+    }
+
+    /// Keeps `n` file descriptors following stdio open in the program,
+    /// like `runc --preserve-fds`. This is how sockets are passed with
+    /// `LISTEN_FDS` of `sd_listen_fds(3)`.
+    ///
+    /// Other file descriptors are closed before executing the program,
+    /// unless they are targets of `fd_map`. Callbacks still see every
+    /// inherited file descriptor.
+    pub fn preserve_fds(&mut self, n: u32) -> &mut Self {
+        self.preserve_fds = n;
+        self
+    }
+
+    /// Makes `parent_fd` available as `child_fd` in the program, like
+    /// `dup2(2)`.
+    ///
+    /// `child_fd` may be the `parent_fd` of another mapping, all sources
+    /// are read before any target is replaced.
+    pub fn fd_map(&mut self, parent_fd: RawFd, child_fd: RawFd) -> &mut Self {
+        self.fd_maps.push((parent_fd, child_fd));
+        self
+    }
+
+    /// Sets where stdin of the child is connected to.
+    ///
+    /// Callbacks see the redirected stream as well.
+    pub fn stdin(&mut self, stdio: config::Stdio) -> &mut Self {
+        self.stdio[0] = stdio;
+        self
+    }
+
+    /// Sets where stdout of the child is connected to.
+    pub fn stdout(&mut self, stdio: config::Stdio) -> &mut Self {
+        self.stdio[1] = stdio;
+        self
+    }
+
+    /// Sets where stderr of the child is connected to.
+    pub fn stderr(&mut self, stdio: config::Stdio) -> &mut Self {
+        self.stdio[2] = stdio;
+        self
+    }
+
     /// Executes the callbacks and program in a child process,
     /// returning a handle to it.
     ///
     /// This instance of Wrap will not be consumed, but it's
     /// queue of callback functions will be empty.
     pub fn spawn(&mut self) -> Result<Child, Error> {
-        let mut wrapcore = core::WrapCore {
+        use nix::fcntl::OFlag;
+        let mut wrapcore = self.core()?;
+        let (child_stdio, parent_stdio) = self.open_stdio()?;
+        let cgroup = match &self.cgroup {
+            Some((cgroup, resources)) => {
+                let created = !cgroup.exists();
+                let fd = cgroup.create().and_then(|_| {
+                    cgroup.apply(resources)?;
+                    let flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+                    nix::fcntl::open(cgroup.path(), flags, nix::sys::stat::Mode::empty())
+                        .map_err(|e| Error::OsErrno(e as i32))
+                });
+                match fd {
+                    Ok(fd) => Some((cgroup.clone(), fd)),
+                    Err(e) => {
+                        if created {
+                            let _ = cgroup.remove();
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+        wrapcore.cgroup_fd = cgroup.as_ref().map(|(_, fd)| *fd);
+        wrapcore.stdio = child_stdio
+            .each_ref()
+            .map(|fd| fd.as_ref().map(|fd| fd.as_raw_fd()));
+        wrapcore.parent_stdio = parent_stdio
+            .iter()
+            .flatten()
+            .map(|fd| fd.as_raw_fd())
+            .collect();
+        wrapcore.callbacks.append(&mut self.callbacks);
+        let spawned = wrapcore.spwan();
+        drop(child_stdio);
+        let spawned = spawned.map(|child| Child {
+            stdio: parent_stdio,
+            ..child
+        });
+        match cgroup {
+            Some((cgroup, fd)) => {
+                let _ = nix::unistd::close(fd);
+                match spawned {
+                    Ok(child) => Ok(Child {
+                        cgroup: Some(cgroup),
+                        ..child
+                    }),
+                    // Nothing runs in it, like after `Child::wait`
+                    Err(e) => {
+                        let _ = cgroup.remove();
+                        Err(e)
+                    }
+                }
+            }
+            None => spawned,
+        }
+    }
+
+    /// Describe what `spawn` would do, without spawning anything.
+    ///
+    /// The operations are listed in the order the child performs them,
+    /// from `clone(2)` to executing the program, with the same paths, id
+    /// maps and flags. The configuration is checked like `spawn` does, but
+    /// the cgroup is not created and callbacks are not run.
+    pub fn plan(&self) -> Result<plan::Plan, Error> {
+        let cgroup = self.cgroup.as_ref().map(|(cgroup, _)| cgroup.path());
+        self.core()?.plan(&self.stdio, cgroup, self.callbacks.len())
+    }
+
+    /// Copy the configuration to a `core::WrapCore`, without the fds
+    /// opened by `spawn` nor the callbacks.
+    fn core(&self) -> Result<core::WrapCore<'a>, Error> {
+        let exec = match &self.process {
+            Some(process) if !process.bin().is_empty() => Some(core::Exec::new(process)?),
+            _ => None,
+        };
+        Ok(core::WrapCore {
             process: self.process.clone(),
+            exec,
             root: self.root.clone(),
+            hostname: self.hostname.clone(),
+            sysctls: self.sysctls.clone(),
+            root_propagation: self.root_propagation,
             mounts: self.mounts.clone(),
             uid_maps: self.uid_maps.clone(),
             gid_maps: self.gid_maps.clone(),
             callbacks: VecDeque::new(),
+            hooks: self.hooks.clone(),
+            hook_state: self.hook_state.clone(),
+            tty: self.tty,
+            console_socket: self.console_socket.clone(),
+            join: self.join.clone(),
+            cgroup_fd: None,
+            landlock: self.landlock.clone(),
+            parent_pid: 0,
+            preserve_fds: self.preserve_fds,
+            fd_maps: self.fd_maps.clone(),
+            stdio: [None; 3],
+            parent_stdio: Vec::new(),
             namespace_nsenter: self.namespace_nsenter.clone(),
             namespace_unshare: self.namespace_unshare.clone(),
-            sandbox_mnt: self.sandbox_mnt.clone(),
-        };
-        wrapcore.callbacks.append(&mut self.callbacks);
-        wrapcore.spwan()
+            sandbox_mnt: self.sandbox_mnt,
+            plan: Default::default(),
+            sys: &sys::Kernel,
+            trace: None,
+        })
+    }
+
+    /// Open the ends of redirected standard streams, for the child and
+    /// for the parent.
+    #[allow(clippy::type_complexity)]
+    fn open_stdio(&self) -> Result<([Option<OwnedFd>; 3], [Option<OwnedFd>; 3]), Error> {
+        use nix::fcntl::OFlag;
+
+        let mut child: [Option<OwnedFd>; 3] = Default::default();
+        let mut parent: [Option<OwnedFd>; 3] = Default::default();
+        for (i, stdio) in self.stdio.iter().enumerate() {
+            match stdio {
+                config::Stdio::Inherit => (),
+                config::Stdio::Null => {
+                    child[i] = Some(
+                        std::fs::File::options()
+                            .read(true)
+                            .write(true)
+                            .open("/dev/null")?
+                            .into(),
+                    );
+                }
+                config::Stdio::Piped => {
+                    let (rd, wr) = nix::unistd::pipe2(OFlag::O_CLOEXEC)
+                        .map_err(|e| Error::OsErrno(e as i32))?;
+                    let (rd, wr) = unsafe { (OwnedFd::from_raw_fd(rd), OwnedFd::from_raw_fd(wr)) };
+                    (child[i], parent[i]) = match i {
+                        0 => (Some(rd), Some(wr)),
+                        _ => (Some(wr), Some(rd)),
+                    };
+                }
+            }
+        }
+        Ok((child, parent))
+    }
+
+    /// Like `spawn`, returning a child to wait on from tokio.
+    ///
+    /// This blocks like `spawn`, which waits for the child to reach the
+    /// runtime `Wrap::hooks` and runs them. With hooks, call it from
+    /// `tokio::task::block_in_place` or a thread that entered the
+    /// runtime. It must be called within a runtime with I/O enabled.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_async(&mut self) -> Result<async_child::AsyncChild, Error> {
+        async_child::AsyncChild::new(self.spawn()?)
     }
 
     /// Executes the command and callback functions in a child process,
@@ -107,13 +473,19 @@ impl<'a> Wrap<'a> {
     /// The return value of last function can be retrieved
     /// from `ExitStatus` if no `program` is executed.
     ///
-    /// # Notes and Safety
+    /// # Safety
     ///
     /// This closure will be run in the context of the child process after a
     /// `clone(2)`. This primarily means that any modifications made to
     /// memory on behalf of this closure will **not** be visible to the
     /// parent process.
     ///
+    /// If other threads may be running while spawning, the closure must
+    /// only call async-signal-safe functions: it must not allocate, lock,
+    /// or panic, as a lock held by another thread at the time of the
+    /// `clone(2)` is never released in the child. The rest of the setup
+    /// of nswrap keeps to this, except for OCI hooks.
+    ///
     /// For further details on this topic, please refer to the
     /// [Rust Std Lib Dcoument], related [github issue of nix library],
     /// [github issue of rust]
@@ -126,11 +498,52 @@ impl<'a> Wrap<'a> {
     ///     https://github.com/nix-rust/nix/issues/360#issuecomment-359271308
     /// [github issue of rust]:
     ///     https://github.com/rust-lang/rust/issues/39575
-    pub fn callback<F>(&mut self, cb: F) -> &mut Self
+    pub unsafe fn callback<F>(&mut self, cb: F) -> &mut Self
     where
         F: FnOnce() -> isize + Send + 'static,
     {
-        self.callbacks.push_back(Box::new(cb));
+        let mut cb = Some(cb);
+        self.callbacks
+            .push_back(Box::new(move || cb.take().map_or(0, |cb| cb())));
+        self
+    }
+
+    /// Run OCI lifecycle hooks while spawning the child.
+    ///
+    /// `state` is passed to the hooks on stdin, with the pid of the child
+    /// filled in. `prestart` and `create_runtime` hooks run in the parent
+    /// once the namespaces of the child are set up, and `spawn` fails if
+    /// any of them fails. `create_container` hooks then run in the child
+    /// before its mounts are set up, and `start_container` hooks right
+    /// before the process is executed; the child exits with status 1 if
+    /// one of them fails. `poststart` and `poststop` are not run, as only
+    /// the caller knows when they are due.
+    pub fn hooks(&mut self, hooks: config::Hooks, state: oci::State) -> &mut Self {
+        self.hooks = hooks;
+        self.hook_state = Some(state);
+        self
+    }
+
+    /// Allocate a pseudo-terminal as the controlling terminal of the child.
+    ///
+    /// The terminal is allocated from `/dev/ptmx` once mounts are set up,
+    /// so it comes from the `devpts` of the container, like the one of a
+    /// `dev` mount. It replaces stdin, stdout and stderr of the child,
+    /// which runs in a new session. The master is returned to the parent
+    /// by `Child::take_pty_master`.
+    pub fn tty(&mut self, tty: bool) -> &mut Self {
+        self.tty = tty;
+        self
+    }
+
+    /// Send the master of the pseudo-terminal to the unix socket at `path`
+    /// instead of returning it, if `tty` is set.
+    ///
+    /// As the `--console-socket` option of OCI runtimes, the master is
+    /// sent with `SCM_RIGHTS` along with the path of the terminal in the
+    /// container, like `/dev/pts/0`. `spawn` fails if it can not be sent.
+    pub fn console_socket<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.console_socket = Some(path.as_ref().to_path_buf());
         self
     }
 
@@ -140,8 +553,7 @@ impl<'a> Wrap<'a> {
     /// use nswrap::Wrap;
     /// use nswrap::config;
     /// let mut wrap = Wrap::new();
-    /// wrap.callback(|| {print!("Cool!");return 5})
-    ///     .unshare(config::NamespaceType::User);
+    /// unsafe { wrap.callback(|| 5) }.unshare(config::NamespaceType::User);
     /// wrap.spawn().unwrap().wait().unwrap();
     /// ```
     pub fn unshare(&mut self, typ: config::NamespaceType) -> &mut Self {
@@ -150,9 +562,75 @@ impl<'a> Wrap<'a> {
 
     /// Reassociate child process with a namespace.
     ///
-    /// The order in which this method is called will affect the result.
-    pub fn nsenter(&mut self, typ: config::NamespaceType, pidfd: RawFd) -> &mut Self {
-        self.add_namespace(typ, config::NamespaceItem::Enter(pidfd))
+    /// Namespaces are entered with the user namespace first and the mount
+    /// namespace last, whatever order this method is called in.
+    pub fn nsenter(&mut self, ns: ns::Namespace) -> &mut Self {
+        self.add_namespace(ns.typ(), config::NamespaceItem::Enter(Arc::new(ns)))
+    }
+
+    /// Join `namespaces` of a running process.
+    ///
+    /// If the mount namespace is joined, the child also takes the root and
+    /// working directory of the process. Namespaces the child is already
+    /// in are skipped. Joining the pid namespace makes the child fork once
+    /// more, so that the command runs inside it.
+    ///
+    /// Namespaces from `nsenter` and `unshare` are applied afterwards,
+    /// instead of being created by `clone(2)`.
+    pub fn join(
+        &mut self,
+        target: config::JoinTarget,
+        namespaces: &[config::NamespaceType],
+    ) -> &mut Self {
+        self.join = Some((target, namespaces.to_vec()));
+        self
+    }
+
+    /// Run the child in the cgroup v2 `cgroup`, limited by `resources`.
+    ///
+    /// The cgroup is created if needed, usually as a child of
+    /// `cgroup::Cgroup::current()`, and the child moves itself into it
+    /// before anything else. It is removed once the child is reaped by
+    /// `Child::wait`. A cgroup namespace from `unshare` is created after
+    /// moving, so that the container sees its cgroup as the root.
+    pub fn cgroup(&mut self, cgroup: cgroup::Cgroup, resources: config::Resources) -> &mut Self {
+        self.cgroup = Some((cgroup, resources));
+        self
+    }
+
+    /// Restrict the process with a Landlock ruleset.
+    ///
+    /// The ruleset is enforced after callbacks and hooks have run, right
+    /// before the process is executed, so paths are resolved inside the
+    /// container.
+    pub fn landlock(&mut self, ruleset: config::Landlock) -> &mut Self {
+        self.landlock = Some(ruleset);
+        self
+    }
+
+    /// Set the hostname inside the container.
+    ///
+    /// This will require a uts namespace.
+    pub fn hostname<S: AsRef<OsStr>>(&mut self, hostname: S) -> &mut Self {
+        self.hostname = Some(hostname.as_ref().to_os_string());
+        self
+    }
+
+    /// Set the kernel parameter `key`, like `net.ipv4.ping_group_range`,
+    /// inside the container.
+    ///
+    /// Only parameters of the network, ipc and uts namespaces can be set,
+    /// and the namespace they belong to must be unshared. Keys are
+    /// separated by dots, or by slashes if a part contains a dot, like
+    /// `net/ipv4/conf/eth0.1/forwarding`.
+    ///
+    /// The child writes them under `/proc/sys` right after mounting a new
+    /// `proc`, before its `/proc/sys` is made read-only, or through the
+    /// `/proc` of the caller before any mount if there is none.
+    pub fn sysctl<K: AsRef<str>, V: AsRef<str>>(&mut self, key: K, value: V) -> &mut Self {
+        self.sysctls
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
     }
 
     /// Add some mount points and file path that application usually needs.
@@ -209,26 +687,84 @@ impl<'a> Wrap<'a> {
     /// inside namespace.
     ///
     /// This is required if a user what to use `Wrap` for mountpoint
-    /// management
+    /// management. The new root is empty except for the mounts added by
+    /// `mount`, whose bind sources are paths of the old root. The old root
+    /// is detached afterwards, so it can not be reached from the container.
     pub fn sandbox_mnt(&mut self, opt: bool) -> &mut Self {
         self.sandbox_mnt = opt;
         self
     }
+
+    /// Use the directory of `root` as the root dir inside namespace,
+    /// rather than an empty tmpfs.
+    ///
+    /// This implies `sandbox_mnt`, mounts are placed into the root dir.
+    /// If `root` is readonly, the root dir is remounted readonly after
+    /// all mounts are created.
+    pub fn root(&mut self, root: config::Root) -> &mut Self {
+        self.sandbox_mnt(true).set_root(root)
+    }
+
+    /// Set a resource limit of the program, see `setrlimit(2)`.
+    pub fn rlimit(&mut self, rlimit: config::Rlimit) -> &mut Self {
+        self.process_mut().rlimits_mut().push(rlimit);
+        self
+    }
+
+    /// Create a new instance from an OCI runtime bundle.
+    ///
+    /// `bundle` is a directory containing `config.json` and the root
+    /// file system it refers to. Namespaces, id mappings, mounts, the
+    /// process, hostname and rlimits are configured from `config.json`.
+    /// Fields of the specification that nswrap does not support are
+    /// reported with `Error::UnsupportedOciFields` rather than ignored.
+    pub fn from_oci_bundle<P: AsRef<Path>>(bundle: P) -> Result<Wrap<'a>, Error> {
+        oci::Spec::load(bundle.as_ref())?.build(bundle.as_ref())
+    }
+
+    /// Set the propagation type of all mounts under `/` inside the
+    /// container.
+    ///
+    /// This will require a mount namespace. If it is not set, nswrap
+    /// leaves propagation to the kernel's default, except that
+    /// `sandbox_mnt` uses `MountPropagation::Slave`, like `bwrap`.
+    ///
+    /// ```no_run
+    /// use nswrap::Wrap;
+    /// use nswrap::config;
+    /// let mut wrap = Wrap::new_cmd("/bin/sh");
+    /// wrap.unshare(config::NamespaceType::User)
+    ///     .unshare(config::NamespaceType::Mount)
+    ///     .root_propagation(config::MountPropagation::Private);
+    /// ```
+    pub fn root_propagation(&mut self, propagation: config::MountPropagation) -> &mut Self {
+        self.root_propagation = Some(propagation);
+        self
+    }
+
+    /// Add a mount point inside the container.
+    ///
+    /// This will require a mount namespace. Mounts are created in the
+    /// same order as they were added, after `root_propagation` is applied.
+    /// Propagation options in `config::Mount::options`, like `rshared`,
+    /// are applied to the mount point right after it is created.
+    pub fn mount(&mut self, mnt: config::Mount) -> &mut Self {
+        self.add_mount(mnt)
+    }
 }
 
 /// Public builder pattern method
-impl<'a> Wrap<'_> {
+impl Wrap<'_> {
     fn add_namespace(
         &mut self,
         typ: config::NamespaceType,
         ns: config::NamespaceItem,
     ) -> &mut Self {
-        let mut set;
-        match ns {
+        let set = match &ns {
             config::NamespaceItem::None => return self,
-            config::NamespaceItem::Unshare => set = &mut self.namespace_unshare,
-            config::NamespaceItem::Enter(_) => set = &mut self.namespace_nsenter,
-        }
+            config::NamespaceItem::Unshare => &mut self.namespace_unshare,
+            config::NamespaceItem::Enter(_) => &mut self.namespace_nsenter,
+        };
         match typ {
             config::NamespaceType::Mount => set.mount = ns,
             config::NamespaceType::Cgroup => set.cgroup = ns,
@@ -239,7 +775,7 @@ impl<'a> Wrap<'_> {
             config::NamespaceType::Network => set.network = ns,
             config::NamespaceType::Time => unimplemented!(),
         }
-        return self;
+        self
     }
 
     /// Set the program that will be executed.
@@ -251,6 +787,22 @@ impl<'a> Wrap<'_> {
         self
     }
 
+    fn process_mut(&mut self) -> &mut config::Process {
+        self.process.get_or_insert_with(Default::default)
+    }
+
+    fn process_env_mut(&mut self) -> &mut Vec<OsString> {
+        self.process_mut().env_mut().get_or_insert_with(|| {
+            std::env::vars_os()
+                .map(|(mut k, v)| {
+                    k.push("=");
+                    k.push(v);
+                    k
+                })
+                .collect()
+        })
+    }
+
     fn set_root(&mut self, root: config::Root) -> &mut Self {
         self.root = Some(root);
         self
@@ -275,29 +827,114 @@ impl<'a> Wrap<'_> {
 }
 
 impl Child {
+    /// Process id of the child, in the pid namespace of the caller.
+    ///
+    /// When `Wrap::join` joins a pid namespace, this is the process that
+    /// forks the program into it and waits for it. It forwards signals
+    /// like SIGTERM and SIGINT to the program and exits like it, and the
+    /// program is killed when it is, so signalling `id()` reaches it.
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw_nonzero().get() as u32
+    }
+
+    /// Take the master of the pseudo-terminal allocated by `Wrap::tty`.
+    pub fn take_pty_master(&mut self) -> Option<OwnedFd> {
+        self.pty_master.take()
+    }
+
+    /// Take the write end of the pipe to stdin, with `Stdio::Piped`.
+    pub fn take_stdin(&mut self) -> Option<OwnedFd> {
+        self.stdio[0].take()
+    }
+
+    /// Take the read end of the pipe from stdout, with `Stdio::Piped`.
+    pub fn take_stdout(&mut self) -> Option<OwnedFd> {
+        self.stdio[1].take()
+    }
+
+    /// Take the read end of the pipe from stderr, with `Stdio::Piped`.
+    pub fn take_stderr(&mut self) -> Option<OwnedFd> {
+        self.stdio[2].take()
+    }
+
+    /// Cgroup of the child, set up by `Wrap::cgroup`.
+    pub fn cgroup(&self) -> Option<&cgroup::Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    /// Resource usage of the cgroup of the child.
+    pub fn stats(&self) -> Result<cgroup::Stats, Error> {
+        match &self.cgroup {
+            Some(cgroup) => cgroup.stats(),
+            None => Err(Error::Cgroup("the child has no cgroup".into())),
+        }
+    }
+
+    /// Freeze every process of the child, through its cgroup if it has
+    /// one, or else with `SIGSTOP`.
+    ///
+    /// Without a cgroup, the processes are those in the pid namespace of
+    /// the child, or the child and its descendants if it has none.
+    pub fn freeze(&self) -> Result<(), Error> {
+        cgroup::freeze_container(self.id() as i32, self.cgroup.as_ref())
+    }
+
+    /// Undo `freeze`.
+    pub fn thaw(&self) -> Result<(), Error> {
+        cgroup::thaw_container(self.id() as i32, self.cgroup.as_ref())
+    }
+
+    /// Kill every process of the child, including those it left behind.
+    ///
+    /// The child itself still has to be reaped with `wait`.
+    pub fn kill_all(&self) -> Result<(), Error> {
+        cgroup::kill_container(self.id() as i32, self.cgroup.as_ref())
+    }
+
+    /// Wait for the child to exit, and collect its status with the
+    /// resources it used and the time since it was spawned.
     pub fn wait(&mut self) -> Result<ExitStatus, Error> {
-        match rustix::process::waitpid(Some(self.pid), rustix::process::WaitOptions::empty()) {
-            Ok(r) => Ok(ExitStatus::new(r.unwrap())),
-            Err(err) => Err(Error::OsErrno(err.raw_os_error())),
+        let mut status = 0;
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            let pid = self.pid.as_raw_nonzero().get();
+            match unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } {
+                -1 if nix::errno::Errno::last() == nix::errno::Errno::EINTR => (),
+                -1 => return Err(Error::OsErrno(nix::errno::errno())),
+                _ => break,
+            }
         }
+        // The last records of the setup are logged before the exit status
+        if let Some(trace) = self.trace.take() {
+            let _ = trace.join();
+        }
+        let status = ExitStatus {
+            std_exit_status: std::process::ExitStatus::from_raw(status),
+            rusage: Some(ResourceUsage::from(&rusage)),
+            duration: Some(self.started.elapsed()),
+            pid_namespace_init: self.pid_namespace_init,
+        };
+        // Processes the child left behind keep the cgroup busy
+        if let Some(cgroup) = self.cgroup.take() {
+            let _ = cgroup.remove();
+        }
+        Ok(status)
     }
 }
 
 impl ExitStatus {
+    /// Status from a raw wait status, without resource usage or duration.
     pub fn new(wait_status: rustix::process::WaitStatus) -> Self {
         Self {
-            wait_status,
-            std_exit_status: std::process::ExitStatus::from_raw(
-                wait_status.as_raw().try_into().unwrap(),
-            ),
+            std_exit_status: std::process::ExitStatus::from_raw(wait_status.as_raw() as i32),
+            rusage: None,
+            duration: None,
+            pid_namespace_init: false,
         }
     }
 
     pub fn code(&self) -> Option<i32> {
-        match self.wait_status.exit_status() {
-            Some(r) => Some(i32::try_from(r).unwrap()),
-            None => None,
-        }
+        self.std_exit_status.code()
     }
 
     pub fn success(&self) -> bool {
@@ -316,6 +953,89 @@ impl ExitStatus {
     pub fn continued(&self) -> bool {
         self.std_exit_status.continued()
     }
+
+    /// Resources used by the child, if it was collected by `Child::wait`.
+    pub fn rusage(&self) -> Option<&ResourceUsage> {
+        self.rusage.as_ref()
+    }
+
+    /// Wall-clock time from spawning to reaping the child, if it was
+    /// collected by `Child::wait`.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Whether the child was pid 1 of a new pid namespace, so that every
+    /// other process in it was killed when it exited.
+    pub fn pid_namespace_init(&self) -> bool {
+        self.pid_namespace_init
+    }
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let signal = |sig: i32| match nix::sys::signal::Signal::try_from(sig) {
+            Ok(sig) => format!("signal {} ({})", sig as i32, sig.as_str()),
+            Err(_) => format!("signal {}", sig),
+        };
+        if let Some(code) = self.code() {
+            write!(f, "exited with code {}", code)?;
+        } else if let Some(sig) = self.signal() {
+            write!(f, "killed by {}", signal(sig))?;
+            if self.core_dumped() {
+                write!(f, ", core dumped")?;
+            }
+        } else if let Some(sig) = self.stopped_signal() {
+            write!(f, "stopped by {}", signal(sig))?;
+        } else if self.continued() {
+            write!(f, "continued")?;
+        } else {
+            write!(f, "wait status {:#x}", self.std_exit_status.into_raw())?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, " in {:.3}s", duration.as_secs_f64())?;
+        }
+        if let Some(usage) = &self.rusage {
+            write!(f, " ({})", usage)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&libc::rusage> for ResourceUsage {
+    fn from(usage: &libc::rusage) -> Self {
+        let time = |tv: libc::timeval| {
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+        };
+        Self {
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+            // In kilobytes on Linux
+            max_rss: usage.ru_maxrss as u64 * 1024,
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+            voluntary_context_switches: usage.ru_nvcsw as u64,
+            involuntary_context_switches: usage.ru_nivcsw as u64,
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "user {:.3}s, sys {:.3}s, max RSS {:.1} MiB, \
+             page faults {} minor {} major, \
+             context switches {} voluntary {} involuntary",
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64(),
+            self.max_rss as f64 / (1024.0 * 1024.0),
+            self.minor_faults,
+            self.major_faults,
+            self.voluntary_context_switches,
+            self.involuntary_context_switches
+        )
+    }
 }
 
 #[cfg(test)]
@@ -330,7 +1050,7 @@ mod tests {
 
     fn make_test_dir() {
         use std::fs;
-        fs::remove_dir_all(_TMP_DIR);
+        let _ = fs::remove_dir_all(_TMP_DIR);
         fs::create_dir_all(_TMP_DIR1).unwrap();
         fs::create_dir_all(_TMP_DIR2).unwrap();
     }
@@ -350,10 +1070,10 @@ mod tests {
             nix::mount::mount(Some(_TMP_DIR1), _TMP_DIR2, Some(""), flags, Some("")).unwrap();
             let mut file = File::create(_TMP_DIR2.to_owned() + "/foo.txt").unwrap();
             std::io::Write::write_all(&mut file, b"Hello, world!").unwrap();
-            return 0;
+            0
         };
         let mut wrap = Wrap::new();
-        wrap.callback(cb).unshare(config::NamespaceType::User);
+        unsafe { wrap.callback(cb) }.unshare(config::NamespaceType::User);
         wrap.spawn().unwrap().wait().unwrap();
 
         // Check Result
@@ -365,27 +1085,22 @@ mod tests {
 
     #[test]
     fn callback_return_value() {
-        let cb = || {
-            return 16;
-        };
+        let cb = || 16;
         let mut wrap = Wrap::new();
-        wrap.callback(cb).unshare(config::NamespaceType::User);
+        unsafe { wrap.callback(cb) }.unshare(config::NamespaceType::User);
         let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
         assert_eq!(16, ret);
     }
 
-    //#[test]
+    #[test]
     fn callback_return_value_in_thread() {
         use std::thread;
 
         let thread_join_handle = thread::spawn(move || {
-            let cb = || {
-                return 16;
-            };
+            let cb = || 16;
             let mut wrap = Wrap::new();
-            wrap.callback(cb).unshare(config::NamespaceType::User);
-            let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
-            ret
+            unsafe { wrap.callback(cb) }.unshare(config::NamespaceType::User);
+            wrap.spawn().unwrap().wait().unwrap().code().unwrap()
         });
 
         assert_eq!(thread_join_handle.join().unwrap(), 16);
@@ -399,11 +1114,82 @@ mod tests {
         let thread_join_handle = thread::spawn(move || {
             let cb = || panic!();
             let mut wrap = Wrap::new();
-            wrap.callback(cb).unshare(config::NamespaceType::User);
+            unsafe { wrap.callback(cb) }.unshare(config::NamespaceType::User);
             let ret = wrap.spawn().unwrap().wait().unwrap();
-            println!("{:?}", ret.wait_status)
+            println!("{}", ret)
         });
-        thread_join_handle.join();
+        let _ = thread_join_handle.join();
+    }
+
+    /// Spawn from several threads while another one keeps allocating, so
+    /// that the allocator lock is often held during `clone(2)`.
+    #[test]
+    fn spawn_in_threads() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let allocator = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    std::hint::black_box(vec![0u8; 4096]);
+                }
+            })
+        };
+        let spawners: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..16 {
+                        let mut mnt = config::Mount::default();
+                        mnt.set_destination("/tmp".into())
+                            .set_typ(Some("tmpfs".into()));
+                        let mut wrap = Wrap::new_cmd("/bin/true");
+                        wrap.unshare(config::NamespaceType::User)
+                            .unshare(config::NamespaceType::Mount)
+                            .id_map_preset(config::IdMapPreset::Current)
+                            .mount(mnt);
+                        let ret = wrap.spawn().unwrap().wait().unwrap();
+                        assert_eq!(ret.code(), Some(0));
+                    }
+                })
+            })
+            .collect();
+        for spawner in spawners {
+            spawner.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        allocator.join().unwrap();
+    }
+
+    #[test]
+    fn exit_status() {
+        let mut wrap = Wrap::new_cmd("/bin/sh");
+        wrap.args(["-c", "sleep 0.1; exit 3"]);
+        let status = wrap.spawn().unwrap().wait().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(!status.success());
+        assert!(!status.pid_namespace_init());
+        assert!(status.duration().unwrap() >= Duration::from_millis(100));
+        assert!(status.rusage().unwrap().max_rss() > 0);
+        assert!(status.to_string().starts_with("exited with code 3 in "));
+
+        let mut wrap = Wrap::new_cmd("/bin/sh");
+        wrap.args(["-c", "kill -9 $$"]);
+        let status = wrap.spawn().unwrap().wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(status
+            .to_string()
+            .starts_with("killed by signal 9 (SIGKILL) in "));
+        assert!(status.to_string().contains("context switches"));
+
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Pid);
+        let status = wrap.spawn().unwrap().wait().unwrap();
+        assert!(status.success());
+        assert!(status.pid_namespace_init());
     }
 
     #[test]
@@ -412,13 +1198,12 @@ mod tests {
             use std::path::Path;
             let p = Path::new("/bin/sh");
             match p.exists() {
-                true => return 16,
-                false => return 32,
-            };
+                true => 16,
+                false => 32,
+            }
         };
         let mut binding = Wrap::new();
-        let wrap = binding
-            .callback(cb)
+        let wrap = unsafe { binding.callback(cb) }
             .unshare(config::NamespaceType::User)
             .unshare(config::NamespaceType::Mount)
             .sandbox_mnt(true)
@@ -427,6 +1212,256 @@ mod tests {
         assert_eq!(32, ret);
     }
 
+    /// Find optional fields of the mount point at `path` in
+    /// `/proc/self/mountinfo`, like `shared:1` or `master:2`.
+    fn mountinfo_optional_fields(path: &str) -> Option<String> {
+        let info = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+        info.lines()
+            .rfind(|l| l.split(' ').nth(4) == Some(path))
+            .map(|l| {
+                l.split(' ')
+                    .skip(6)
+                    .take_while(|f| *f != "-")
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+    }
+
+    #[test]
+    fn root_propagation_private() {
+        let cb = || match mountinfo_optional_fields("/") {
+            Some(f) if f.is_empty() => 0,
+            _ => 1,
+        };
+        let mut binding = Wrap::new();
+        let wrap = unsafe { binding.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .root_propagation(config::MountPropagation::Private);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn mount_propagation_shared() {
+        const DIR: &str = "/tmp/nswrap.test.propagation";
+        std::fs::create_dir_all(DIR).unwrap();
+        let cb = || match mountinfo_optional_fields(DIR) {
+            Some(f) if f.starts_with("shared:") => 0,
+            _ => 1,
+        };
+        let mut mnt = config::Mount::default();
+        mnt.set_destination(DIR.into())
+            .set_typ(Some("tmpfs".into()))
+            .set_options(Some(vec!["mode=755".into(), "shared".into()]));
+        let mut binding = Wrap::new();
+        let wrap = unsafe { binding.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .root_propagation(config::MountPropagation::Private)
+            .mount(mnt);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn sandbox_mnt_mounts() {
+        let cb = || {
+            use std::fs::OpenOptions;
+            use std::path::Path;
+            let mut ret = 0;
+            if Path::new("/oldroot").exists() || Path::new("/newroot").exists() {
+                ret |= 1;
+            }
+            if !Path::new("/usr/bin").is_dir() {
+                ret |= 2;
+            }
+            if std::fs::File::create("/usr/nswrap-test").is_ok() {
+                ret |= 4;
+            }
+            if OpenOptions::new().write(true).open("/dev/null").is_err() {
+                ret |= 8;
+            }
+            if std::fs::File::create("/tmp/nswrap-test").is_err() {
+                ret |= 16;
+            }
+            ret
+        };
+        let mut usr = config::Mount::default();
+        usr.set_destination("/usr".into())
+            .set_source(Some("/usr".into()))
+            .set_options(Some(vec!["rbind".into(), "ro".into()]));
+        let mut dev = config::Mount::default();
+        dev.set_destination("/dev".into())
+            .set_typ(Some("dev".into()));
+        let mut tmp = config::Mount::default();
+        tmp.set_destination("/tmp".into())
+            .set_typ(Some("tmpfs".into()))
+            .set_options(Some(vec!["nosuid".into(), "nodev".into()]));
+        let mut binding = Wrap::new();
+        let wrap = unsafe { binding.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .id_map_preset(config::IdMapPreset::Current)
+            .mount(usr)
+            .mount(dev)
+            .mount(tmp);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn exec_in_new_namespaces() {
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .args([
+                "-c",
+                "test $$ -eq 1 && test $(cat /proc/sys/kernel/hostname) = nswrap && test $FOO = bar",
+            ])
+            .env("FOO", "bar")
+            .hostname("nswrap")
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Pid)
+            .unshare(config::NamespaceType::Uts)
+            .id_map_preset(config::IdMapPreset::Current);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn sysctls() {
+        let msgmax = std::fs::read_to_string("/proc/sys/kernel/msgmax").unwrap();
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .args([
+                "-c",
+                "test \"$(cat /proc/sys/net/ipv4/ping_group_range)\" = \"$(printf '0\\t0')\" \
+                 && test $(cat /proc/sys/kernel/msgmax) = 16384",
+            ])
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Network)
+            .unshare(config::NamespaceType::Ipc)
+            .id_map_preset(config::IdMapPreset::Current)
+            .sysctl("net.ipv4.ping_group_range", "0 0")
+            .sysctl("kernel.msgmax", "16384");
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+        // Only the namespaces of the child changed
+        assert_eq!(
+            std::fs::read_to_string("/proc/sys/kernel/msgmax").unwrap(),
+            msgmax
+        );
+    }
+
+    #[test]
+    fn prctl_attrs() {
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .args(["-c", "grep -q 'NoNewPrivs:.1' /proc/self/status"])
+            .no_new_privs(true)
+            .dumpable(false)
+            .child_subreaper(true);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn die_with_parent() {
+        let mut child = std::thread::spawn(|| {
+            let mut binding = Wrap::new_cmd("/bin/sleep");
+            let child = binding.arg("60").die_with_parent().spawn().unwrap();
+            // The signal is set once the program runs
+            let comm = format!("/proc/{}/comm", child.id());
+            while std::fs::read(&comm).unwrap() != b"sleep\n" {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            child
+        })
+        .join()
+        .unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn fds() {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+        use std::os::fd::IntoRawFd;
+
+        let pipe = |msg: &[u8]| {
+            let (rd, wr) = nix::unistd::pipe2(OFlag::O_CLOEXEC).unwrap();
+            nix::unistd::write(wr, msg).unwrap();
+            nix::unistd::close(wr).unwrap();
+            rd
+        };
+        let (one, two) = (pipe(b"one\n"), pipe(b"two\n"));
+        // Inherited without close-on-exec
+        let leaked = std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+        fcntl(leaked, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .arg("-c")
+            .arg(format!(
+                "read a <&{one} && read b <&{two} && test $a = two -a $b = one \
+                 && test ! -e /proc/self/fd/{leaked}"
+            ))
+            .fd_map(one, two)
+            .fd_map(two, one);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+
+        let three = pipe(b"three\n");
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .arg("-c")
+            .arg(format!("read a < /proc/self/fd/{three} && test $a = three"))
+            .preserve_fds(three as u32 - 2);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+
+        for fd in [one, two, three, leaked] {
+            nix::unistd::close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn env_clear() {
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .args([
+                "-c",
+                "test $container = petbox -a $HOME = / -a $FOO = bar -a $CARGO_PKG_NAME = nswrap \
+                 && test -z \"$CARGO_MANIFEST_DIR\"",
+            ])
+            .env("CARGO_MANIFEST_DIR", "/")
+            .env_clear()
+            .env("FOO", "bar")
+            .inherit_env(&["CARGO_PKG_NAME", "NSWRAP_UNSET"]);
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn stdio() {
+        use std::io::Read;
+
+        let mut binding = Wrap::new_cmd("/bin/sh");
+        let wrap = binding
+            .args(["-c", "cat; echo out"])
+            .stdin(config::Stdio::Null)
+            .stdout(config::Stdio::Piped);
+        let mut child = wrap.spawn().unwrap();
+        let mut out = String::new();
+        std::fs::File::from(child.take_stdout().unwrap())
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "out\n");
+        assert!(child.take_stdin().is_none());
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+    }
+
     #[test]
     fn raw_child_pipe() {
         use nix::fcntl::OFlag;
@@ -435,11 +1470,10 @@ mod tests {
             nix::unistd::close(read_end).unwrap();
             nix::unistd::dup3(write_end, 16, OFlag::empty()).unwrap();
             nix::unistd::write(16, b"16").unwrap();
-            return 42;
+            42
         };
         let mut binding = Wrap::new();
-        let wrap = binding
-            .callback(cb)
+        let wrap = unsafe { binding.callback(cb) }
             .unshare(config::NamespaceType::User)
             .unshare(config::NamespaceType::Mount)
             .sandbox_mnt(true)
@@ -452,4 +1486,224 @@ mod tests {
         nix::unistd::read(read_end, &mut buf).unwrap();
         assert_eq!(buf, *b"16");
     }
+
+    #[test]
+    fn tty() {
+        let cb = || {
+            let mut ret = 0;
+            // The terminal is the controlling terminal of a new session
+            if unsafe { libc::tcgetsid(0) } != util::get_pid() {
+                ret |= 1;
+            }
+            if !nix::unistd::isatty(1).unwrap_or(false) {
+                ret |= 2;
+            }
+            let name = nix::unistd::ttyname(0).unwrap();
+            nix::unistd::write(1, name.to_str().unwrap().as_bytes()).unwrap();
+            ret
+        };
+        let mut dev = config::Mount::default();
+        dev.set_destination("/dev".into())
+            .set_typ(Some("dev".into()));
+        let mut binding = Wrap::new();
+        let wrap = unsafe { binding.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .id_map_preset(config::IdMapPreset::Current)
+            .mount(dev)
+            .tty(true);
+        let mut child = wrap.spawn().unwrap();
+        let mut master = std::fs::File::from(child.take_pty_master().unwrap());
+        let mut output = Vec::new();
+        // Reading fails with EIO once the child closes the terminal
+        let _ = std::io::Read::read_to_end(&mut master, &mut output);
+        assert_eq!(child.wait().unwrap().code().unwrap(), 0);
+        // The first pty of the new devpts instance of the container
+        assert_eq!(output, b"/dev/pts/0");
+    }
+
+    #[test]
+    fn console_socket() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::net::UnixListener;
+
+        let path = Path::new("/tmp/nswrap.test.console.sock");
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+
+        let cb = || {
+            nix::unistd::write(1, b"hello").unwrap();
+            0
+        };
+        let mut dev = config::Mount::default();
+        dev.set_destination("/dev".into())
+            .set_typ(Some("dev".into()));
+        let mut binding = Wrap::new();
+        let wrap = unsafe { binding.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .id_map_preset(config::IdMapPreset::Current)
+            .mount(dev)
+            .tty(true)
+            .console_socket(path);
+        // The connection is queued by the listener until it is accepted
+        let mut child = wrap.spawn().unwrap();
+        assert!(child.take_pty_master().is_none());
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut name = [0; 64];
+        let master = util::recv_fd(stream.as_raw_fd(), &mut name)
+            .unwrap()
+            .unwrap();
+        assert!(name.starts_with(b"/dev/pts/0\0"));
+        let mut master = std::fs::File::from(master);
+        let mut output = Vec::new();
+        let _ = std::io::Read::read_to_end(&mut master, &mut output);
+        assert_eq!(output, b"hello");
+        assert_eq!(child.wait().unwrap().code().unwrap(), 0);
+    }
+
+    #[test]
+    fn join() {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let (hold_rd, hold_wr) = nix::unistd::pipe().unwrap();
+        let (ready_rd, ready_wr) = nix::unistd::pipe().unwrap();
+        let mut binding = Wrap::new();
+        let target = unsafe {
+            binding.callback(move || {
+                let _ = nix::unistd::close(hold_wr);
+                let _ = nix::unistd::write(ready_wr, &[0]);
+                // Stay alive until the test closes its end of the pipe
+                let _ = nix::unistd::read(hold_rd, &mut [0]);
+                0
+            })
+        }
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Uts)
+        .unshare(config::NamespaceType::Pid)
+        .id_map_preset(config::IdMapPreset::Current)
+        .hostname("target");
+        let mut target_child = target.spawn().unwrap();
+        nix::unistd::read(ready_rd, &mut [0]).unwrap();
+
+        let pid = target_child.id() as i32;
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as i32;
+        assert!(pidfd >= 0);
+        let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd) };
+        for join_target in [
+            config::JoinTarget::Pid(pid),
+            config::JoinTarget::Pidfd(pidfd.as_raw_fd()),
+        ] {
+            let pid_ns = std::fs::read_link(format!("/proc/{}/ns/pid", pid)).unwrap();
+            let cb = move || {
+                let mut ret = 0;
+                if std::fs::read("/proc/sys/kernel/hostname").unwrap() != b"target\n" {
+                    ret |= 1;
+                }
+                if std::fs::read_link("/proc/self/ns/pid").unwrap() != pid_ns {
+                    ret |= 2;
+                }
+                // The target is pid 1 in its pid namespace
+                if util::get_pid() == 1 {
+                    ret |= 4;
+                }
+                ret
+            };
+            let mut binding = Wrap::new();
+            let wrap = unsafe { binding.callback(cb) }.join(
+                join_target,
+                &[
+                    config::NamespaceType::Pid,
+                    config::NamespaceType::Uts,
+                    config::NamespaceType::User,
+                ],
+            );
+            let ret = wrap.spawn().unwrap().wait().unwrap();
+            assert_eq!(ret.code().unwrap(), 0, "{:?}", join_target);
+        }
+
+        // Signals to the child reach the program forked into the pid namespace
+        let mut wrap = Wrap::new_cmd("sleep");
+        wrap.arg("100").join(
+            config::JoinTarget::Pid(pid),
+            &[config::NamespaceType::Pid, config::NamespaceType::User],
+        );
+        let mut child = wrap.spawn().unwrap();
+        let children = format!("/proc/{0}/task/{0}/children", child.id());
+        let program = loop {
+            let content = std::fs::read_to_string(&children).unwrap();
+            if let Some(program) = content.split_whitespace().next() {
+                let comm = std::fs::read_to_string(format!("/proc/{}/comm", program));
+                if comm.is_ok_and(|comm| comm == "sleep\n") {
+                    break program.to_owned();
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(child.id() as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        )
+        .unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(128 + libc::SIGTERM));
+        assert!(!Path::new(&format!("/proc/{}", program)).exists());
+
+        nix::unistd::close(hold_wr).unwrap();
+        assert_eq!(target_child.wait().unwrap().code().unwrap(), 0);
+    }
+
+    #[test]
+    fn plan() {
+        use plan::Operation;
+
+        let mut wrap = Wrap::new_cmd("/bin/true");
+        wrap.arg("x")
+            .env_clear()
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .uid_map(util::get_uid(), 0, 1)
+            .hostname("pet");
+        unsafe { wrap.callback(|| 0) };
+        let plan = wrap.plan().unwrap();
+        let ops = plan.operations();
+        assert_eq!(
+            ops[0],
+            Operation::Clone {
+                flags: vec!["CLONE_NEWNS".into(), "CLONE_NEWUSER".into()]
+            }
+        );
+        assert_eq!(
+            ops[1],
+            Operation::WriteFile {
+                path: "/proc/self/uid_map".into(),
+                content: format!("0 {} 1\n", util::get_uid()),
+            }
+        );
+        assert!(ops.contains(&Operation::PivotRoot {
+            new_root: ".".into(),
+            put_old: "oldroot".into()
+        }));
+        assert!(ops.contains(&Operation::Callbacks { count: 1 }));
+        let Some(Operation::Exec { argv, env }) = ops.last() else {
+            panic!("{}", plan)
+        };
+        assert_eq!(argv, &["/bin/true", "x"]);
+        assert!(env.contains(&"HOME=/".to_string()));
+
+        let text = plan.to_string();
+        assert!(
+            text.starts_with("  1. clone CLONE_NEWNS|CLONE_NEWUSER\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains(". sethostname pet\n"), "{}", text);
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["operations"][0]["op"], "clone");
+        // Planning does not consume the callbacks
+        assert_eq!(wrap.callbacks.len(), 1);
+    }
 }
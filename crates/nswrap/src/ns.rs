@@ -0,0 +1,247 @@
+//! Handles of namespaces, see `namespaces(7)` and `ioctl_ns(2)`.
+//!
+//! A `Namespace` keeps the namespace alive as long as it is open. To keep
+//! it alive without a process or an open file, it can be bind-mounted to
+//! a file with `Namespace::persist`, and opened again from that file.
+
+use crate::{config::NamespaceType, error::Error};
+use std::fs::File;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// An open namespace, typed with `NS_GET_NSTYPE`.
+///
+/// Two handles are equal when they refer to the same namespace.
+#[derive(Debug)]
+pub struct Namespace {
+    fd: OwnedFd,
+    typ: NamespaceType,
+    dev: u64,
+    ino: u64,
+}
+
+impl Namespace {
+    /// Take ownership of a namespace file descriptor.
+    ///
+    /// Fails with `EINVAL` or `ENOTTY` if `fd` does not refer to a
+    /// namespace.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        let ret = unsafe { libc::ioctl(fd.as_raw_fd(), libc::NS_GET_NSTYPE) };
+        if ret == -1 {
+            return Err(Error::OsErrno(nix::errno::errno()));
+        }
+        let typ = NamespaceType::from_clone_flag(ret).ok_or(Error::OsErrno(libc::EINVAL))?;
+        let meta = File::from(fd.try_clone()?).metadata()?;
+        Ok(Self {
+            fd,
+            typ,
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+
+    /// Open a namespace file, like `/proc/<pid>/ns/net` or one created
+    /// by `persist`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_fd(File::open(path)?.into())
+    }
+
+    /// Open the namespace of type `typ` of the process `pid`.
+    pub fn of_process(pid: i32, typ: NamespaceType) -> Result<Self, Error> {
+        Self::open(format!("/proc/{}/ns/{}", pid, typ.proc_name()))
+    }
+
+    /// Open the namespace of type `typ` of the calling thread.
+    pub fn current(typ: NamespaceType) -> Result<Self, Error> {
+        Self::open(format!("/proc/thread-self/ns/{}", typ.proc_name()))
+    }
+
+    /// Type of the namespace.
+    pub fn typ(&self) -> NamespaceType {
+        self.typ
+    }
+
+    /// Device of the namespace filesystem the namespace is on.
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Inode number identifying the namespace, as shown by `lsns(8)`.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// User namespace owning the namespace.
+    pub fn owner(&self) -> Result<Self, Error> {
+        self.ioctl_ns(libc::NS_GET_USERNS)
+    }
+
+    /// Parent of a user or pid namespace.
+    ///
+    /// Fails with `EPERM` if the parent is outside of the namespaces of
+    /// the caller, and `EINVAL` for other types of namespace.
+    pub fn parent(&self) -> Result<Self, Error> {
+        self.ioctl_ns(libc::NS_GET_PARENT)
+    }
+
+    /// Move the calling thread into the namespace with `setns(2)`.
+    pub fn enter(&self) -> Result<(), Error> {
+        match unsafe { libc::setns(self.fd.as_raw_fd(), self.typ.clone_flag()) } {
+            -1 => Err(Error::OsErrno(nix::errno::errno())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep the namespace alive by bind-mounting it to `path`, like
+    /// `ip netns add` does.
+    ///
+    /// The file is created if it does not exist. The mount belongs to
+    /// the mount namespace of the caller, which needs `CAP_SYS_ADMIN` in
+    /// its owner. A mount namespace can not be persisted inside itself.
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        nix::mount::mount(
+            Some(Path::new(&format!("/proc/self/fd/{}", self.fd.as_raw_fd()))),
+            path,
+            None::<&str>,
+            nix::mount::MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| Error::OsErrno(e as i32))
+    }
+
+    /// Undo `persist`, removing the file at `path`.
+    ///
+    /// The namespace lives on while it is in use.
+    pub fn unpersist<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        nix::mount::umount2(path, nix::mount::MntFlags::MNT_DETACH)
+            .map_err(|e| Error::OsErrno(e as i32))?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn ioctl_ns(&self, request: libc::Ioctl) -> Result<Self, Error> {
+        match unsafe { libc::ioctl(self.fd.as_raw_fd(), request) } {
+            -1 => Err(Error::OsErrno(nix::errno::errno())),
+            fd => Self::from_fd(unsafe { OwnedFd::from_raw_fd(fd) }),
+        }
+    }
+}
+
+impl PartialEq for Namespace {
+    fn eq(&self, other: &Self) -> bool {
+        (self.dev, self.ino) == (other.dev, other.ino)
+    }
+}
+
+impl Eq for Namespace {}
+
+impl AsFd for Namespace {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for Namespace {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl From<Namespace> for OwnedFd {
+    fn from(ns: Namespace) -> Self {
+        ns.fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, Wrap};
+
+    #[test]
+    fn namespace() {
+        let (hold_rd, hold_wr) = nix::unistd::pipe().unwrap();
+        let mut wrap = Wrap::new();
+        unsafe {
+            wrap.callback(move || {
+                let _ = nix::unistd::close(hold_wr);
+                // Stay alive until the test closes its end of the pipe
+                let _ = nix::unistd::read(hold_rd, &mut [0]);
+                0
+            })
+        }
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Pid);
+        let mut child = wrap.spawn().unwrap();
+        let pid = child.id() as i32;
+
+        let user = Namespace::of_process(pid, NamespaceType::User).unwrap();
+        let pid_ns = Namespace::of_process(pid, NamespaceType::Pid).unwrap();
+        let uts = Namespace::of_process(pid, NamespaceType::Uts).unwrap();
+        assert_eq!(user.typ(), NamespaceType::User);
+        assert_eq!(pid_ns.typ(), NamespaceType::Pid);
+        assert_eq!(pid_ns.owner().unwrap(), user);
+        assert_ne!(user, Namespace::current(NamespaceType::User).unwrap());
+        assert_eq!(
+            user.parent().unwrap(),
+            Namespace::current(NamespaceType::User).unwrap()
+        );
+        assert_eq!(
+            pid_ns.parent().unwrap(),
+            Namespace::current(NamespaceType::Pid).unwrap()
+        );
+        // Not a hierarchical namespace
+        assert!(uts.parent().is_err());
+        assert_eq!(uts, Namespace::current(NamespaceType::Uts).unwrap());
+
+        let fd: OwnedFd = uts.into();
+        assert!(Namespace::from_fd(fd).is_ok());
+        let file: OwnedFd = File::open("/proc/self/stat").unwrap().into();
+        assert!(Namespace::from_fd(file).is_err());
+
+        nix::unistd::close(hold_wr).unwrap();
+        assert_eq!(child.wait().unwrap().code().unwrap(), 0);
+    }
+
+    #[test]
+    fn persist() {
+        const PATH: &str = "/tmp/nswrap.test.ns";
+        let cb = || {
+            let _ = std::fs::remove_file(PATH);
+            let first = Namespace::current(NamespaceType::Uts).unwrap();
+            nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWUTS).unwrap();
+            nix::unistd::sethostname("persisted").unwrap();
+            let persisted = Namespace::current(NamespaceType::Uts).unwrap();
+            persisted.persist(PATH).unwrap();
+            drop(persisted);
+
+            first.enter().unwrap();
+            if nix::unistd::gethostname().unwrap() == "persisted" {
+                return 1;
+            }
+            Namespace::open(PATH).unwrap().enter().unwrap();
+            if nix::unistd::gethostname().unwrap() != "persisted" {
+                return 2;
+            }
+            Namespace::unpersist(PATH).unwrap();
+            0
+        };
+        let mut wrap = Wrap::new();
+        unsafe { wrap.callback(cb) }
+            .unshare(config::NamespaceType::User)
+            .unshare(config::NamespaceType::Mount)
+            .unshare(config::NamespaceType::Uts)
+            .root_propagation(config::MountPropagation::Private)
+            .id_map_preset(config::IdMapPreset::Root);
+        let ret = wrap.spawn().unwrap().wait().unwrap();
+        assert_eq!(ret.code().unwrap(), 0);
+    }
+}
@@ -0,0 +1,627 @@
+//! Support of bundles of the [OCI Runtime Specification].
+//!
+//! The types in `config` are modelled on the specification, this module
+//! reads them from `config.json` and translates them into a `Wrap`.
+//!
+//! [OCI Runtime Specification]: https://github.com/opencontainers/runtime-spec
+
+use crate::{config, error::Error, Wrap};
+use getset::{Getters, Setters};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the configuration file in a bundle
+pub const CONFIG_FILE: &str = "config.json";
+
+/// Version of the specification nswrap implements.
+pub const OCI_VERSION: &str = "1.0.2";
+
+/// Fields of `config.json` that nswrap supports, with `[]` standing for
+/// any element of an array.
+///
+/// Any other field is reported by `Spec::load`, unless its value is
+/// empty, like `false`, `null`, `[]` or `{}`.
+const SUPPORTED_FIELDS: &[&str] = &[
+    "ociVersion",
+    "root",
+    "root.path",
+    "root.readonly",
+    "mounts",
+    "mounts[].destination",
+    "mounts[].type",
+    "mounts[].source",
+    "mounts[].options",
+    "process",
+    "process.terminal",
+    "process.args",
+    "process.env",
+    "process.cwd",
+    "process.user",
+    "process.user.uid",
+    "process.user.gid",
+    "process.user.umask",
+    "process.rlimits",
+    "process.rlimits[].type",
+    "process.rlimits[].hard",
+    "process.rlimits[].soft",
+    "process.noNewPrivileges",
+    "hostname",
+    "hooks",
+    "hooks.prestart",
+    "hooks.createRuntime",
+    "hooks.createContainer",
+    "hooks.startContainer",
+    "hooks.poststart",
+    "hooks.poststop",
+    "annotations",
+    "linux",
+    "linux.namespaces",
+    "linux.namespaces[].type",
+    "linux.namespaces[].path",
+    "linux.uidMappings",
+    "linux.uidMappings[].hostID",
+    "linux.uidMappings[].containerID",
+    "linux.uidMappings[].size",
+    "linux.gidMappings",
+    "linux.gidMappings[].hostID",
+    "linux.gidMappings[].containerID",
+    "linux.gidMappings[].size",
+    "linux.rootfsPropagation",
+];
+
+/// Fields of the hooks in each of the `hooks.*` arrays.
+const HOOK_FIELDS: &[&str] = &["path", "args", "env", "timeout"];
+
+/// Fields whose content is free-form, so it is not checked.
+const FREE_FORM_FIELDS: &[&str] = &["annotations"];
+
+/// Device nodes every container gets, see "Default Devices" of the
+/// specification.
+const DEFAULT_DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Symbolic links every container gets in `/dev`.
+const DEFAULT_LINKS: [(&str, &str); 5] = [
+    ("/proc/self/fd", "/dev/fd"),
+    ("/proc/self/fd/0", "/dev/stdin"),
+    ("/proc/self/fd/1", "/dev/stdout"),
+    ("/proc/self/fd/2", "/dev/stderr"),
+    ("pts/ptmx", "/dev/ptmx"),
+];
+
+/// Container configuration, the content of `config.json`.
+#[derive(Getters, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+#[getset(get = "pub")]
+pub struct Spec {
+    /// Version of the specification the bundle complies with.
+    oci_version: String,
+    /// Root filesystem of the container.
+    root: Option<config::Root>,
+    /// Additional mounts beyond `root`, in the order to mount them.
+    mounts: Vec<config::Mount>,
+    /// Container process.
+    process: Option<config::Process>,
+    /// Hostname of the container.
+    hostname: Option<String>,
+    /// Commands to run at points of the container lifecycle.
+    hooks: Option<config::Hooks>,
+    /// Arbitrary metadata for the container.
+    annotations: BTreeMap<String, String>,
+    /// Linux specific configuration.
+    linux: Option<Linux>,
+}
+
+/// Linux specific configuration.
+#[derive(Getters, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+#[getset(get = "pub")]
+pub struct Linux {
+    /// Namespaces to create or join.
+    namespaces: Vec<Namespace>,
+    /// User namespace uid mappings from the host to the container.
+    uid_mappings: Vec<config::IdMap>,
+    /// User namespace gid mappings from the host to the container.
+    gid_mappings: Vec<config::IdMap>,
+    /// Propagation of the root filesystem, like `slave` or `private`.
+    rootfs_propagation: Option<String>,
+}
+
+/// A namespace to create, or to join if `path` is set.
+#[derive(Getters, Clone, Serialize, Deserialize)]
+#[getset(get = "pub")]
+pub struct Namespace {
+    #[serde(rename = "type")]
+    typ: config::NamespaceType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+}
+
+/// Runtime state of a container, as reported by the `state` operation.
+#[derive(Getters, Setters, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub", set = "pub")]
+pub struct State {
+    /// Version of the specification the state complies with.
+    oci_version: String,
+    /// Id of the container, unique on the host.
+    id: String,
+    /// Lifecycle status of the container.
+    status: Status,
+    /// Process id of the container process, in the runtime's pid namespace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+    /// Absolute path of the bundle.
+    bundle: PathBuf,
+    /// Annotations copied from the configuration.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+/// Lifecycle status of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The container is being created.
+    Creating,
+    /// The container is created, but the user process is not started yet.
+    Created,
+    /// The user process is running.
+    Running,
+    /// The processes of the container are frozen. Not part of the
+    /// specification, but reported by runc.
+    Paused,
+    /// The user process has exited.
+    Stopped,
+}
+
+impl State {
+    /// State of a container that is being created from `spec`.
+    pub fn new(id: &str, bundle: &Path, spec: &Spec) -> Self {
+        Self {
+            oci_version: OCI_VERSION.into(),
+            id: id.into(),
+            status: Status::Creating,
+            pid: None,
+            bundle: bundle.into(),
+            annotations: spec.annotations.clone(),
+        }
+    }
+}
+
+impl Spec {
+    /// Read and validate `config.json` of `bundle`.
+    pub fn load(bundle: &Path) -> Result<Self, Error> {
+        let content = std::fs::read(bundle.join(CONFIG_FILE))?;
+        let value: Value =
+            serde_json::from_slice(&content).map_err(|e| Error::InvalidOciSpec(e.to_string()))?;
+
+        let mut unsupported = Vec::new();
+        find_unsupported_fields(&value, "", "", &mut unsupported);
+        if !unsupported.is_empty() {
+            return Err(Error::UnsupportedOciFields(unsupported));
+        }
+
+        let spec: Spec =
+            serde_json::from_value(value).map_err(|e| Error::InvalidOciSpec(e.to_string()))?;
+        spec.validate(bundle)?;
+        Ok(spec)
+    }
+
+    fn validate(&self, bundle: &Path) -> Result<(), Error> {
+        let invalid = |msg: &str| Err(Error::InvalidOciSpec(msg.to_string()));
+
+        if self.oci_version.split('.').next() != Some("1") {
+            return Err(Error::InvalidOciSpec(format!(
+                "unsupported ociVersion {:?}",
+                self.oci_version
+            )));
+        }
+        match &self.root {
+            Some(root) if bundle.join(root.path()).is_dir() => (),
+            Some(_) => return invalid("root.path is not a directory"),
+            None => return invalid("root is required"),
+        }
+        match &self.process {
+            Some(process) if process.args().is_empty() => {
+                return invalid("process.args must not be empty")
+            }
+            Some(process) if !process.cwd().is_absolute() => {
+                return invalid("process.cwd must be an absolute path")
+            }
+            Some(process) => {
+                if let Some(r) = process.rlimits().iter().find(|r| r.resource().is_none()) {
+                    return Err(Error::InvalidOciSpec(format!(
+                        "unknown rlimit type {:?}",
+                        r.typ()
+                    )));
+                }
+            }
+            None => return invalid("process is required"),
+        }
+        let linux = self.linux.clone().unwrap_or_default();
+        if !linux
+            .namespaces
+            .iter()
+            .any(|ns| ns.typ == config::NamespaceType::Mount)
+        {
+            return invalid("a mount namespace is required");
+        }
+        if let Some(ns) = linux
+            .namespaces
+            .iter()
+            .find(|ns| ns.typ == config::NamespaceType::Time)
+        {
+            return Err(Error::UnsupportedOciFields(vec![format!(
+                "linux.namespaces.{:?}",
+                ns.typ
+            )]));
+        }
+        if let Some(propagation) = &linux.rootfs_propagation {
+            if propagation.parse::<config::MountPropagation>().is_err() {
+                return Err(Error::InvalidOciSpec(format!(
+                    "unknown rootfsPropagation {:?}",
+                    propagation
+                )));
+            }
+        }
+        let hooks = self.hooks.clone().unwrap_or_default();
+        for hook in hooks
+            .prestart()
+            .iter()
+            .chain(hooks.create_runtime())
+            .chain(hooks.create_container())
+            .chain(hooks.start_container())
+            .chain(hooks.poststart())
+            .chain(hooks.poststop())
+        {
+            if !hook.path().is_absolute() {
+                return invalid("hook path must be an absolute path");
+            }
+            if hook.timeout() == Some(0) {
+                return invalid("hook timeout must be greater than zero");
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate the configuration into a `Wrap`.
+    ///
+    /// Relative paths in the configuration are relative to `bundle`. The
+    /// configuration is validated like `load` does, in case it was not
+    /// loaded from there.
+    pub fn build<'a>(&self, bundle: &Path) -> Result<Wrap<'a>, Error> {
+        let bundle = bundle.canonicalize()?;
+        self.validate(&bundle)?;
+        let mut wrap = Wrap::new();
+
+        let linux = self.linux.clone().unwrap_or_default();
+        for ns in linux.namespaces() {
+            match ns.path() {
+                Some(path) => {
+                    let namespace = crate::ns::Namespace::open(path)?;
+                    if namespace.typ() != ns.typ {
+                        return Err(Error::InvalidOciSpec(format!(
+                            "{} is not a {:?} namespace",
+                            path.display(),
+                            ns.typ
+                        )));
+                    }
+                    wrap.nsenter(namespace)
+                }
+                None => wrap.unshare(ns.typ),
+            };
+        }
+        for map in linux.uid_mappings() {
+            wrap.uid_map(map.host_id(), map.container_id(), map.size());
+        }
+        for map in linux.gid_mappings() {
+            wrap.gid_map(map.host_id(), map.container_id(), map.size());
+        }
+        if let Some(propagation) = linux.rootfs_propagation() {
+            wrap.root_propagation(propagation.parse().unwrap());
+        }
+
+        let mut root = self.root.clone().unwrap();
+        root.set_path(bundle.join(root.path()));
+        wrap.root(root);
+
+        for mnt in &self.mounts {
+            let mut mnt = mnt.clone();
+            if mnt.is_bind() {
+                if let Some(source) = mnt.source() {
+                    mnt.set_source(Some(bundle.join(source)));
+                }
+            }
+            wrap.mount(mnt);
+        }
+        for dev in DEFAULT_DEVICES {
+            let path = Path::new("/dev").join(dev);
+            let mut mnt = config::Mount::default();
+            mnt.set_destination(path.clone())
+                .set_source(Some(path))
+                .set_typ(Some("bind".into()))
+                .set_options(Some(vec!["bind".into()]));
+            wrap.mount(mnt);
+        }
+        for (target, link) in DEFAULT_LINKS {
+            let mut mnt = config::Mount::default();
+            mnt.set_destination(link.into())
+                .set_source(Some(target.into()))
+                .set_typ(Some("symlink".into()));
+            wrap.mount(mnt);
+        }
+
+        let mut process = self.process.clone().unwrap();
+        // Unlike `Wrap::new_cmd`, the environment is only what `env` lists
+        if process.env().is_none() {
+            process.set_env(Some(Vec::new()));
+        }
+        wrap.tty(process.terminal());
+        let mut args = process.args().clone();
+        process.set_bin(args.remove(0));
+        process.set_args(args);
+        wrap.set_process(process);
+
+        if let Some(hostname) = &self.hostname {
+            wrap.hostname(hostname);
+        }
+        Ok(wrap)
+    }
+}
+
+/// Collect paths of fields in `value` that are not supported, like
+/// `mounts[1].uidMappings`.
+///
+/// `field` is the path of `value` as written in `SUPPORTED_FIELDS`, and
+/// `path` the same with the indexes of array elements.
+fn find_unsupported_fields(value: &Value, field: &str, path: &str, unsupported: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let (field, path) = match field {
+                    "" => (key.clone(), key.clone()),
+                    _ => (format!("{}.{}", field, key), format!("{}.{}", path, key)),
+                };
+                if FREE_FORM_FIELDS.contains(&field.as_str()) {
+                    continue;
+                } else if is_supported(&field) {
+                    find_unsupported_fields(value, &field, &path, unsupported);
+                } else if !is_empty_value(value) {
+                    unsupported.push(path);
+                }
+            }
+        }
+        Value::Array(items) => {
+            let field = format!("{}[]", field);
+            for (i, item) in items.iter().enumerate() {
+                find_unsupported_fields(item, &field, &format!("{}[{}]", path, i), unsupported);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn is_supported(field: &str) -> bool {
+    if SUPPORTED_FIELDS.contains(&field) {
+        return true;
+    }
+    // Fields of a hook, like `hooks.prestart[].path`
+    match field.split_once("[].") {
+        Some((hooks, key)) if hooks.starts_with("hooks.") => {
+            SUPPORTED_FIELDS.contains(&hooks) && HOOK_FIELDS.contains(&key)
+        }
+        _ => false,
+    }
+}
+
+fn is_empty_value(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => true,
+        Value::Array(a) => a.is_empty(),
+        Value::Object(o) => o.is_empty(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use serde_json::json;
+
+    /// Create a bundle with an empty rootfs and `config` as `config.json`.
+    fn make_bundle(name: &str, config: &Value) -> PathBuf {
+        let bundle = Path::new("/tmp/nswrap.test.oci").join(name);
+        let _ = std::fs::remove_dir_all(&bundle);
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        std::fs::write(bundle.join(CONFIG_FILE), config.to_string()).unwrap();
+        bundle
+    }
+
+    fn minimal_config() -> Value {
+        json!({
+            "ociVersion": "1.0.2",
+            "root": {"path": "rootfs"},
+            "process": {"args": ["/bin/sh"], "cwd": "/"},
+            "linux": {"namespaces": [{"type": "user"}, {"type": "mount"}]},
+        })
+    }
+
+    #[test]
+    fn run_bundle() {
+        let config = json!({
+            "ociVersion": "1.0.2",
+            "root": {"path": "rootfs", "readonly": true},
+            "process": {
+                "args": [
+                    "/usr/bin/sh",
+                    "-c",
+                    "test $(cat /proc/sys/kernel/hostname) = oci && test $$ -eq 1 \
+                     && test $(id -u) -eq 0 && test $FOO = bar && test $(pwd) = /usr \
+                     && test $(ulimit -n) -eq 256 && test -c /dev/null && ! touch /x 2>/dev/null",
+                ],
+                "env": ["PATH=/usr/bin", "FOO=bar"],
+                "cwd": "/usr",
+                "user": {"uid": 0, "gid": 0},
+                "rlimits": [{"type": "RLIMIT_NOFILE", "hard": 256, "soft": 256}],
+            },
+            "hostname": "oci",
+            "mounts": [
+                {"destination": "/usr", "type": "bind", "source": "/usr", "options": ["rbind", "ro"]},
+                {"destination": "/proc", "type": "proc", "source": "proc"},
+                {"destination": "/dev", "type": "tmpfs", "source": "tmpfs", "options": ["nosuid", "mode=755"]},
+            ],
+            "linux": {
+                "namespaces": [{"type": "user"}, {"type": "mount"}, {"type": "pid"}, {"type": "uts"}],
+                "uidMappings": [{"hostID": util::get_uid(), "containerID": 0, "size": 1}],
+                "gidMappings": [{"hostID": util::get_gid(), "containerID": 0, "size": 1}],
+                "rootfsPropagation": "private",
+            },
+        });
+        let bundle = make_bundle("run", &config);
+        // Mirror `/bin`, `/lib` and so on of the host, which are either
+        // symbolic links into `/usr` or directories to bind.
+        let mut config = config;
+        for dir in ["bin", "sbin", "lib", "lib32", "lib64"] {
+            let path = Path::new("/").join(dir);
+            match std::fs::read_link(&path) {
+                Ok(target) => {
+                    std::os::unix::fs::symlink(target, bundle.join("rootfs").join(dir)).unwrap()
+                }
+                Err(_) if path.is_dir() => config["mounts"].as_array_mut().unwrap().push(json!(
+                    {"destination": path, "type": "bind", "source": path, "options": ["rbind", "ro"]}
+                )),
+                Err(_) => (),
+            }
+        }
+        std::fs::write(bundle.join(CONFIG_FILE), config.to_string()).unwrap();
+        let mut wrap = Wrap::from_oci_bundle(&bundle).unwrap();
+        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn unsupported_fields() {
+        let mut config = minimal_config();
+        config["process"]["terminal"] = json!(false);
+        config["process"]["capabilities"] = json!({"bounding": ["CAP_KILL"]});
+        config["linux"]["maskedPaths"] = json!([]);
+        config["linux"]["seccomp"] = json!({"defaultAction": "SCMP_ACT_ALLOW"});
+        config["annotations"] = json!({"org.example.key": "value"});
+        config["mounts"] = json!([
+            {"destination": "/tmp", "type": "tmpfs"},
+            {"destination": "/usr", "type": "bind", "source": "/usr", "uidMappings": [{"size": 1}]},
+        ]);
+        config["hooks"] = json!({"prestart": [{"path": "/bin/true", "timeout": 1, "retries": 3}]});
+        let bundle = make_bundle("unsupported", &config);
+        match Spec::load(&bundle) {
+            Err(Error::UnsupportedOciFields(fields)) => assert_eq!(
+                fields,
+                [
+                    "hooks.prestart[0].retries",
+                    "linux.seccomp",
+                    "mounts[1].uidMappings",
+                    "process.capabilities"
+                ]
+            ),
+            _ => panic!("unsupported fields are not reported"),
+        }
+    }
+
+    #[test]
+    fn empty_env() {
+        let bundle = make_bundle("env", &minimal_config());
+        let spec = Spec::load(&bundle).unwrap();
+        let wrap = spec.build(&bundle).unwrap();
+        assert_eq!(wrap.process.unwrap().env().as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn invalid_spec() {
+        let mut config = minimal_config();
+        config["ociVersion"] = json!("2.0.0");
+        let bundle = make_bundle("version", &config);
+        assert!(matches!(Spec::load(&bundle), Err(Error::InvalidOciSpec(_))));
+
+        let mut config = minimal_config();
+        config["process"]["cwd"] = json!("relative");
+        let bundle = make_bundle("cwd", &config);
+        assert!(matches!(Spec::load(&bundle), Err(Error::InvalidOciSpec(_))));
+
+        let bundle = make_bundle("minimal", &minimal_config());
+        assert!(Spec::load(&bundle).is_ok());
+
+        // Without going through `load`
+        let ret = Spec::default().build(&bundle);
+        assert!(matches!(ret, Err(Error::InvalidOciSpec(_))));
+        let mut config = minimal_config();
+        config.as_object_mut().unwrap().remove("process");
+        let spec: Spec = serde_json::from_value(config).unwrap();
+        assert!(matches!(spec.build(&bundle), Err(Error::InvalidOciSpec(_))));
+    }
+
+    #[test]
+    fn hooks() {
+        let out = Path::new("/tmp/nswrap.test.oci/hooks.out");
+        let _ = std::fs::remove_dir_all(out);
+        std::fs::create_dir_all(out).unwrap();
+        // Save the state and the mount namespace the hook runs in
+        let record = |name: &str| {
+            json!({
+                "path": "/bin/sh",
+                "args": ["sh", "-c", format!(
+                    "cat > {0}/{1}.json && readlink /proc/self/ns/mnt > {0}/{1}.ns",
+                    out.display(),
+                    name
+                )],
+                "env": ["PATH=/usr/bin:/bin"],
+            })
+        };
+        let mut config = minimal_config();
+        config["linux"]["uidMappings"] =
+            json!([{"hostID": util::get_uid(), "containerID": 0, "size": 1}]);
+        config["linux"]["gidMappings"] =
+            json!([{"hostID": util::get_gid(), "containerID": 0, "size": 1}]);
+        config["hooks"] = json!({
+            "createRuntime": [record("runtime")],
+            "createContainer": [record("container")],
+            "startContainer": [{"path": "/nonexistent"}],
+        });
+        let bundle = make_bundle("hooks", &config);
+        let spec = Spec::load(&bundle).unwrap();
+        let mut wrap = spec.build(&bundle).unwrap();
+        wrap.hooks(
+            spec.hooks().clone().unwrap(),
+            State::new("hooks", &bundle, &spec),
+        );
+        let mut child = wrap.spawn().unwrap();
+        // The failed startContainer hook stops the process from executing
+        assert_eq!(child.wait().unwrap().code(), Some(1));
+
+        let read_state = |name: &str| -> Value {
+            serde_json::from_slice(&std::fs::read(out.join(name)).unwrap()).unwrap()
+        };
+        let state = read_state("runtime.json");
+        assert_eq!(state["pid"], child.id());
+        assert_eq!(state["id"], "hooks");
+        assert_eq!(read_state("container.json")["pid"], child.id());
+
+        let ns = |name: &str| std::fs::read_to_string(out.join(name)).unwrap();
+        let own = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+        assert_eq!(ns("runtime.ns").trim(), own.to_str().unwrap());
+        assert_ne!(ns("container.ns").trim(), own.to_str().unwrap());
+
+        // A failed createRuntime hook fails `spawn`
+        config["hooks"] = json!({"createRuntime": [{"path": "/bin/false"}]});
+        let bundle = make_bundle("hooks", &config);
+        let spec = Spec::load(&bundle).unwrap();
+        let mut wrap = spec.build(&bundle).unwrap();
+        wrap.hooks(
+            spec.hooks().clone().unwrap(),
+            State::new("hooks", &bundle, &spec),
+        );
+        assert!(matches!(wrap.spawn(), Err(Error::HookFailed(_))));
+    }
+}
@@ -0,0 +1,389 @@
+//! Description of the setup of a child, from `Wrap::plan`.
+//!
+//! The operations are the ones `Wrap::spawn` performs, in the same order,
+//! so a failing container can be diagnosed without reading `core.rs`.
+//! A `Plan` prints as one operation per line, or as JSON with serde.
+
+use crate::config::NamespaceType;
+use crate::util::CloneFlags;
+use getset::Getters;
+use nix::mount::{MntFlags, MsFlags};
+use serde::Serialize;
+use std::fmt;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+
+/// Names of the `CLONE_NEW*` flags of `clone(2)`.
+const CLONE_FLAGS: [(libc::c_int, &str); 8] = [
+    (libc::CLONE_NEWNS, "CLONE_NEWNS"),
+    (libc::CLONE_NEWCGROUP, "CLONE_NEWCGROUP"),
+    (libc::CLONE_NEWUTS, "CLONE_NEWUTS"),
+    (libc::CLONE_NEWIPC, "CLONE_NEWIPC"),
+    (libc::CLONE_NEWUSER, "CLONE_NEWUSER"),
+    (libc::CLONE_NEWPID, "CLONE_NEWPID"),
+    (libc::CLONE_NEWNET, "CLONE_NEWNET"),
+    (libc::CLONE_NEWTIME, "CLONE_NEWTIME"),
+];
+
+/// Names of the `MS_*` flags of `mount(2)`.
+const MS_FLAGS: [(MsFlags, &str); 21] = [
+    (MsFlags::MS_RDONLY, "MS_RDONLY"),
+    (MsFlags::MS_NOSUID, "MS_NOSUID"),
+    (MsFlags::MS_NODEV, "MS_NODEV"),
+    (MsFlags::MS_NOEXEC, "MS_NOEXEC"),
+    (MsFlags::MS_SYNCHRONOUS, "MS_SYNCHRONOUS"),
+    (MsFlags::MS_REMOUNT, "MS_REMOUNT"),
+    (MsFlags::MS_MANDLOCK, "MS_MANDLOCK"),
+    (MsFlags::MS_DIRSYNC, "MS_DIRSYNC"),
+    (MsFlags::MS_NOATIME, "MS_NOATIME"),
+    (MsFlags::MS_NODIRATIME, "MS_NODIRATIME"),
+    (MsFlags::MS_BIND, "MS_BIND"),
+    (MsFlags::MS_MOVE, "MS_MOVE"),
+    (MsFlags::MS_REC, "MS_REC"),
+    (MsFlags::MS_SILENT, "MS_SILENT"),
+    (MsFlags::MS_UNBINDABLE, "MS_UNBINDABLE"),
+    (MsFlags::MS_PRIVATE, "MS_PRIVATE"),
+    (MsFlags::MS_SLAVE, "MS_SLAVE"),
+    (MsFlags::MS_SHARED, "MS_SHARED"),
+    (MsFlags::MS_RELATIME, "MS_RELATIME"),
+    (MsFlags::MS_STRICTATIME, "MS_STRICTATIME"),
+    (MsFlags::MS_LAZYTIME, "MS_LAZYTIME"),
+];
+
+/// Names of the flags of `umount2(2)`.
+const MNT_FLAGS: [(MntFlags, &str); 4] = [
+    (MntFlags::MNT_FORCE, "MNT_FORCE"),
+    (MntFlags::MNT_DETACH, "MNT_DETACH"),
+    (MntFlags::MNT_EXPIRE, "MNT_EXPIRE"),
+    (MntFlags::UMOUNT_NOFOLLOW, "UMOUNT_NOFOLLOW"),
+];
+
+/// Operations `Wrap::spawn` would perform, in order.
+#[derive(Getters, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[getset(get = "pub")]
+pub struct Plan {
+    /// Operations in the order they are performed.
+    operations: Vec<Operation>,
+}
+
+/// An operation of `Wrap::spawn`, in the child unless stated otherwise.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// `clone(2)` of the child by the parent, creating the namespaces of
+    /// `flags`.
+    Clone {
+        flags: Vec<String>,
+    },
+    /// `prctl(2)` with the `PR_*` option `option`.
+    Prctl {
+        option: String,
+        value: u64,
+    },
+    /// Connect the standard stream `fd` to `/dev/null` or a pipe.
+    Stdio {
+        fd: RawFd,
+        to: String,
+    },
+    /// Move the child into the cgroup at `path`.
+    EnterCgroup {
+        path: PathBuf,
+    },
+    /// `setns(2)` into the namespaces of the process `pid` the child is
+    /// not in yet, along with its root and working directory if the
+    /// mount namespace is joined.
+    ///
+    /// Joining a pid namespace forks the child, as it only applies to
+    /// new processes.
+    JoinProcess {
+        pid: i32,
+        namespaces: Vec<NamespaceType>,
+    },
+    /// `setns(2)` into a namespace given by `Wrap::nsenter`.
+    Setns {
+        namespace: NamespaceType,
+        fd: RawFd,
+    },
+    /// `unshare(2)` of namespaces `clone(2)` did not create.
+    Unshare {
+        namespaces: Vec<NamespaceType>,
+    },
+    /// Write `content` to the existing file `path`, like id maps.
+    WriteFile {
+        path: PathBuf,
+        content: String,
+    },
+    Sethostname {
+        hostname: String,
+    },
+    /// Run an OCI hook of `stage`, the `prestart` and `createRuntime`
+    /// ones in the parent.
+    Hook {
+        stage: String,
+        path: PathBuf,
+        args: Vec<String>,
+    },
+    /// `mount(2)`.
+    Mount {
+        source: Option<PathBuf>,
+        target: PathBuf,
+        fstype: Option<String>,
+        flags: Vec<String>,
+        data: Option<String>,
+    },
+    /// Remount the bind mount at `target` with `flags`, plus the flags
+    /// locked on it.
+    RemountBind {
+        target: PathBuf,
+        flags: Vec<String>,
+        data: Option<String>,
+    },
+    /// Create the directory `path`, or a file if `like` is not a
+    /// directory.
+    MountPoint {
+        path: PathBuf,
+        like: Option<PathBuf>,
+    },
+    Symlink {
+        target: PathBuf,
+        link: PathBuf,
+    },
+    /// Cover `path` with a read-only bind mount of itself, if it exists.
+    ProtectIfExists {
+        path: PathBuf,
+    },
+    Chdir {
+        path: PathBuf,
+    },
+    PivotRoot {
+        new_root: PathBuf,
+        put_old: PathBuf,
+    },
+    Umount {
+        target: PathBuf,
+        flags: Vec<String>,
+    },
+    /// Allocate a pseudo-terminal as the controlling terminal and stdio,
+    /// and send its master to the parent.
+    SetUpPty,
+    /// Run the callbacks of `Wrap::callback`.
+    Callbacks {
+        count: usize,
+    },
+    /// Duplicate the fd `from` of the parent to `to`.
+    MapFd {
+        from: RawFd,
+        to: RawFd,
+    },
+    /// Close every fd above stdio but `keep`.
+    CloseFds {
+        keep: Vec<RawFd>,
+    },
+    /// Enforce a Landlock ruleset, see `landlock(7)`.
+    Landlock {
+        handled_fs: Vec<String>,
+        handled_net: Vec<String>,
+        paths: Vec<(PathBuf, Vec<String>)>,
+        ports: Vec<(u16, Vec<String>)>,
+    },
+    Setrlimit {
+        resource: String,
+        soft: u64,
+        hard: u64,
+    },
+    Umask {
+        mask: u32,
+    },
+    /// `setresgid(2)` and `setresuid(2)`.
+    SetUser {
+        uid: u32,
+        gid: u32,
+    },
+    /// `execvpe(3)`.
+    Exec {
+        argv: Vec<String>,
+        env: Vec<String>,
+    },
+}
+
+impl Plan {
+    pub(crate) fn new(operations: Vec<Operation>) -> Self {
+        Self { operations }
+    }
+}
+
+/// Names of the `CLONE_NEW*` flags set in `flags`.
+pub(crate) fn clone_flag_names(flags: CloneFlags) -> Vec<String> {
+    CLONE_FLAGS
+        .iter()
+        .filter(|(flag, _)| flags.bits() as libc::c_int & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// Names of the `MS_*` flags set in `flags`.
+pub(crate) fn ms_flag_names(flags: MsFlags) -> Vec<String> {
+    MS_FLAGS
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// Names of the `umount2(2)` flags set in `flags`.
+pub(crate) fn mnt_flag_names(flags: MntFlags) -> Vec<String> {
+    MNT_FLAGS
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// Names of the flags of a `bitflags` type, like `LandlockFs`.
+pub(crate) fn bitflag_names<B: bitflags::Flags>(flags: B) -> Vec<String> {
+    flags
+        .iter_names()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, op) in self.operations.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", i + 1, op)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |names: &[String]| match names.is_empty() {
+            true => "0".to_string(),
+            false => names.join("|"),
+        };
+        let ns_list = |namespaces: &[NamespaceType]| {
+            namespaces
+                .iter()
+                .map(|ns| ns.proc_name())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            Operation::Clone { flags } => write!(f, "clone {}", list(flags)),
+            Operation::Prctl { option, value } => write!(f, "prctl {} {}", option, value),
+            Operation::Stdio { fd, to } => write!(f, "connect fd {} to {}", fd, to),
+            Operation::EnterCgroup { path } => write!(f, "enter cgroup {}", path.display()),
+            Operation::JoinProcess { pid, namespaces } => {
+                write!(f, "join namespaces {} of pid {}", ns_list(namespaces), pid)
+            }
+            Operation::Setns { namespace, fd } => {
+                write!(f, "setns {} fd {}", namespace.proc_name(), fd)
+            }
+            Operation::Unshare { namespaces } => write!(f, "unshare {}", ns_list(namespaces)),
+            Operation::WriteFile { path, content } => {
+                write!(f, "write {:?} to {}", content, path.display())
+            }
+            Operation::Sethostname { hostname } => write!(f, "sethostname {}", hostname),
+            Operation::Hook { stage, path, args } => {
+                write!(f, "run {} hook {}", stage, path.display())?;
+                args.iter()
+                    .skip(1)
+                    .try_for_each(|arg| write!(f, " {}", arg))
+            }
+            Operation::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data,
+            } => {
+                write!(f, "mount ")?;
+                if let Some(source) = source {
+                    write!(f, "{} on ", source.display())?;
+                }
+                write!(f, "{}", target.display())?;
+                if let Some(fstype) = fstype {
+                    write!(f, " type {}", fstype)?;
+                }
+                write!(f, " ({}", list(flags))?;
+                if let Some(data) = data {
+                    write!(f, ", {}", data)?;
+                }
+                write!(f, ")")
+            }
+            Operation::RemountBind {
+                target,
+                flags,
+                data,
+            } => {
+                write!(f, "remount {} ({}", target.display(), list(flags))?;
+                if let Some(data) = data {
+                    write!(f, ", {}", data)?;
+                }
+                write!(f, ")")
+            }
+            Operation::MountPoint { path, like } => match like {
+                Some(like) => write!(
+                    f,
+                    "create {} as a mount point for {}",
+                    path.display(),
+                    like.display()
+                ),
+                None => write!(f, "create directory {}", path.display()),
+            },
+            Operation::Symlink { target, link } => {
+                write!(f, "symlink {} -> {}", link.display(), target.display())
+            }
+            Operation::ProtectIfExists { path } => {
+                write!(f, "protect {} read-only if it exists", path.display())
+            }
+            Operation::Chdir { path } => write!(f, "chdir {}", path.display()),
+            Operation::PivotRoot { new_root, put_old } => {
+                write!(f, "pivot_root {} {}", new_root.display(), put_old.display())
+            }
+            Operation::Umount { target, flags } => {
+                write!(f, "umount {} ({})", target.display(), list(flags))
+            }
+            Operation::SetUpPty => write!(f, "set up a pseudo-terminal"),
+            Operation::Callbacks { count } => write!(f, "run {} callback(s)", count),
+            Operation::MapFd { from, to } => write!(f, "map fd {} to {}", from, to),
+            Operation::CloseFds { keep } => {
+                write!(f, "close fds above 2")?;
+                if !keep.is_empty() {
+                    let keep: Vec<_> = keep.iter().map(|fd| fd.to_string()).collect();
+                    write!(f, " but {}", keep.join(","))?;
+                }
+                Ok(())
+            }
+            Operation::Landlock {
+                handled_fs,
+                handled_net,
+                paths,
+                ports,
+            } => {
+                write!(f, "landlock handling {}", list(handled_fs))?;
+                if !handled_net.is_empty() {
+                    write!(f, " {}", list(handled_net))?;
+                }
+                for (path, access) in paths {
+                    write!(f, ", allow {} on {}", list(access), path.display())?;
+                }
+                for (port, access) in ports {
+                    write!(f, ", allow {} on port {}", list(access), port)?;
+                }
+                Ok(())
+            }
+            Operation::Setrlimit {
+                resource,
+                soft,
+                hard,
+            } => write!(f, "setrlimit {} soft {} hard {}", resource, soft, hard),
+            Operation::Umask { mask } => write!(f, "umask {:04o}", mask),
+            Operation::SetUser { uid, gid } => write!(f, "set uid {} gid {}", uid, gid),
+            Operation::Exec { argv, env } => {
+                write!(f, "execute {:?} with environment {:?}", argv, env)
+            }
+        }
+    }
+}
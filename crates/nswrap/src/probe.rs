@@ -0,0 +1,509 @@
+//! Check which features the running kernel offers to an unprivileged
+//! user, and why the missing ones are missing.
+//!
+//! Each check reads the sysctls that gate a feature and, where the
+//! answer depends on more than those, tries the feature in a short-lived
+//! child process. The report serializes to JSON, and its `Display` is
+//! the table `petbox check` prints.
+
+use crate::{cgroup::Cgroup, config::NamespaceType, landlock};
+use getset::Getters;
+use nix::errno::Errno;
+use serde::Serialize;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Whether a feature is available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Available,
+    Unavailable,
+    /// The feature could not be checked, as one it depends on is missing.
+    Unknown,
+}
+
+/// A checked feature.
+#[derive(Getters, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub")]
+pub struct Feature {
+    name: &'static str,
+    status: Status,
+    /// What the check found, like the value of a sysctl or an errno.
+    detail: String,
+    /// How to make the feature available, if it is not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'static str>,
+}
+
+/// Features of the running kernel, from `probe`.
+#[derive(Getters, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[getset(get = "pub")]
+pub struct Report {
+    /// Release of the kernel, like `6.8.0-45-generic`.
+    kernel: String,
+    features: Vec<Feature>,
+}
+
+const USER_NAMESPACES: &str = "user namespaces";
+
+impl Report {
+    /// Whether `Wrap` can create containers at all, which takes a user
+    /// namespace unless running as root.
+    pub fn is_usable(&self) -> bool {
+        nix::unistd::geteuid().is_root()
+            || self
+                .feature(USER_NAMESPACES)
+                .is_some_and(|f| f.status == Status::Available)
+    }
+
+    /// The feature called `name`.
+    pub fn feature(&self, name: &str) -> Option<&Feature> {
+        self.features.iter().find(|f| f.name == name)
+    }
+}
+
+/// Check the features nswrap depends on.
+///
+/// This forks a few processes, which exit right after their check.
+pub fn probe() -> Report {
+    let userns = user_namespaces();
+    let can_unshare = userns.status == Status::Available;
+    let mut features: Vec<Feature> = unprivileged_userns_clone().into_iter().collect();
+    features.extend([
+        max_user_namespaces(),
+        apparmor_restrict_unprivileged_userns(can_unshare),
+        userns,
+        overlayfs(can_unshare),
+        time_namespaces(can_unshare),
+        clone3(),
+        landlock(),
+        cgroup_delegation(),
+    ]);
+    Report {
+        kernel: nix::sys::utsname::uname()
+            .map(|u| u.release().to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        features,
+    }
+}
+
+/// Whether a child can unshare a namespace of type `typ`, for options
+/// like `bwrap --unshare-user-try` which skip namespaces the kernel
+/// refuses.
+///
+/// Unless running as root, the namespace is created together with a user
+/// namespace, as `Wrap` does.
+pub fn can_unshare(typ: NamespaceType) -> bool {
+    let mut flags = typ.clone_flag();
+    if !nix::unistd::geteuid().is_root() {
+        flags |= libc::CLONE_NEWUSER;
+    }
+    in_child(flags, || 0).is_ok()
+}
+
+impl Feature {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn available(name: &'static str, detail: impl Into<String>) -> Self {
+        Self::new(name, Status::Available, detail)
+    }
+
+    /// Unavailable, fixed by following `hint`.
+    fn unavailable(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            hint: Some(hint),
+            ..Self::new(name, Status::Unavailable, detail)
+        }
+    }
+}
+
+/// Value of the sysctl at `/proc/sys/{key}`, `None` if the kernel has not
+/// got it.
+fn sysctl(key: &str) -> Option<i64> {
+    std::fs::read_to_string(Path::new("/proc/sys").join(key))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Debian and older Ubuntu kernels only, `None` elsewhere.
+fn unprivileged_userns_clone() -> Option<Feature> {
+    const NAME: &str = "unprivileged_userns_clone";
+    Some(match sysctl("kernel/unprivileged_userns_clone")? {
+        0 => Feature::unavailable(
+            NAME,
+            "kernel.unprivileged_userns_clone = 0",
+            "sysctl -w kernel.unprivileged_userns_clone=1",
+        ),
+        v => Feature::available(NAME, format!("kernel.unprivileged_userns_clone = {}", v)),
+    })
+}
+
+fn max_user_namespaces() -> Feature {
+    const NAME: &str = "max_user_namespaces";
+    match sysctl("user/max_user_namespaces") {
+        None => Feature::new(NAME, Status::Unknown, "user.max_user_namespaces is absent"),
+        Some(0) => Feature::unavailable(
+            NAME,
+            "user.max_user_namespaces = 0",
+            "sysctl -w user.max_user_namespaces=15000",
+        ),
+        Some(v) => Feature::available(NAME, format!("user.max_user_namespaces = {}", v)),
+    }
+}
+
+/// Ubuntu 23.10 and later, where user namespaces lack capabilities
+/// unless an AppArmor profile allows them, so `can_unshare` tells whether
+/// one does.
+fn apparmor_restrict_unprivileged_userns(can_unshare: bool) -> Feature {
+    const NAME: &str = "apparmor_restrict_unprivileged_userns";
+    match sysctl("kernel/apparmor_restrict_unprivileged_userns") {
+        None => Feature::available(NAME, "absent"),
+        Some(0) => Feature::available(NAME, "kernel.apparmor_restrict_unprivileged_userns = 0"),
+        Some(v) if can_unshare => Feature::available(
+            NAME,
+            format!(
+                "kernel.apparmor_restrict_unprivileged_userns = {}, allowed by a profile",
+                v
+            ),
+        ),
+        Some(v) => Feature::unavailable(
+            NAME,
+            format!("kernel.apparmor_restrict_unprivileged_userns = {}", v),
+            "give petbox an AppArmor profile with the `userns,` rule, \
+             or sysctl -w kernel.apparmor_restrict_unprivileged_userns=0",
+        ),
+    }
+}
+
+/// Unshare a mount namespace inside a new user namespace, which fails
+/// when AppArmor denies the user namespace its capabilities.
+fn user_namespaces() -> Feature {
+    const HINT: &str = "see the sysctls above, or run as root";
+    let ret = in_child(libc::CLONE_NEWUSER, || unsafe {
+        libc::unshare(libc::CLONE_NEWNS)
+    });
+    match ret {
+        Ok(()) => Feature::available(USER_NAMESPACES, "clone(2) with CLONE_NEWUSER works"),
+        Err(Step::Clone(e)) => {
+            Feature::unavailable(USER_NAMESPACES, format!("clone(2) failed: {}", e), HINT)
+        }
+        Err(Step::Child(e)) => Feature::unavailable(
+            USER_NAMESPACES,
+            format!(
+                "a new user namespace cannot unshare(2) a mount namespace: {}",
+                e
+            ),
+            HINT,
+        ),
+    }
+}
+
+/// Mount an overlay in a new user and mount namespace, supported since
+/// Linux 5.11.
+fn overlayfs(can_unshare: bool) -> Feature {
+    const NAME: &str = "overlayfs in user namespaces";
+    const HINT: &str = "upgrade to Linux 5.11 or later, or use fuse-overlayfs";
+    if !can_unshare && !nix::unistd::geteuid().is_root() {
+        return Feature::new(NAME, Status::Unknown, "needs user namespaces");
+    }
+    let filesystems = std::fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    if !filesystems.lines().any(|l| l.ends_with("\toverlay")) {
+        return Feature::unavailable(
+            NAME,
+            "overlay is not in /proc/filesystems",
+            "modprobe overlay",
+        );
+    }
+    let dir = std::env::temp_dir().join(format!("nswrap-probe-{}", std::process::id()));
+    let ret = try_overlay(&dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    match ret {
+        Ok(()) => Feature::available(NAME, "mounting an overlay works"),
+        Err(Step::Clone(e)) => {
+            Feature::new(NAME, Status::Unknown, format!("clone(2) failed: {}", e))
+        }
+        Err(Step::Child(e)) => {
+            Feature::unavailable(NAME, format!("mounting an overlay failed: {}", e), HINT)
+        }
+    }
+}
+
+fn try_overlay(dir: &Path) -> Result<(), Step> {
+    for sub in ["lower", "upper", "work", "merged"] {
+        std::fs::create_dir_all(dir.join(sub))
+            .map_err(|e| Step::Clone(Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO))))?;
+    }
+    let cstring = |bytes: Vec<u8>| CString::new(bytes).unwrap();
+    let dir = dir.as_os_str().as_bytes();
+    let merged = cstring([dir, b"/merged"].concat());
+    let data = cstring(
+        [
+            b"lowerdir=".as_slice(),
+            dir,
+            b"/lower,upperdir=",
+            dir,
+            b"/upper,workdir=",
+            dir,
+            b"/work",
+        ]
+        .concat(),
+    );
+    let uid_map = format!("0 {} 1", nix::unistd::geteuid());
+    let gid_map = format!("0 {} 1", nix::unistd::getegid());
+    in_child(libc::CLONE_NEWUSER | libc::CLONE_NEWNS, || unsafe {
+        // Files of the upper layer must be owned by a mapped user
+        let ret = write_proc(c"/proc/self/uid_map", uid_map.as_bytes());
+        if ret != 0 {
+            return ret;
+        }
+        let ret = write_proc(c"/proc/self/setgroups", b"deny");
+        if ret != 0 {
+            return ret;
+        }
+        let ret = write_proc(c"/proc/self/gid_map", gid_map.as_bytes());
+        if ret != 0 {
+            return ret;
+        }
+        libc::mount(
+            c"overlay".as_ptr(),
+            merged.as_ptr(),
+            c"overlay".as_ptr(),
+            0,
+            data.as_ptr().cast(),
+        )
+    })
+}
+
+/// Unshare a time namespace, supported since Linux 5.6.
+fn time_namespaces(can_unshare: bool) -> Feature {
+    const NAME: &str = "time namespaces";
+    const HINT: &str = "upgrade to Linux 5.6 or later, built with CONFIG_TIME_NS";
+    if !Path::new("/proc/self/ns/time").exists() {
+        return Feature::unavailable(NAME, "/proc/self/ns/time is absent", HINT);
+    }
+    let flags = match nix::unistd::geteuid().is_root() {
+        true => 0,
+        false if can_unshare => libc::CLONE_NEWUSER,
+        false => return Feature::new(NAME, Status::Unknown, "needs user namespaces"),
+    };
+    match in_child(flags, || unsafe { libc::unshare(libc::CLONE_NEWTIME) }) {
+        Ok(()) => Feature::available(NAME, "unshare(2) with CLONE_NEWTIME works"),
+        Err(Step::Clone(e)) => {
+            Feature::new(NAME, Status::Unknown, format!("clone(2) failed: {}", e))
+        }
+        Err(Step::Child(e)) => {
+            Feature::unavailable(NAME, format!("unshare(2) failed: {}", e), HINT)
+        }
+    }
+}
+
+/// Call `clone3(2)` with a size too small for its arguments, which fails
+/// with `EINVAL` if the kernel knows it.
+fn clone3() -> Feature {
+    const NAME: &str = "clone3";
+    let ret = unsafe { libc::syscall(libc::SYS_clone3, std::ptr::null::<u8>(), 0usize) };
+    match Errno::result(ret) {
+        Err(Errno::ENOSYS) => Feature::unavailable(
+            NAME,
+            "clone3(2) is not implemented",
+            "upgrade to Linux 5.3 or later; nswrap falls back to clone(2)",
+        ),
+        Err(Errno::EPERM) => Feature::unavailable(
+            NAME,
+            "clone3(2) is denied, likely by a seccomp filter",
+            "allow clone3 in the seccomp profile of the outer container",
+        ),
+        _ => Feature::available(NAME, "clone3(2) is implemented"),
+    }
+}
+
+fn landlock() -> Feature {
+    const NAME: &str = "landlock";
+    match landlock::abi_version() {
+        Some(abi) if abi < 4 => Feature::available(
+            NAME,
+            format!("ABI {}, without network rules (ABI 4, Linux 6.7)", abi),
+        ),
+        Some(abi) => Feature::available(NAME, format!("ABI {}", abi)),
+        None => Feature::unavailable(
+            NAME,
+            "Landlock is not built in or not enabled",
+            "boot with `lsm=landlock,...` on Linux 5.13 or later",
+        ),
+    }
+}
+
+/// Whether the cgroup of this process is writable, so that `Wrap::cgroup`
+/// can create cgroups under it.
+fn cgroup_delegation() -> Feature {
+    const NAME: &str = "cgroup delegation";
+    const HINT: &str = "run under `systemd-run --user --scope -p Delegate=yes`";
+    let cgroup = match Cgroup::current() {
+        Ok(cgroup) => cgroup,
+        Err(e) => {
+            return Feature::unavailable(NAME, e.to_string(), "mount cgroup v2 on /sys/fs/cgroup")
+        }
+    };
+    let path = cgroup.path();
+    let writable =
+        |name: &str| nix::unistd::access(&path.join(name), nix::unistd::AccessFlags::W_OK).is_ok();
+    if !writable("") || !writable("cgroup.procs") {
+        return Feature::unavailable(NAME, format!("{} is not writable", path.display()), HINT);
+    }
+    let controllers = std::fs::read_to_string(path.join("cgroup.controllers")).unwrap_or_default();
+    let controllers = controllers.trim();
+    Feature::available(
+        NAME,
+        format!(
+            "{} is writable, controllers: {}",
+            path.display(),
+            if controllers.is_empty() {
+                "none"
+            } else {
+                controllers
+            }
+        ),
+    )
+}
+
+/// Where `in_child` failed.
+enum Step {
+    Clone(Errno),
+    Child(Errno),
+}
+
+/// Run `f` in a child cloned with `flags`, where it fails by returning
+/// -1 and setting errno.
+///
+/// Like `sys::Kernel::fork`, this is a raw `clone(2)`,
+/// so `f` must not allocate or take locks.
+fn in_child(flags: libc::c_int, f: impl Fn() -> libc::c_int) -> Result<(), Step> {
+    use nix::sys::wait::{waitpid, WaitStatus};
+
+    let flags = flags as libc::c_long | libc::SIGCHLD as libc::c_long;
+    let ret = unsafe { libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0) };
+    match Errno::result(ret).map_err(Step::Clone)? {
+        0 => {
+            let code = match f() {
+                -1 => Errno::last() as i32,
+                _ => 0,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        child => loop {
+            match waitpid(nix::unistd::Pid::from_raw(child as libc::pid_t), None) {
+                Ok(WaitStatus::Exited(_, 0)) => return Ok(()),
+                Ok(WaitStatus::Exited(_, code)) => return Err(Step::Child(Errno::from_i32(code))),
+                Ok(WaitStatus::Signaled(..)) => return Err(Step::Child(Errno::EINTR)),
+                Err(Errno::EINTR) | Ok(_) => (),
+                Err(e) => return Err(Step::Clone(e)),
+            }
+        },
+    }
+}
+
+/// Write `data` to the file at `path`, returning -1 with errno set on
+/// failure.
+unsafe fn write_proc(path: &std::ffi::CStr, data: &[u8]) -> libc::c_int {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    if fd == -1 {
+        return -1;
+    }
+    let written = libc::write(fd, data.as_ptr().cast(), data.len());
+    libc::close(fd);
+    match written {
+        -1 => -1,
+        _ => 0,
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Available => "ok",
+            Status::Unavailable => "missing",
+            Status::Unknown => "unknown",
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Linux {}", self.kernel)?;
+        let width = self
+            .features
+            .iter()
+            .map(|f| f.name.len())
+            .max()
+            .unwrap_or(0);
+        for feature in &self.features {
+            writeln!(
+                f,
+                "{:<7} {:<width$}  {}",
+                feature.status.to_string(),
+                feature.name,
+                feature.detail,
+            )?;
+            if let Some(hint) = feature.hint {
+                writeln!(f, "{:<7} {:<width$}  hint: {}", "", "", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let report = probe();
+        assert!(!report.kernel().is_empty());
+        assert_eq!(
+            report.feature("landlock").unwrap().status() == &Status::Available,
+            landlock::abi_version().is_some()
+        );
+        // This test suite creates user namespaces elsewhere
+        assert!(report.is_usable());
+        assert_eq!(
+            report.feature(USER_NAMESPACES).unwrap().status(),
+            &Status::Available
+        );
+        for feature in report.features() {
+            if feature.status() == &Status::Unavailable {
+                assert!(feature.hint().is_some(), "{:?}", feature);
+            }
+        }
+        let text = report.to_string();
+        assert!(text.starts_with("Linux "));
+        assert_eq!(
+            text.lines().filter(|l| !l.contains("hint:")).count(),
+            1 + report.features().len()
+        );
+    }
+
+    #[test]
+    fn child_errno() {
+        let ret = in_child(0, || unsafe {
+            *libc::__errno_location() = libc::EACCES;
+            -1
+        });
+        assert!(matches!(ret, Err(Step::Child(Errno::EACCES))));
+        assert!(in_child(0, || 0).is_ok());
+    }
+}
@@ -0,0 +1,437 @@
+//! Setup of the child, prepared in the parent.
+//!
+//! After `clone(2)` from a multithreaded parent, the child may only call
+//! async-signal-safe functions until it executes the program: a lock held
+//! by another thread of the parent, like the one of `malloc(3)`, is never
+//! released in the child. So every path and file content the child needs
+//! is built here beforehand, and running a `Step` only makes system calls.
+
+use crate::plan::{self, Operation};
+use crate::sys::Syscalls;
+use crate::trace::Stage;
+use crate::{config, landlock};
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
+use std::ffi::{CStr, CString, OsStr};
+use std::os::fd::RawFd;
+use std::os::unix::prelude::OsStrExt;
+use std::path::PathBuf;
+
+/// Everything the child does that needs memory, prepared by the parent.
+#[derive(Default)]
+pub(crate) struct Plan {
+    /// Writes of the id mappings of the child.
+    pub(crate) id_maps: Vec<Step>,
+    /// Propagation of the root and mounts, or the whole sandbox root.
+    pub(crate) mounts: Vec<Step>,
+    pub(crate) join: Option<Join>,
+    /// Fds above stdio kept open on `execve(2)`, in ascending order.
+    pub(crate) keep_fds: Vec<RawFd>,
+    /// First fd above every fd of `fd_maps` and the preserved ones, where
+    /// `fd_maps` are copied to first.
+    pub(crate) fds_above: RawFd,
+    pub(crate) landlock: Option<landlock::Ruleset>,
+    /// Paths of the createContainer hooks, to report their failure.
+    pub(crate) create_hooks: Vec<CString>,
+    /// Paths of the startContainer hooks, to report their failure.
+    pub(crate) start_hooks: Vec<CString>,
+    /// Stages the child runs, in order, which `Wrap::plan` describes.
+    pub(crate) stages: Vec<Stage>,
+}
+
+/// Paths to join the namespaces of a process with.
+pub(crate) struct Join {
+    pub(crate) target: config::JoinTarget,
+    /// `/proc/<pid>` of the target.
+    pub(crate) proc: CString,
+    /// Namespaces to join in `config::NamespaceType::JOIN_ORDER`, with
+    /// their paths under `/proc/self/ns` and `/proc/<pid>/ns`.
+    pub(crate) namespaces: Vec<(config::NamespaceType, CString, CString)>,
+    pub(crate) root: CString,
+    pub(crate) cwd: CString,
+}
+
+/// An operation of the child on the file system.
+pub(crate) enum Step {
+    /// `mount(2)`.
+    Mount {
+        source: Option<CString>,
+        target: CString,
+        fstype: Option<CString>,
+        flags: MsFlags,
+        data: Option<CString>,
+    },
+    /// Remount the bind mount at `target` with `flags`.
+    ///
+    /// Flags like `MS_NOSUID` of a mount that comes from a more privileged
+    /// mount namespace are locked, so they are kept on remount.
+    RemountBind {
+        target: CString,
+        flags: MsFlags,
+        data: Option<CString>,
+    },
+    /// Create the directory `path` and its parents, or a regular file if
+    /// `like` exists and isn't a directory.
+    MountPoint {
+        path: CString,
+        like: Option<CString>,
+    },
+    /// Create a symbolic link at `link`, unless the same one exists.
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    /// Cover `path` with a read-only bind mount of itself, if it exists.
+    ProtectIfExists(CString),
+    /// Write `data` to the existing file `path` at once.
+    WriteFile {
+        path: CString,
+        data: Vec<u8>,
+    },
+    Chdir(CString),
+    PivotRoot {
+        new_root: CString,
+        put_old: CString,
+    },
+    Umount {
+        target: CString,
+        flags: MntFlags,
+    },
+}
+
+impl Step {
+    /// Change the propagation type of the mount at `target`.
+    pub(crate) fn propagation(target: CString, flags: MsFlags) -> Self {
+        Step::Mount {
+            source: None,
+            target,
+            fstype: None,
+            flags,
+            data: None,
+        }
+    }
+
+    /// Describe the step for `Wrap::plan`.
+    pub(crate) fn operation(&self) -> Operation {
+        let data = |data: &Option<CString>| data.as_deref().map(string);
+        match self {
+            Step::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data: d,
+            } => Operation::Mount {
+                source: source.as_deref().map(path),
+                target: path(target),
+                fstype: fstype.as_deref().map(string),
+                flags: plan::ms_flag_names(*flags),
+                data: data(d),
+            },
+            Step::RemountBind {
+                target,
+                flags,
+                data: d,
+            } => Operation::RemountBind {
+                target: path(target),
+                flags: plan::ms_flag_names(*flags),
+                data: data(d),
+            },
+            Step::MountPoint { path: p, like } => Operation::MountPoint {
+                path: path(p),
+                like: like.as_deref().map(path),
+            },
+            Step::Symlink { target, link } => Operation::Symlink {
+                target: path(target),
+                link: path(link),
+            },
+            Step::ProtectIfExists(p) => Operation::ProtectIfExists { path: path(p) },
+            Step::WriteFile { path: p, data } => Operation::WriteFile {
+                path: path(p),
+                content: String::from_utf8_lossy(data).into_owned(),
+            },
+            Step::Chdir(p) => Operation::Chdir { path: path(p) },
+            Step::PivotRoot { new_root, put_old } => Operation::PivotRoot {
+                new_root: path(new_root),
+                put_old: path(put_old),
+            },
+            Step::Umount { target, flags } => Operation::Umount {
+                target: path(target),
+                flags: plan::mnt_flag_names(*flags),
+            },
+        }
+    }
+
+    /// Run the step in the child.
+    pub(crate) fn run(&self, sys: &dyn Syscalls) -> Result<(), Failure<'_>> {
+        match self {
+            Step::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data,
+            } => sys
+                .mount(
+                    source.as_deref(),
+                    target,
+                    fstype.as_deref(),
+                    *flags,
+                    data.as_deref(),
+                )
+                .context_at("mount", target),
+            Step::RemountBind {
+                target,
+                flags,
+                data,
+            } => remount_bind(sys, target, *flags, data.as_deref()).context_at("remount", target),
+            Step::MountPoint { path, like } => {
+                let is_file = like
+                    .as_deref()
+                    .is_some_and(|like| sys.file_type(like).is_ok_and(|typ| typ != libc::S_IFDIR));
+                match is_file {
+                    true => mkdir_all(sys, path, false).and_then(|_| sys.create(path)),
+                    false => mkdir_all(sys, path, true),
+                }
+                .context_at("create", path)
+            }
+            Step::Symlink { target, link } => {
+                create_symlink(sys, target, link).context_at("create symlink", link)
+            }
+            Step::ProtectIfExists(path) => {
+                if sys.file_type(path).is_err() {
+                    return Ok(());
+                }
+                sys.mount(Some(path), path, None, MsFlags::MS_BIND, None)
+                    .and_then(|_| remount_bind(sys, path, MsFlags::MS_RDONLY, None))
+                    .context_at("protect", path)
+            }
+            Step::WriteFile { path, data } => sys.write_file(path, data).context_at("write", path),
+            Step::Chdir(path) => sys.chdir(path).context_at("chdir", path),
+            Step::PivotRoot { new_root, put_old } => sys
+                .pivot_root(new_root, put_old)
+                .context_at("pivot_root", new_root),
+            Step::Umount { target, flags } => {
+                sys.umount2(target, *flags).context_at("umount", target)
+            }
+        }
+    }
+}
+
+/// Convert a path or string for the child.
+pub(crate) fn cstr<S: AsRef<OsStr> + ?Sized>(s: &S) -> Result<CString, std::io::Error> {
+    Ok(CString::new(s.as_ref().as_bytes())?)
+}
+
+/// Convert a string of the child back for `Wrap::plan`.
+pub(crate) fn path(s: &CStr) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(s.to_bytes()))
+}
+
+/// Like `path`, for strings other than paths.
+pub(crate) fn string(s: &CStr) -> String {
+    s.to_string_lossy().into_owned()
+}
+
+fn remount_bind(
+    sys: &dyn Syscalls,
+    target: &CStr,
+    flags: MsFlags,
+    data: Option<&CStr>,
+) -> nix::Result<()> {
+    use nix::sys::statvfs::FsFlags;
+
+    let locked = [
+        (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+    let current = sys.mount_flags(target)?;
+    let mut flags = flags;
+    for (st, ms) in locked {
+        if current.contains(st) {
+            flags |= ms;
+        }
+    }
+    sys.mount(
+        None,
+        target,
+        None,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | flags,
+        data,
+    )
+}
+
+/// Create the directories of `path`, including the last component if
+/// `last` is set, on a copy of it in a buffer on the stack.
+fn mkdir_all(sys: &dyn Syscalls, path: &CStr, last: bool) -> nix::Result<()> {
+    let path = path.to_bytes();
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    if path.len() >= buf.len() {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    buf[..path.len()].copy_from_slice(path);
+    for end in 1..=path.len() {
+        if end < path.len() && path[end] != b'/' || end == path.len() && !last {
+            continue;
+        }
+        buf[end] = 0;
+        let dir = CStr::from_bytes_with_nul(&buf[..=end]).map_err(|_| Errno::EINVAL)?;
+        match sys.mkdir(dir, 0o755) {
+            Ok(()) | Err(Errno::EEXIST) => (),
+            Err(e) => return Err(e),
+        }
+        if end < path.len() {
+            buf[end] = b'/';
+        }
+    }
+    Ok(())
+}
+
+fn create_symlink(sys: &dyn Syscalls, target: &CStr, link: &CStr) -> nix::Result<()> {
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    if let Ok(len) = sys.readlink(link, &mut buf) {
+        if &buf[..len] == target.to_bytes() {
+            return Ok(());
+        }
+    }
+    mkdir_all(sys, link, false)?;
+    sys.symlink(target, link)
+}
+
+/// A failed operation of the child, reported without allocating.
+pub(crate) struct Failure<'a> {
+    pub(crate) what: &'static str,
+    pub(crate) path: Option<&'a CStr>,
+    pub(crate) errno: Errno,
+}
+
+impl Failure<'_> {
+    /// Write `nswrap: <what> <path>: <error>` to stderr.
+    pub(crate) fn report(&self) {
+        let path = self.path.map(CStr::to_bytes).unwrap_or_default();
+        let sep: &[u8] = if path.is_empty() { b"" } else { b" " };
+        for part in [
+            b"nswrap: ",
+            self.what.as_bytes(),
+            sep,
+            path,
+            b": ",
+            self.errno.desc().as_bytes(),
+            b"\n",
+        ] {
+            let _ = nix::unistd::write(libc::STDERR_FILENO, part);
+        }
+    }
+}
+
+/// Attach what failed to an error of the child.
+pub(crate) trait Context<T> {
+    fn context(self, what: &'static str) -> Result<T, Failure<'static>>;
+    fn context_at<'a>(self, what: &'static str, path: &'a CStr) -> Result<T, Failure<'a>>;
+}
+
+impl<T> Context<T> for nix::Result<T> {
+    fn context(self, what: &'static str) -> Result<T, Failure<'static>> {
+        self.map_err(|errno| Failure {
+            what,
+            path: None,
+            errno,
+        })
+    }
+
+    fn context_at<'a>(self, what: &'static str, path: &'a CStr) -> Result<T, Failure<'a>> {
+        self.map_err(|errno| Failure {
+            what,
+            path: Some(path),
+            errno,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::{Kernel, Recorder};
+
+    #[test]
+    fn create_paths() {
+        const DIR: &str = "/tmp/nswrap.test.setup";
+        let _ = std::fs::remove_dir_all(DIR);
+        let steps = [
+            Step::MountPoint {
+                path: cstr("/tmp/nswrap.test.setup/a//b/").unwrap(),
+                like: None,
+            },
+            Step::MountPoint {
+                path: cstr("/tmp/nswrap.test.setup/c/file").unwrap(),
+                like: Some(cstr("/dev/null").unwrap()),
+            },
+            Step::Symlink {
+                target: cstr("a/b").unwrap(),
+                link: cstr("/tmp/nswrap.test.setup/d/link").unwrap(),
+            },
+            Step::WriteFile {
+                path: cstr("/tmp/nswrap.test.setup/c/file").unwrap(),
+                data: b"data".to_vec(),
+            },
+        ];
+        for step in &steps {
+            assert!(step.run(&Kernel).is_ok());
+        }
+        // Running them again finds them in place
+        assert!(steps.iter().all(|step| step.run(&Kernel).is_ok()));
+        assert!(std::path::Path::new(DIR).join("a/b").is_dir());
+        assert_eq!(std::fs::read(format!("{}/c/file", DIR)).unwrap(), b"data");
+        assert_eq!(
+            std::fs::read_link(format!("{}/d/link", DIR)).unwrap(),
+            std::path::Path::new("a/b")
+        );
+        std::fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[test]
+    fn recorded_steps() {
+        let sys = Recorder::default()
+            .with_file("/dev/null", libc::S_IFCHR)
+            .with_file("/proc/sys", libc::S_IFDIR);
+        let steps = [
+            Step::MountPoint {
+                path: cstr("/new/dev/null").unwrap(),
+                like: Some(cstr("/dev/null").unwrap()),
+            },
+            Step::ProtectIfExists(cstr("/proc/sys").unwrap()),
+            Step::ProtectIfExists(cstr("/proc/bus").unwrap()),
+            Step::Symlink {
+                target: cstr("pts/ptmx").unwrap(),
+                link: cstr("/new/dev/ptmx").unwrap(),
+            },
+        ];
+        for step in &steps {
+            assert!(step.run(&sys).is_ok());
+        }
+        assert_eq!(
+            sys.calls(),
+            [
+                "mkdir /new",
+                "mkdir /new/dev",
+                "create /new/dev/null",
+                "mount /proc/sys /proc/sys - MS_BIND -",
+                "mount - /proc/sys - MS_RDONLY|MS_REMOUNT|MS_BIND -",
+                "mkdir /new",
+                "mkdir /new/dev",
+                "symlink pts/ptmx /new/dev/ptmx",
+            ]
+        );
+
+        let sys = Recorder::default().failing("mkdir", Some("/new/dev"), Errno::EROFS);
+        let e = steps[0].run(&sys).err().unwrap();
+        assert_eq!((e.what, e.errno), ("create", Errno::EROFS));
+        assert_eq!(e.path.unwrap().to_bytes(), b"/new/dev/null");
+        assert_eq!(sys.calls(), ["mkdir /new", "mkdir /new/dev"]);
+    }
+}
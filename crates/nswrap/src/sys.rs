@@ -0,0 +1,523 @@
+//! System calls of `WrapCore`, behind a trait.
+//!
+//! `Kernel` makes the calls. For tests, `Recorder` records them instead,
+//! so the setup logic can be checked where unprivileged namespaces are
+//! not allowed. Implementations used in the child must not allocate, see
+//! `setup`.
+
+use crate::{error::Error, util};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::mount::{MntFlags, MsFlags};
+use nix::sched::CloneFlags;
+use nix::sys::statvfs::FsFlags;
+use std::ffi::{CStr, OsStr};
+use std::os::fd::RawFd;
+
+/// System calls the setup of the child is made of.
+///
+/// Paths are the ones of the child, and are not checked by the caller.
+pub(crate) trait Syscalls {
+    /// `clone(2)` running `cb` on `stack`, see `util::clone`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `util::clone`.
+    unsafe fn clone(
+        &self,
+        cb: util::CloneCb,
+        stack: &mut [u8],
+        flags: util::CloneFlags,
+        signal: Option<libc::c_int>,
+    ) -> Result<u32, Error>;
+
+    /// `fork(2)` as a raw `clone(2)` with `SIGCHLD`, as `fork(3)` runs
+    /// the `pthread_atfork(3)` handlers, which take locks. Returns 0 in
+    /// the new process and its pid in the caller.
+    fn fork(&self) -> nix::Result<libc::pid_t>;
+
+    fn unshare(&self, flags: CloneFlags) -> nix::Result<()>;
+
+    /// `setns(2)` with `nstype`, which is a set of `CLONE_NEW*` flags
+    /// for a pidfd.
+    fn setns(&self, fd: RawFd, nstype: libc::c_int) -> nix::Result<()>;
+
+    fn pidfd_open(&self, pid: libc::pid_t) -> nix::Result<RawFd>;
+
+    fn mount(
+        &self,
+        source: Option<&CStr>,
+        target: &CStr,
+        fstype: Option<&CStr>,
+        flags: MsFlags,
+        data: Option<&CStr>,
+    ) -> nix::Result<()>;
+
+    fn umount2(&self, target: &CStr, flags: MntFlags) -> nix::Result<()>;
+
+    fn pivot_root(&self, new_root: &CStr, put_old: &CStr) -> nix::Result<()>;
+
+    fn chdir(&self, path: &CStr) -> nix::Result<()>;
+
+    fn fchdir(&self, fd: RawFd) -> nix::Result<()>;
+
+    fn chroot(&self, path: &CStr) -> nix::Result<()>;
+
+    /// `open(2)` of `path` with `flags` and `O_CLOEXEC`.
+    fn open(&self, path: &CStr, flags: OFlag) -> nix::Result<RawFd>;
+
+    fn mkdir(&self, path: &CStr, mode: libc::mode_t) -> nix::Result<()>;
+
+    /// Create the regular file `path` if it does not exist.
+    fn create(&self, path: &CStr) -> nix::Result<()>;
+
+    fn symlink(&self, target: &CStr, link: &CStr) -> nix::Result<()>;
+
+    /// `readlink(2)` into `buf`, returning the length of the target.
+    fn readlink(&self, link: &CStr, buf: &mut [u8]) -> nix::Result<usize>;
+
+    /// `S_IFMT` bits of the mode of `path`, from `stat(2)`.
+    fn file_type(&self, path: &CStr) -> nix::Result<libc::mode_t>;
+
+    /// Device and inode of `path`, from `stat(2)`.
+    fn file_id(&self, path: &CStr) -> nix::Result<(libc::dev_t, libc::ino_t)>;
+
+    /// Flags of the mount at `path`, from `statvfs(3)`.
+    fn mount_flags(&self, path: &CStr) -> nix::Result<FsFlags>;
+
+    /// Write `data` to the existing file `path` at once, like the files
+    /// of `proc(5)`.
+    fn write_file(&self, path: &CStr, data: &[u8]) -> nix::Result<()>;
+
+    fn sethostname(&self, name: &OsStr) -> nix::Result<()>;
+
+    fn dup2(&self, old: RawFd, new: RawFd) -> nix::Result<()>;
+
+    fn close(&self, fd: RawFd) -> nix::Result<()>;
+
+    /// Clear `FD_CLOEXEC` of `fd`, so it stays open on `execve(2)`.
+    fn inherit(&self, fd: RawFd) -> nix::Result<()>;
+
+    /// Close the fds from `first` to `last`.
+    fn close_range(&self, first: u32, last: u32) -> nix::Result<()>;
+
+    /// `execvpe(3)` with null terminated `argv` and `envp`, returning the
+    /// error if it returns at all.
+    fn execvpe(
+        &self,
+        bin: &CStr,
+        argv: &[*const libc::c_char],
+        envp: &[*const libc::c_char],
+    ) -> Errno;
+}
+
+/// The running kernel.
+pub(crate) struct Kernel;
+
+impl Syscalls for Kernel {
+    unsafe fn clone(
+        &self,
+        cb: util::CloneCb,
+        stack: &mut [u8],
+        flags: util::CloneFlags,
+        signal: Option<libc::c_int>,
+    ) -> Result<u32, Error> {
+        util::clone(cb, stack, flags, signal)
+    }
+
+    fn fork(&self) -> nix::Result<libc::pid_t> {
+        let ret = unsafe { libc::syscall(libc::SYS_clone, libc::SIGCHLD, 0, 0, 0, 0) };
+        Errno::result(ret).map(|pid| pid as libc::pid_t)
+    }
+
+    fn unshare(&self, flags: CloneFlags) -> nix::Result<()> {
+        nix::sched::unshare(flags)
+    }
+
+    fn setns(&self, fd: RawFd, nstype: libc::c_int) -> nix::Result<()> {
+        Errno::result(unsafe { libc::setns(fd, nstype) }).map(drop)
+    }
+
+    fn pidfd_open(&self, pid: libc::pid_t) -> nix::Result<RawFd> {
+        let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        Errno::result(ret).map(|fd| fd as RawFd)
+    }
+
+    fn mount(
+        &self,
+        source: Option<&CStr>,
+        target: &CStr,
+        fstype: Option<&CStr>,
+        flags: MsFlags,
+        data: Option<&CStr>,
+    ) -> nix::Result<()> {
+        nix::mount::mount(source, target, fstype, flags, data)
+    }
+
+    fn umount2(&self, target: &CStr, flags: MntFlags) -> nix::Result<()> {
+        nix::mount::umount2(target, flags)
+    }
+
+    fn pivot_root(&self, new_root: &CStr, put_old: &CStr) -> nix::Result<()> {
+        nix::unistd::pivot_root(new_root, put_old)
+    }
+
+    fn chdir(&self, path: &CStr) -> nix::Result<()> {
+        nix::unistd::chdir(path)
+    }
+
+    fn fchdir(&self, fd: RawFd) -> nix::Result<()> {
+        nix::unistd::fchdir(fd)
+    }
+
+    fn chroot(&self, path: &CStr) -> nix::Result<()> {
+        nix::unistd::chroot(path)
+    }
+
+    fn open(&self, path: &CStr, flags: OFlag) -> nix::Result<RawFd> {
+        nix::fcntl::open(
+            path,
+            flags | OFlag::O_CLOEXEC,
+            nix::sys::stat::Mode::empty(),
+        )
+    }
+
+    fn mkdir(&self, path: &CStr, mode: libc::mode_t) -> nix::Result<()> {
+        Errno::result(unsafe { libc::mkdir(path.as_ptr(), mode) }).map(drop)
+    }
+
+    fn create(&self, path: &CStr) -> nix::Result<()> {
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                0o644,
+            )
+        };
+        Errno::result(fd)?;
+        nix::unistd::close(fd)
+    }
+
+    fn symlink(&self, target: &CStr, link: &CStr) -> nix::Result<()> {
+        Errno::result(unsafe { libc::symlink(target.as_ptr(), link.as_ptr()) }).map(drop)
+    }
+
+    fn readlink(&self, link: &CStr, buf: &mut [u8]) -> nix::Result<usize> {
+        let len = unsafe { libc::readlink(link.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        Errno::result(len).map(|len| len as usize)
+    }
+
+    fn file_type(&self, path: &CStr) -> nix::Result<libc::mode_t> {
+        nix::sys::stat::stat(path).map(|st| st.st_mode & libc::S_IFMT)
+    }
+
+    fn file_id(&self, path: &CStr) -> nix::Result<(libc::dev_t, libc::ino_t)> {
+        nix::sys::stat::stat(path).map(|st| (st.st_dev, st.st_ino))
+    }
+
+    fn mount_flags(&self, path: &CStr) -> nix::Result<FsFlags> {
+        nix::sys::statvfs::statvfs(path).map(|st| st.flags())
+    }
+
+    fn write_file(&self, path: &CStr, data: &[u8]) -> nix::Result<()> {
+        let fd =
+            Errno::result(unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) })?;
+        let written = nix::unistd::write(fd, data);
+        let _ = nix::unistd::close(fd);
+        written.map(drop)
+    }
+
+    fn sethostname(&self, name: &OsStr) -> nix::Result<()> {
+        nix::unistd::sethostname(name)
+    }
+
+    fn dup2(&self, old: RawFd, new: RawFd) -> nix::Result<()> {
+        nix::unistd::dup2(old, new).map(drop)
+    }
+
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        nix::unistd::close(fd)
+    }
+
+    fn inherit(&self, fd: RawFd) -> nix::Result<()> {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).map(drop)
+    }
+
+    /// `close_range(2)`, or closing one fd at a time up to the limit of
+    /// open fds on kernels older than 5.9.
+    fn close_range(&self, first: u32, last: u32) -> nix::Result<()> {
+        let ret = unsafe { libc::syscall(libc::SYS_close_range, first, last, 0) };
+        match Errno::result(ret) {
+            Ok(_) => Ok(()),
+            Err(Errno::ENOSYS) => {
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                Errno::result(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) })?;
+                let last = (last as libc::rlim_t).min(limit.rlim_cur.saturating_sub(1));
+                for fd in first as libc::rlim_t..=last {
+                    let _ = nix::unistd::close(fd as RawFd);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn execvpe(
+        &self,
+        bin: &CStr,
+        argv: &[*const libc::c_char],
+        envp: &[*const libc::c_char],
+    ) -> Errno {
+        unsafe { libc::execvpe(bin.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+        Errno::last()
+    }
+}
+
+/// A fake kernel recording the calls made to it, for tests.
+///
+/// Calls succeed unless they match `fail`, and queries about files are
+/// answered from `files`, every other path does not exist.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct Recorder {
+    calls: std::cell::RefCell<Vec<String>>,
+    files: Vec<(String, libc::mode_t)>,
+    fail: Option<(&'static str, Option<String>, Errno)>,
+}
+
+#[cfg(test)]
+impl Recorder {
+    /// Pid returned by `clone`, above the largest pid Linux allows.
+    pub(crate) const PID: u32 = 1 << 22;
+
+    /// Lowest fd returned by `open` and `pidfd_open`, above the ones of
+    /// the test process.
+    pub(crate) const FD: RawFd = 1000;
+
+    /// Pretend the file `path` of type `S_IFMT` bits `typ` exists.
+    pub(crate) fn with_file(mut self, path: &str, typ: libc::mode_t) -> Self {
+        self.files.push((path.into(), typ));
+        self
+    }
+
+    /// Fail calls named `call` with `errno`, only the ones whose first
+    /// argument is `arg` if it is set.
+    pub(crate) fn failing(mut self, call: &'static str, arg: Option<&str>, errno: Errno) -> Self {
+        self.fail = Some((call, arg.map(Into::into), errno));
+        self
+    }
+
+    /// Calls so far, like `mount tmpfs /tmp tmpfs MS_NOSUID|MS_NODEV mode=0755`.
+    pub(crate) fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn record(&self, call: &'static str, args: &[String]) -> nix::Result<()> {
+        let mut line = call.to_string();
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        self.calls.borrow_mut().push(line);
+        match &self.fail {
+            Some((name, arg, errno))
+                if *name == call && arg.as_ref().is_none_or(|arg| Some(arg) == args.first()) =>
+            {
+                Err(*errno)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fd returned by the last call, `FD` plus its index among the calls.
+    fn fd(&self) -> RawFd {
+        Self::FD + self.calls.borrow().len() as RawFd - 1
+    }
+
+    fn find(&self, path: &CStr) -> nix::Result<libc::mode_t> {
+        let path = path.to_string_lossy();
+        self.files
+            .iter()
+            .find(|(p, _)| *p == path)
+            .map(|(_, typ)| *typ)
+            .ok_or(Errno::ENOENT)
+    }
+}
+
+#[cfg(test)]
+fn arg(s: Option<&CStr>) -> String {
+    s.map_or("-".into(), |s| s.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+fn flags(names: Vec<String>) -> String {
+    match names.is_empty() {
+        true => "0".into(),
+        false => names.join("|"),
+    }
+}
+
+#[cfg(test)]
+impl Syscalls for Recorder {
+    unsafe fn clone(
+        &self,
+        _cb: util::CloneCb,
+        _stack: &mut [u8],
+        flags: util::CloneFlags,
+        _signal: Option<libc::c_int>,
+    ) -> Result<u32, Error> {
+        let names = crate::plan::clone_flag_names(flags);
+        self.record("clone", &[self::flags(names)])
+            .map_err(Error::CloneFailed)?;
+        Ok(Self::PID)
+    }
+
+    /// Continues as the new process.
+    fn fork(&self) -> nix::Result<libc::pid_t> {
+        self.record("fork", &[]).map(|()| 0)
+    }
+
+    fn unshare(&self, flags: CloneFlags) -> nix::Result<()> {
+        let flags = util::CloneFlags::from_bits_retain(flags.bits() as u32);
+        self.record(
+            "unshare",
+            &[self::flags(crate::plan::clone_flag_names(flags))],
+        )
+    }
+
+    fn setns(&self, fd: RawFd, nstype: libc::c_int) -> nix::Result<()> {
+        let flags = util::CloneFlags::from_bits_retain(nstype as u32);
+        let flags = self::flags(crate::plan::clone_flag_names(flags));
+        self.record("setns", &[fd.to_string(), flags])
+    }
+
+    fn pidfd_open(&self, pid: libc::pid_t) -> nix::Result<RawFd> {
+        self.record("pidfd_open", &[pid.to_string()])
+            .map(|()| self.fd())
+    }
+
+    fn mount(
+        &self,
+        source: Option<&CStr>,
+        target: &CStr,
+        fstype: Option<&CStr>,
+        flags: MsFlags,
+        data: Option<&CStr>,
+    ) -> nix::Result<()> {
+        let args = [
+            arg(source),
+            arg(Some(target)),
+            arg(fstype),
+            self::flags(crate::plan::ms_flag_names(flags)),
+            arg(data),
+        ];
+        self.record("mount", &args)
+    }
+
+    fn umount2(&self, target: &CStr, flags: MntFlags) -> nix::Result<()> {
+        let flags = self::flags(crate::plan::mnt_flag_names(flags));
+        self.record("umount2", &[arg(Some(target)), flags])
+    }
+
+    fn pivot_root(&self, new_root: &CStr, put_old: &CStr) -> nix::Result<()> {
+        self.record("pivot_root", &[arg(Some(new_root)), arg(Some(put_old))])
+    }
+
+    fn chdir(&self, path: &CStr) -> nix::Result<()> {
+        self.record("chdir", &[arg(Some(path))])
+    }
+
+    fn fchdir(&self, fd: RawFd) -> nix::Result<()> {
+        self.record("fchdir", &[fd.to_string()])
+    }
+
+    fn chroot(&self, path: &CStr) -> nix::Result<()> {
+        self.record("chroot", &[arg(Some(path))])
+    }
+
+    fn open(&self, path: &CStr, _flags: OFlag) -> nix::Result<RawFd> {
+        self.record("open", &[arg(Some(path))])?;
+        self.find(path).map(|_| self.fd())
+    }
+
+    fn mkdir(&self, path: &CStr, _mode: libc::mode_t) -> nix::Result<()> {
+        self.record("mkdir", &[arg(Some(path))])
+    }
+
+    fn create(&self, path: &CStr) -> nix::Result<()> {
+        self.record("create", &[arg(Some(path))])
+    }
+
+    fn symlink(&self, target: &CStr, link: &CStr) -> nix::Result<()> {
+        self.record("symlink", &[arg(Some(target)), arg(Some(link))])
+    }
+
+    fn readlink(&self, link: &CStr, _buf: &mut [u8]) -> nix::Result<usize> {
+        self.find(link).and(Err(Errno::EINVAL))
+    }
+
+    fn file_type(&self, path: &CStr) -> nix::Result<libc::mode_t> {
+        self.find(path)
+    }
+
+    /// Files are on the same device, with their index as inode.
+    fn file_id(&self, path: &CStr) -> nix::Result<(libc::dev_t, libc::ino_t)> {
+        let path = path.to_string_lossy();
+        self.files
+            .iter()
+            .position(|(p, _)| *p == path)
+            .map(|i| (0, i as libc::ino_t))
+            .ok_or(Errno::ENOENT)
+    }
+
+    /// No flags are locked on fake mounts.
+    fn mount_flags(&self, _path: &CStr) -> nix::Result<FsFlags> {
+        Ok(FsFlags::empty())
+    }
+
+    fn write_file(&self, path: &CStr, data: &[u8]) -> nix::Result<()> {
+        let data = format!("{:?}", String::from_utf8_lossy(data));
+        self.record("write", &[arg(Some(path)), data])
+    }
+
+    fn sethostname(&self, name: &OsStr) -> nix::Result<()> {
+        self.record("sethostname", &[name.to_string_lossy().into_owned()])
+    }
+
+    fn dup2(&self, old: RawFd, new: RawFd) -> nix::Result<()> {
+        self.record("dup2", &[old.to_string(), new.to_string()])
+    }
+
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        self.record("close", &[fd.to_string()])
+    }
+
+    fn inherit(&self, fd: RawFd) -> nix::Result<()> {
+        self.record("inherit", &[fd.to_string()])
+    }
+
+    fn close_range(&self, first: u32, last: u32) -> nix::Result<()> {
+        self.record("close_range", &[first.to_string(), last.to_string()])
+    }
+
+    fn execvpe(
+        &self,
+        bin: &CStr,
+        argv: &[*const libc::c_char],
+        _envp: &[*const libc::c_char],
+    ) -> Errno {
+        let mut args = vec![arg(Some(bin))];
+        for &ptr in argv.iter().take_while(|ptr| !ptr.is_null()) {
+            args.push(arg(Some(unsafe { CStr::from_ptr(ptr) })));
+        }
+        // It only returns on failure
+        self.record("execvpe", &args)
+            .err()
+            .unwrap_or(Errno::ENOEXEC)
+    }
+}
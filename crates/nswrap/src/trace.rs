@@ -0,0 +1,276 @@
+//! Debug logs of the setup of the child.
+//!
+//! The child may not allocate, so it cannot log by itself. When debug
+//! logs of nswrap are enabled, it writes a fixed size `Record` per stage
+//! of its setup to a pipe instead, and a thread of the parent logs them
+//! with the steps of the `setup::Plan` they refer to.
+
+use crate::error::Error;
+use crate::setup::{self, Step};
+use nix::errno::Errno;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A stage of the setup of the child, in the order they run, see
+/// `setup::Plan::stages`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Stage {
+    /// Stdio, the parent death signal, the cgroup and `Wrap::join`.
+    Process,
+    /// Fork into the joined pid namespace.
+    Fork,
+    /// Enter and unshare namespaces `clone(2)` did not create.
+    Namespaces,
+    /// A step of `setup::Plan::id_maps`.
+    IdMap,
+    Hostname,
+    CreateHooks,
+    /// A step of `setup::Plan::mounts`.
+    Mount,
+    Pty,
+    Callbacks,
+    StartHooks,
+    Fds,
+    Landlock,
+    /// Working directory, rlimits, user and `prctl(2)` attributes.
+    Attributes,
+    /// `execve(2)` of the program.
+    Exec,
+}
+
+impl Stage {
+    const ALL: [Stage; 14] = [
+        Stage::Process,
+        Stage::Fork,
+        Stage::Namespaces,
+        Stage::IdMap,
+        Stage::Hostname,
+        Stage::CreateHooks,
+        Stage::Mount,
+        Stage::Pty,
+        Stage::Callbacks,
+        Stage::StartHooks,
+        Stage::Fds,
+        Stage::Landlock,
+        Stage::Attributes,
+        Stage::Exec,
+    ];
+
+    fn describe(self) -> &'static str {
+        match self {
+            Stage::Process => "set up the process",
+            Stage::Fork => "fork into the joined pid namespace",
+            Stage::Namespaces => "enter and unshare namespaces",
+            Stage::IdMap => "write an id map",
+            Stage::Hostname => "set the hostname",
+            Stage::CreateHooks => "run createContainer hooks",
+            Stage::Mount => "run a mount step",
+            Stage::Pty => "set up the pseudo-terminal",
+            Stage::Callbacks => "run callbacks",
+            Stage::StartHooks => "run startContainer hooks",
+            Stage::Fds => "set up fds",
+            Stage::Landlock => "enforce Landlock",
+            Stage::Attributes => "set process attributes",
+            Stage::Exec => "execute",
+        }
+    }
+}
+
+/// What the child sends about a stage, as `RECORD_SIZE` bytes in the
+/// order of the fields.
+#[derive(Clone, Copy)]
+struct Record {
+    stage: u8,
+    /// Step of the stage, for id maps and mounts.
+    index: u32,
+    nanos: u64,
+    /// 0 if the stage succeeded, `FAILED` if it failed without an errno.
+    errno: i32,
+}
+
+const FAILED: i32 = -1;
+
+const RECORD_SIZE: usize = 1 + 4 + 8 + 4;
+
+// Written at once, see `send`
+const _: () = assert!(RECORD_SIZE <= libc::PIPE_BUF);
+
+impl Record {
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[0] = self.stage;
+        buf[1..5].copy_from_slice(&self.index.to_ne_bytes());
+        buf[5..13].copy_from_slice(&self.nanos.to_ne_bytes());
+        buf[13..].copy_from_slice(&self.errno.to_ne_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; RECORD_SIZE]) -> Self {
+        Record {
+            stage: buf[0],
+            index: u32::from_ne_bytes(buf[1..5].try_into().unwrap()),
+            nanos: u64::from_ne_bytes(buf[5..13].try_into().unwrap()),
+            errno: i32::from_ne_bytes(buf[13..].try_into().unwrap()),
+        }
+    }
+}
+
+/// Whether the child should send records.
+pub(crate) fn enabled() -> bool {
+    log::log_enabled!(log::Level::Debug)
+}
+
+/// Send a record of `stage` to `fd`, from the child.
+///
+/// Records are smaller than `PIPE_BUF`, so they are written at once.
+pub(crate) fn send(
+    fd: RawFd,
+    stage: Stage,
+    index: usize,
+    took: Duration,
+    outcome: Result<(), Option<Errno>>,
+) {
+    let record = Record {
+        stage: stage as u8,
+        index: index as u32,
+        nanos: took.as_nanos() as u64,
+        errno: match outcome {
+            Ok(()) => 0,
+            Err(Some(errno)) => errno as i32,
+            Err(None) => FAILED,
+        },
+    };
+    let _ = nix::unistd::write(fd, &record.to_bytes());
+}
+
+/// The pipe records are sent over, with descriptions of the steps they
+/// refer to.
+pub(crate) struct Channel {
+    read: OwnedFd,
+    write: OwnedFd,
+    id_maps: Vec<String>,
+    mounts: Vec<String>,
+    exec: Option<String>,
+}
+
+impl Channel {
+    /// Open a pipe whose write end is at or above `lowest`, so that it
+    /// stays clear of the fds the child moves around.
+    pub(crate) fn new(
+        plan: &setup::Plan,
+        lowest: RawFd,
+        exec: Option<String>,
+    ) -> Result<Self, Error> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+        let errno = |e: Errno| Error::OsErrno(e as i32);
+        let (read, write) = nix::unistd::pipe2(OFlag::O_CLOEXEC).map_err(errno)?;
+        let read = unsafe { OwnedFd::from_raw_fd(read) };
+        let write = unsafe { OwnedFd::from_raw_fd(write) };
+        let moved = fcntl(write.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(lowest)).map_err(errno)?;
+        let describe = |steps: &[Step]| steps.iter().map(|s| s.operation().to_string()).collect();
+        Ok(Self {
+            read,
+            write: unsafe { OwnedFd::from_raw_fd(moved) },
+            id_maps: describe(&plan.id_maps),
+            mounts: describe(&plan.mounts),
+            exec,
+        })
+    }
+
+    /// The write end, for the child.
+    pub(crate) fn fd(&self) -> RawFd {
+        self.write.as_raw_fd()
+    }
+
+    /// Close the write end, and log the records of the child `pid` in a
+    /// thread until it executes or exits.
+    pub(crate) fn forward(self, pid: u32, cloned: Instant) -> Option<JoinHandle<()>> {
+        let Channel {
+            read,
+            write,
+            id_maps,
+            mounts,
+            exec,
+        } = self;
+        drop(write);
+        let mut read = File::from(read);
+        let forward = move || {
+            let mut buf = [0u8; RECORD_SIZE];
+            while read.read_exact(&mut buf).is_ok() {
+                let record = Record::from_bytes(&buf);
+                let Some(stage) = Stage::ALL.get(record.stage as usize).copied() else {
+                    continue;
+                };
+                let index = record.index as usize;
+                let what = match stage {
+                    Stage::IdMap => id_maps.get(index).map(String::as_str),
+                    Stage::Mount => mounts.get(index).map(String::as_str),
+                    Stage::Exec => exec.as_deref(),
+                    _ => None,
+                }
+                .unwrap_or(stage.describe());
+                let took = Duration::from_nanos(record.nanos);
+                match (stage, record.errno) {
+                    (Stage::Exec, 0) => log::debug!(
+                        "child {}: {} after {:?} of setup",
+                        pid,
+                        what,
+                        cloned.elapsed()
+                    ),
+                    (_, 0) => log::debug!("child {}: {} in {:?}", pid, what, took),
+                    (_, FAILED) => log::debug!("child {}: {} failed after {:?}", pid, what, took),
+                    (_, errno) => log::debug!(
+                        "child {}: {} failed after {:?}: {}",
+                        pid,
+                        what,
+                        took,
+                        Errno::from_i32(errno).desc()
+                    ),
+                }
+            }
+        };
+        std::thread::Builder::new()
+            .name("nswrap-trace".into())
+            .spawn(forward)
+            .map_err(|e| log::debug!("child {}: not forwarding its setup: {}", pid, e))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_by_value() {
+        for (i, stage) in Stage::ALL.iter().enumerate() {
+            assert_eq!(*stage as usize, i);
+        }
+    }
+
+    #[test]
+    fn records_fit_the_pipe() {
+        let channel = Channel::new(&Default::default(), 10, None).unwrap();
+        assert!(channel.fd() >= 10);
+        let took = Duration::from_micros(7);
+        send(channel.fd(), Stage::Mount, 2, took, Err(Some(Errno::EPERM)));
+        send(channel.fd(), Stage::Exec, 0, took, Err(None));
+
+        let mut read = File::from(channel.read);
+        let mut buf = [0u8; RECORD_SIZE];
+        read.read_exact(&mut buf).unwrap();
+        let record = Record::from_bytes(&buf);
+        assert_eq!(record.stage, Stage::Mount as u8);
+        assert_eq!(record.index, 2);
+        assert_eq!(record.nanos, 7000);
+        assert_eq!(record.errno, libc::EPERM);
+        read.read_exact(&mut buf).unwrap();
+        let record = Record::from_bytes(&buf);
+        assert_eq!(record.errno, FAILED);
+    }
+}
@@ -5,6 +5,9 @@ use linux_raw_sys::general::{
     CLONE_FILES, CLONE_FS, CLONE_NEWCGROUP, CLONE_NEWIPC, CLONE_NEWNET, CLONE_NEWNS, CLONE_NEWPID,
     CLONE_NEWTIME, CLONE_NEWUSER, CLONE_NEWUTS, CLONE_SYSVSEM,
 };
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use std::io::IoSliceMut;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
 
 pub fn get_uid() -> u32 {
     nix::unistd::Uid::current().into()
@@ -40,6 +43,8 @@ bitflags! {
         const NEWTIME = CLONE_NEWTIME;
         /// `CLONE_NEWUSER`.
         const NEWUSER = CLONE_NEWUSER;
+        /// `CLONE_NEWUTS`.
+        const NEWUTS = CLONE_NEWUTS;
         /// `CLONE_SYSVSEM`.
         const SYSVSEM = CLONE_SYSVSEM;
     }
@@ -56,6 +61,12 @@ pub type CloneCb<'a> = Box<dyn FnMut() -> isize + 'a>;
 /// address need not be the highest address of the region.  Nix will take
 /// care of that requirement.  The user only needs to provide a reference to
 /// a normally allocated buffer.
+///
+/// # Safety
+///
+/// The child process runs `cb` on `stack` with a copy of the parent's
+/// memory, so `stack` must stay valid until `cb` returns and `cb` must
+/// only perform operations that are safe after `clone(2)`.
 pub unsafe fn clone(
     mut cb: CloneCb,
     stack: &mut [u8],
@@ -72,7 +83,10 @@ pub unsafe fn clone(
         let ptr = stack.as_mut_ptr().add(stack.len());
         let ptr_aligned = ptr.sub(ptr as usize % 16);
         libc::clone(
-            std::mem::transmute(callback as extern "C" fn(*mut Box<dyn FnMut() -> isize>) -> i32),
+            std::mem::transmute::<
+                extern "C" fn(*mut Box<dyn FnMut() -> isize>) -> i32,
+                extern "C" fn(*mut libc::c_void) -> i32,
+            >(callback),
             ptr_aligned as *mut libc::c_void,
             combined,
             &mut cb as *mut _ as *mut libc::c_void,
@@ -81,9 +95,58 @@ pub unsafe fn clone(
 
     if res == -1 {
         Err({ Error::OsErrno(unsafe {
-            *libc::__errno_location().clone()
+            *libc::__errno_location()
         }) })
     } else {
         Ok(res as u32)
     }
 }
+
+/// Send `fd` over the unix socket `sock` with `SCM_RIGHTS`, along with
+/// `data`, which must not be empty.
+///
+/// The message is built on the stack, as the child sends the pty master
+/// with it.
+pub fn send_fd(sock: RawFd, fd: RawFd, data: &[u8]) -> Result<(), Error> {
+    // Room for one fd, aligned for `cmsghdr`
+    let mut cmsg_buf = [0u64; 4];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+    match unsafe { libc::sendmsg(sock, &msg, 0) } {
+        -1 => Err(Error::OsErrno(nix::errno::errno())),
+        _ => Ok(()),
+    }
+}
+
+/// Receive a fd sent by [`send_fd`] from the unix socket `sock`, the data
+/// sent along is read into `buf`.
+///
+/// Returns `None` if the peer closed the socket without sending one.
+pub fn recv_fd(sock: RawFd, buf: &mut [u8]) -> Result<Option<OwnedFd>, Error> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(sock, &mut iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC)
+        .map_err(|e| Error::OsErrno(e as i32))?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(Some(unsafe { OwnedFd::from_raw_fd(*fd) }));
+            }
+        }
+    }
+    Ok(None)
+}
@@ -2,7 +2,8 @@
 extern crate log;
 use clap::{Args, Parser, Subcommand};
 use petbox::config::Config;
-use std::path::Path;
+use std::ffi::OsString;
+use std::path::PathBuf;
 #[cfg(debug_assertions)]
 const DEBUG_ENV: bool = true;
 
@@ -20,7 +21,7 @@ enum Commands {
     /// Create new petbox rootfs container
     Create(Create),
 
-    #[command(subcommand)]
+    #[command()]
     /// Low-level container runtime
     ///
     /// Run a process in a petbox container with new namespace
@@ -32,6 +33,13 @@ enum Commands {
     /// Start a container and put it in background
     Start(Start),
 
+    #[command()]
+    /// OCI runtime command line interface
+    ///
+    /// Manage containers created from OCI bundles, compatible with
+    /// tools that drive runc(8) like runtimes
+    Oci(Oci),
+
     #[command(subcommand)]
     /// Low-level container monitor utility
     /// 
@@ -44,12 +52,102 @@ enum Commands {
     /// This sub-command will try to use existent namespace,
     /// and will start one when appropriate
     Exec(Exec),
+
+    #[command()]
+    /// Show the state of a container as JSON
+    ///
+    /// With --runtime, show what the kernel reports for its process
+    /// instead: namespaces, id maps, root, mounts and cgroup
+    Inspect(Inspect),
+
+    #[command()]
+    /// Check which container features the kernel offers
+    ///
+    /// Prints how to enable the missing ones, and fails if containers
+    /// cannot be created at all
+    Check(Check),
+}
+
+#[derive(Args)]
+#[command(disable_help_flag(true))]
+struct Wrap {
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    /// Options compatible with bwrap(1), followed by the command to run
+    args: Vec<OsString>,
+}
+
+#[derive(Args)]
+struct Oci {
+    #[arg(long, global = true)]
+    /// Directory to store the state of containers
+    root: Option<PathBuf>,
+
+    #[arg(long, global = true, action = clap::ArgAction::Help)]
+    /// Show this message
+    help: (),
+
+    #[command(subcommand)]
+    command: OciCommand,
 }
 
 #[derive(Subcommand)]
-enum Wrap {
+enum OciCommand {
+    /// Create a container from a bundle
+    Create {
+        /// Id of the container
+        id: String,
 
-    
+        #[arg(short, long, default_value = ".")]
+        /// Path to the bundle directory
+        bundle: PathBuf,
+
+        #[arg(long)]
+        /// File to write the process id of the container to
+        pid_file: Option<PathBuf>,
+
+        #[arg(long)]
+        /// Unix socket to send the master of the pseudo-terminal to
+        console_socket: Option<PathBuf>,
+    },
+    /// Run the user process of a created container
+    Start {
+        /// Id of the container
+        id: String,
+    },
+    /// Print the state of a container as JSON
+    State {
+        /// Id of the container
+        id: String,
+    },
+    /// Send a signal to the container process
+    Kill {
+        /// Id of the container
+        id: String,
+
+        #[arg(default_value = "SIGTERM")]
+        /// Signal to send, like SIGKILL, KILL or 9
+        signal: String,
+    },
+    /// Freeze the processes of a running container
+    Pause {
+        /// Id of the container
+        id: String,
+    },
+    #[command(alias = "resume")]
+    /// Let the processes of a paused container run again
+    Unpause {
+        /// Id of the container
+        id: String,
+    },
+    /// Delete a stopped container
+    Delete {
+        /// Id of the container
+        id: String,
+
+        #[arg(short, long)]
+        /// Kill the container first if it is still running
+        force: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -118,15 +216,57 @@ struct Start {
 
 #[derive(Args)]
 struct Exec {
+    #[arg(short, long, required_unless_present = "nsenter")]
+    /// Name of the container, the id of a created or running OCI container
+    name: Option<String>,
+
+    #[arg(long, conflicts_with = "nsenter")]
+    /// Directory storing the state of OCI containers
+    root: Option<PathBuf>,
+
+    #[arg(long, value_name = "PID", conflicts_with = "name")]
+    /// Join all namespaces, the root and working directory of a running
+    /// process instead
+    nsenter: Option<i32>,
+
     #[arg(short, long)]
-    /// Name of the container
-    name: String,
+    /// Allocate a pseudo-terminal for the program
+    tty: bool,
+
+    #[arg(long, value_name = "KEYS")]
+    /// Key sequence to detach from the terminal [default: ctrl-p,ctrl-q]
+    detach_keys: Option<String>,
+
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    /// Program to run, followed by its arguments
+    command: Vec<String>,
 
     #[arg(long,action = clap::ArgAction::Help)]
     /// Show this message
     help: (),
 }
 
+#[derive(Args)]
+struct Inspect {
+    /// Id of an OCI container, or a process id with --runtime
+    target: String,
+
+    #[arg(long)]
+    /// Show the namespaces and container setup of the running process
+    runtime: bool,
+
+    #[arg(long)]
+    /// Directory storing the state of OCI containers
+    root: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Check {
+    #[arg(long)]
+    /// Print the report as JSON
+    json: bool,
+}
+
 fn main() {
     let mut logger: env_logger::Builder;
     if DEBUG_ENV {
@@ -143,14 +283,211 @@ fn main() {
     match &cli.command {
         Commands::Create(opt) => {
             let config = Config::build();
-            let root_path = config.get_container_rootfs(&opt.name);
+            let _root_path = config.get_container_rootfs(&opt.name);
             todo!()
         }
         Commands::Wrap(opt) => {
-            todo!()
+            let opts = match petbox::wrap::parse(opt.args.clone()) {
+                Ok(opts) => opts,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1)
+                }
+            };
+            if opts.help {
+                print!("{}", petbox::wrap::USAGE);
+                return;
+            }
+            if opts.dry_run {
+                if let Err(e) = print_plan(&opts) {
+                    error!("{}", e);
+                    std::process::exit(1)
+                }
+                return;
+            }
+            let status = match run_wrap(&opts) {
+                Ok(Some(status)) => status,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1)
+                }
+            };
+            if opts.report {
+                info!("Command {}", status);
+            }
+            std::process::exit(
+                status
+                    .code()
+                    .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+            )
+        }
+        Commands::Oci(opt) => {
+            if let Err(e) = run_oci(opt) {
+                error!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        Commands::Exec(opt) => {
+            let status = match run_exec(opt) {
+                Ok(Some(status)) => status,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1)
+                }
+            };
+            std::process::exit(
+                status
+                    .code()
+                    .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+            )
+        }
+        Commands::Inspect(opt) => {
+            if let Err(e) = run_inspect(opt) {
+                error!("{}", e);
+                std::process::exit(1)
+            }
         }
-        Commands::Exec(_) => todo!(),
+        Commands::Check(opt) => match run_check(opt) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1)
+            }
+        },
         Commands::Start(_) => todo!(),
         Commands::Cmon(_) => todo!(),
     }
 }
+
+/// Run the sandbox, proxying its terminal with `--tty`.
+///
+/// Returns `None` if the user detached from the terminal.
+fn run_wrap(
+    opts: &petbox::wrap::WrapOptions,
+) -> Result<Option<nswrap::ExitStatus>, petbox::error::Error> {
+    attach(opts.build().spawn()?, opts.detach_keys.as_deref())
+}
+
+/// Print what running the sandbox would do, for `--dry-run`.
+fn print_plan(opts: &petbox::wrap::WrapOptions) -> Result<(), petbox::error::Error> {
+    let plan = opts.build().plan()?;
+    match opts.json {
+        true => println!("{}", serde_json::to_string_pretty(&plan)?),
+        false => print!("{}", plan),
+    }
+    Ok(())
+}
+
+/// Run a program in the namespaces of the container `--name`, or of a
+/// running process with `--nsenter`, proxying its terminal with `--tty`.
+///
+/// Returns `None` if the user detached from the terminal.
+fn run_exec(opt: &Exec) -> Result<Option<nswrap::ExitStatus>, petbox::error::Error> {
+    use nswrap::config::{JoinTarget, NamespaceType};
+    use petbox::oci::Runtime;
+    let pid = match (opt.nsenter, &opt.name) {
+        (Some(pid), _) => pid,
+        (None, Some(name)) => {
+            Runtime::new(opt.root.clone().unwrap_or_else(Runtime::default_root)).pid(name)?
+        }
+        (None, None) => unreachable!("clap requires --name or --nsenter"),
+    };
+    let detach_keys = match &opt.detach_keys {
+        Some(keys) => Some(petbox::tty::parse_detach_keys(keys)?),
+        None => None,
+    };
+    let mut wrap = nswrap::Wrap::new_cmd(&opt.command[0]);
+    wrap.args(&opt.command[1..])
+        .join(
+            JoinTarget::Pid(pid),
+            &[
+                NamespaceType::User,
+                NamespaceType::Mount,
+                NamespaceType::Cgroup,
+                NamespaceType::Uts,
+                NamespaceType::Ipc,
+                NamespaceType::Pid,
+                NamespaceType::Network,
+                NamespaceType::Time,
+            ],
+        )
+        .tty(opt.tty);
+    attach(wrap.spawn()?, detach_keys.as_deref())
+}
+
+/// Proxy the pseudo-terminal of `child`, if it has one, and wait for it.
+///
+/// Returns `None` if the user detached from the terminal.
+fn attach(
+    mut child: nswrap::Child,
+    detach_keys: Option<&[u8]>,
+) -> Result<Option<nswrap::ExitStatus>, petbox::error::Error> {
+    use petbox::tty;
+    if let Some(master) = child.take_pty_master() {
+        let keys = match detach_keys {
+            Some(keys) => keys.to_vec(),
+            None => tty::parse_detach_keys(tty::DEFAULT_DETACH_KEYS)?,
+        };
+        if tty::proxy(&master, &keys)? == tty::ProxyExit::Detached {
+            tty::detach(master)?;
+            info!("Detached from pid {}", child.id());
+            return Ok(None);
+        }
+    }
+    Ok(Some(child.wait()?))
+}
+
+fn run_inspect(opt: &Inspect) -> Result<(), petbox::error::Error> {
+    use petbox::oci::Runtime;
+    let runtime = Runtime::new(opt.root.clone().unwrap_or_else(Runtime::default_root));
+    let json = match (opt.runtime, opt.target.parse::<i32>()) {
+        (true, Ok(pid)) => serde_json::to_string_pretty(&nswrap::inspect::inspect(
+            nswrap::config::JoinTarget::Pid(pid),
+        )?)?,
+        (true, Err(_)) => serde_json::to_string_pretty(&runtime.inspect(&opt.target)?)?,
+        (false, _) => serde_json::to_string_pretty(&runtime.state(&opt.target)?)?,
+    };
+    println!("{}", json);
+    Ok(())
+}
+
+/// Print the features of the kernel, returns whether containers can be
+/// created.
+fn run_check(opt: &Check) -> Result<bool, petbox::error::Error> {
+    let report = nswrap::probe();
+    match opt.json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => print!("{}", report),
+    }
+    Ok(report.is_usable())
+}
+
+fn run_oci(opt: &Oci) -> Result<(), petbox::error::Error> {
+    use petbox::oci::Runtime;
+    let runtime = Runtime::new(opt.root.clone().unwrap_or_else(Runtime::default_root));
+    match &opt.command {
+        OciCommand::Create {
+            id,
+            bundle,
+            pid_file,
+            console_socket,
+        } => {
+            let state = runtime.create(id, bundle, console_socket.as_deref())?;
+            if let Some(pid_file) = pid_file {
+                std::fs::write(pid_file, state.pid().unwrap().to_string())?;
+            }
+        }
+        OciCommand::Start { id } => runtime.start(id)?,
+        OciCommand::State { id } => {
+            println!("{}", serde_json::to_string_pretty(&runtime.state(id)?)?)
+        }
+        OciCommand::Kill { id, signal } => runtime.kill(id, petbox::oci::parse_signal(signal)?)?,
+        OciCommand::Pause { id } => runtime.pause(id)?,
+        OciCommand::Unpause { id } => runtime.unpause(id)?,
+        OciCommand::Delete { id, force } => runtime.delete(id, *force)?,
+    }
+    Ok(())
+}
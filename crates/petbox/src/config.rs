@@ -8,15 +8,14 @@ pub struct Config {
 
 impl Config {
     pub fn build() -> Self {
-        let data_dir;
-        match std::env::var("PETBOX_DATA_DIR") {
-            Ok(dir) => data_dir = PathBuf::from(dir),
+        let data_dir = match std::env::var("PETBOX_DATA_DIR") {
+            Ok(dir) => PathBuf::from(dir),
             Err(_) => {
                 let xdg_dirs = xdg::BaseDirectories::with_prefix("petbox").unwrap();
                 //let config_dir = xdg_dirs.get_config_home().into();
-                data_dir = xdg_dirs.get_data_home();
+                xdg_dirs.get_data_home()
             }
-        }
+        };
         let s = Self {
             //config_dir,
             data_dir,
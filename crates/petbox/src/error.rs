@@ -3,6 +3,22 @@ use thiserror::Error;
 pub enum Error {
     #[error("command exit with bad state: `{0}`")]
     CommandFailed(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("io error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("container runtime failed: `{0}`")]
+    Wrap(#[from] nswrap::error::Error),
+    #[error("container `{0}` does not exist")]
+    ContainerNotFound(String),
+    #[error("container `{0}` already exists")]
+    ContainerExists(String),
+    #[error("container `{0}` is {1:?}")]
+    InvalidState(String, nswrap::oci::Status),
+    #[error("system call failed: `{0}`")]
+    Os(#[from] nix::errno::Errno),
+    #[error("invalid state file: `{0}`")]
+    Json(#[from] serde_json::Error),
     #[error("unknown data store error")]
     Unknown,
 }
\ No newline at end of file
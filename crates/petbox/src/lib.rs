@@ -1,4 +1,7 @@
 #[macro_use]
 extern crate log;
 pub mod config;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod oci;
+pub mod tty;
+pub mod wrap;
\ No newline at end of file
@@ -0,0 +1,719 @@
+//! OCI runtime command line interface, the `petbox oci` sub-command.
+//!
+//! Containers are created from bundles with `nswrap::oci`, and their state
+//! is kept in a directory per container under the runtime root. The
+//! container process waits on a FIFO in that directory after `create`,
+//! until `start` reads from it.
+//!
+//! When the cgroup of the runtime is writable, each container gets a
+//! cgroup of its own, so that it can be paused and killed as a whole.
+//! Otherwise its processes are signalled one by one.
+
+use crate::error::Error;
+use nix::fcntl::OFlag;
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nswrap::cgroup::{self, Cgroup};
+use nswrap::config::{Hook, Hooks, IdMapPreset, JoinTarget, NamespaceType, Resources};
+use nswrap::oci::{Spec, State, Status};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the state file in the directory of a container
+const STATE_FILE: &str = "state.json";
+
+/// Field of the state file with the start time of the container process,
+/// which tells it apart from a later process that reuses its pid
+const START_TIME_FIELD: &str = "petboxStartTime";
+
+/// Name of the FIFO the container process waits on until `start`
+const EXEC_FIFO: &str = "exec.fifo";
+
+/// Name of the file keeping the hooks of the container for later operations
+const HOOKS_FILE: &str = "hooks.json";
+
+/// Name of the file keeping the path of the cgroup of the container
+const CGROUP_FILE: &str = "cgroup";
+
+/// Interval to check whether the container process is still alive
+const POLL_INTERVAL_MS: i32 = 100;
+
+/// Containers under a runtime root directory.
+pub struct Runtime {
+    root: PathBuf,
+}
+
+impl Runtime {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// `$XDG_RUNTIME_DIR/petbox`, or a directory in `/tmp` when
+    /// `XDG_RUNTIME_DIR` is not set.
+    pub fn default_root() -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => Path::new(&dir).join("petbox"),
+            None => PathBuf::from(format!("/tmp/petbox-{}", nix::unistd::getuid())),
+        }
+    }
+
+    /// Create the container `id` from `bundle`.
+    ///
+    /// The container process is set up, and then waits for `start`
+    /// before it runs the user process. If `process.terminal` is set in
+    /// the configuration, the master of its pseudo-terminal is sent to
+    /// `console_socket`, which is required then.
+    pub fn create(
+        &self,
+        id: &str,
+        bundle: &Path,
+        console_socket: Option<&Path>,
+    ) -> Result<State, Error> {
+        let dir = self.container_dir(id)?;
+        std::fs::create_dir_all(&self.root)?;
+        if let Err(e) = std::fs::create_dir(&dir) {
+            return match e.kind() {
+                std::io::ErrorKind::AlreadyExists => Err(Error::ContainerExists(id.into())),
+                _ => Err(e.into()),
+            };
+        }
+        let result = create_in(id, &dir, bundle, console_socket);
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+        result
+    }
+
+    /// Run the user process of the created container `id`.
+    pub fn start(&self, id: &str) -> Result<(), Error> {
+        let dir = self.container_dir(id)?;
+        let (state, start_time) = self.load(id)?;
+        if *state.status() != Status::Created {
+            return Err(Error::InvalidState(id.into(), *state.status()));
+        }
+        let pid = state.pid().unwrap();
+
+        // Opening for reading does not block, and unblocks the container
+        // process waiting to open the FIFO for writing.
+        let fifo = nix::fcntl::open(
+            &dir.join(EXEC_FIFO),
+            OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let mut fifo = unsafe { File::from_raw_fd(fifo) };
+        let mut started = [0];
+        loop {
+            let mut fds = [nix::poll::PollFd::new(
+                fifo.as_raw_fd(),
+                nix::poll::PollFlags::POLLIN,
+            )];
+            if nix::poll::poll(&mut fds, POLL_INTERVAL_MS)? > 0 {
+                match fifo.read(&mut started)? {
+                    0 => return Err(Error::CommandFailed("container exited before start".into())),
+                    _ => break,
+                }
+            }
+            if !is_alive(pid, start_time) {
+                return Err(Error::CommandFailed("container exited before start".into()));
+            }
+        }
+        // The container process keeps the FIFO open while it runs the
+        // startContainer hooks, it is closed on execution, or a byte is
+        // written if a hook fails.
+        nix::fcntl::fcntl(fifo.as_raw_fd(), nix::fcntl::F_SETFL(OFlag::empty()))?;
+        let hook_failed = fifo.read(&mut started)? != 0;
+        std::fs::remove_file(dir.join(EXEC_FIFO))?;
+        if hook_failed {
+            return Err(Error::CommandFailed("startContainer hook failed".into()));
+        }
+
+        let mut state = state;
+        state.set_status(Status::Running);
+        for hook in load_hooks(&dir)?.poststart() {
+            if let Err(e) = hook.run(&state) {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// State of the container `id`, with the status as of now.
+    pub fn state(&self, id: &str) -> Result<State, Error> {
+        Ok(self.load(id)?.0)
+    }
+
+    /// State of the container `id` with the status as of now, and the
+    /// start time of its process.
+    fn load(&self, id: &str) -> Result<(State, Option<u64>), Error> {
+        let dir = self.container_dir(id)?;
+        let content = match std::fs::read(dir.join(STATE_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::ContainerNotFound(id.into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&content)?;
+        let start_time = value.get(START_TIME_FIELD).and_then(|t| t.as_u64());
+        let mut state: State = serde_json::from_value(value)?;
+        if let Some(pid) = *state.pid() {
+            let status = if !is_alive(pid, start_time) {
+                Status::Stopped
+            } else if dir.join(EXEC_FIFO).exists() {
+                Status::Created
+            } else if cgroup::is_container_frozen(pid, load_cgroup(&dir).as_ref()).unwrap_or(false)
+            {
+                Status::Paused
+            } else {
+                Status::Running
+            };
+            state.set_status(status);
+        }
+        Ok((state, start_time))
+    }
+
+    /// Namespaces, id maps, mounts and cgroup of the container process
+    /// of `id`, as set up by the kernel.
+    pub fn inspect(&self, id: &str) -> Result<nswrap::inspect::ProcessInfo, Error> {
+        Ok(nswrap::inspect::inspect(JoinTarget::Pid(self.pid(id)?))?)
+    }
+
+    /// Pid of the container process of `id`, which must be created or
+    /// running.
+    pub fn pid(&self, id: &str) -> Result<i32, Error> {
+        let state = self.state(id)?;
+        match (state.status(), state.pid()) {
+            (Status::Created | Status::Running, Some(pid)) => Ok(*pid),
+            (status, _) => Err(Error::InvalidState(id.into(), *status)),
+        }
+    }
+
+    /// Send `signal` to the container process of `id`.
+    pub fn kill(&self, id: &str, signal: Signal) -> Result<(), Error> {
+        let state = self.state(id)?;
+        match state.status() {
+            Status::Created | Status::Running | Status::Paused => {
+                let pid = nix::unistd::Pid::from_raw(state.pid().unwrap());
+                nix::sys::signal::kill(pid, signal)?;
+                Ok(())
+            }
+            status => Err(Error::InvalidState(id.into(), *status)),
+        }
+    }
+
+    /// Freeze the processes of the running container `id`.
+    pub fn pause(&self, id: &str) -> Result<(), Error> {
+        let state = self.state(id)?;
+        match state.status() {
+            Status::Running => {
+                let cgroup = load_cgroup(&self.container_dir(id)?);
+                Ok(cgroup::freeze_container(
+                    state.pid().unwrap(),
+                    cgroup.as_ref(),
+                )?)
+            }
+            status => Err(Error::InvalidState(id.into(), *status)),
+        }
+    }
+
+    /// Let the processes of the paused container `id` run again.
+    pub fn unpause(&self, id: &str) -> Result<(), Error> {
+        let state = self.state(id)?;
+        match state.status() {
+            Status::Paused => {
+                let cgroup = load_cgroup(&self.container_dir(id)?);
+                Ok(cgroup::thaw_container(
+                    state.pid().unwrap(),
+                    cgroup.as_ref(),
+                )?)
+            }
+            status => Err(Error::InvalidState(id.into(), *status)),
+        }
+    }
+
+    /// Delete the stopped container `id`.
+    ///
+    /// With `force`, every process of a container that is still alive is
+    /// killed first.
+    pub fn delete(&self, id: &str, force: bool) -> Result<(), Error> {
+        let (state, start_time) = self.load(id)?;
+        let dir = self.container_dir(id)?;
+        let cgroup = load_cgroup(&dir);
+        match (state.status(), state.pid()) {
+            (Status::Stopped, _) => (),
+            (_, Some(pid)) if force => {
+                cgroup::kill_container(*pid, cgroup.as_ref())?;
+                while is_alive(*pid, start_time) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+            }
+            (status, _) => return Err(Error::InvalidState(id.into(), *status)),
+        }
+        if let Some(cgroup) = cgroup {
+            // Processes leave the cgroup shortly after they are killed
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+            while cgroup.remove().is_err() && std::time::Instant::now() < deadline {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+        let hooks = load_hooks(&dir)?;
+        std::fs::remove_dir_all(dir)?;
+
+        let mut state = state;
+        state.set_status(Status::Stopped);
+        for hook in hooks.poststop() {
+            if let Err(e) = hook.run(&state) {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn container_dir(&self, id: &str) -> Result<PathBuf, Error> {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(Error::InvalidArgument(format!(
+                "invalid container id: {}",
+                id
+            )));
+        }
+        Ok(self.root.join(id))
+    }
+}
+
+/// Parse a signal like `SIGTERM`, `TERM` or `15`.
+pub fn parse_signal(signal: &str) -> Result<Signal, Error> {
+    let invalid = || Error::InvalidArgument(format!("invalid signal: {}", signal));
+    if let Ok(num) = signal.parse::<i32>() {
+        return Signal::try_from(num).map_err(|_| invalid());
+    }
+    let name = signal.to_ascii_uppercase();
+    match name.starts_with("SIG") {
+        true => Signal::from_str(&name),
+        false => Signal::from_str(&format!("SIG{}", name)),
+    }
+    .map_err(|_| invalid())
+}
+
+fn create_in(
+    id: &str,
+    dir: &Path,
+    bundle: &Path,
+    console_socket: Option<&Path>,
+) -> Result<State, Error> {
+    let bundle = bundle.canonicalize()?;
+    let spec = Spec::load(&bundle)?;
+    let terminal = spec.process().as_ref().is_some_and(|p| p.terminal());
+    match (terminal, console_socket) {
+        (true, None) => {
+            return Err(Error::InvalidArgument(
+                "process.terminal requires --console-socket".into(),
+            ))
+        }
+        (false, Some(_)) => {
+            return Err(Error::InvalidArgument(
+                "--console-socket requires process.terminal".into(),
+            ))
+        }
+        _ => (),
+    }
+    let mut state = State::new(id, &bundle, &spec);
+    save_state(dir, &state, None)?;
+
+    let mut wrap = spec.build(&bundle)?;
+    // Without privileges, only a user namespace grants the capabilities
+    // to set up the others. Map the current user to root in it, like
+    // rootless runtimes do.
+    let linux = spec.linux().clone().unwrap_or_default();
+    if !linux
+        .namespaces()
+        .iter()
+        .any(|ns| *ns.typ() == NamespaceType::User)
+    {
+        wrap.unshare(NamespaceType::User);
+    }
+    if linux.uid_mappings().is_empty() && linux.gid_mappings().is_empty() {
+        wrap.id_map_preset(IdMapPreset::Root);
+    }
+
+    // startContainer hooks are run by the container process itself after
+    // `start`, so that their failure can be reported to `start`.
+    let mut hooks = spec.hooks().clone().unwrap_or_default();
+    std::fs::write(dir.join(HOOKS_FILE), serde_json::to_vec(&hooks)?)?;
+    let start_hooks = std::mem::take(hooks.start_container_mut());
+    wrap.hooks(hooks, state.clone());
+    if let Ok(parent) = Cgroup::current() {
+        if nix::unistd::access(parent.path(), nix::unistd::AccessFlags::W_OK).is_ok() {
+            // Ids are only unique under a runtime root, the inode of the
+            // container directory is unique on the host while it exists.
+            let ino = std::os::unix::fs::MetadataExt::ino(&dir.metadata()?);
+            let cgroup = parent.child(&format!("petbox-{}-{}", id, ino));
+            std::fs::write(dir.join(CGROUP_FILE), cgroup.path().as_os_str().as_bytes())?;
+            wrap.cgroup(cgroup, Resources::default());
+        }
+    }
+    if let Some(path) = console_socket {
+        wrap.console_socket(path);
+    }
+
+    nix::unistd::mkfifo(&dir.join(EXEC_FIFO), Mode::from_bits_truncate(0o600))?;
+    let dir_fd = nix::fcntl::open(
+        dir,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let (ready_read, ready_write) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+    // Like OCI hooks run by nswrap, the start hooks allocate, which is
+    // fine as petbox does not spawn threads.
+    unsafe {
+        wrap.callback(move || {
+            let _ = nix::unistd::close(ready_read);
+            let _ = nix::unistd::write(ready_write, &[0]);
+            let _ = nix::unistd::close(ready_write);
+            match wait_for_start(dir_fd, &start_hooks) {
+                Ok(()) => 0,
+                // Do not run the user process if `start` can not be waited for
+                Err(_) => libc::_exit(1),
+            }
+        })
+    };
+
+    let spawned = wrap.spawn();
+    let _ = nix::unistd::close(ready_write);
+    let _ = nix::unistd::close(dir_fd);
+    let mut ready = unsafe { File::from_raw_fd(ready_read) };
+    let mut child = spawned?;
+    // The pipe is closed without data if the container process failed
+    // to set up.
+    if ready.read(&mut [0])? == 0 {
+        let status = child.wait()?;
+        return Err(Error::CommandFailed(format!(
+            "container process exited during creation with {:?}",
+            status.code()
+        )));
+    }
+
+    let pid = child.id() as i32;
+    state.set_pid(Some(pid)).set_status(Status::Created);
+    save_state(dir, &state, start_time(pid))?;
+    Ok(state)
+}
+
+/// Block until `start` opens the FIFO in the container directory, and
+/// then run the startContainer hooks.
+///
+/// Runs in the container process, the directory is reached through
+/// `dir_fd` as the host filesystem is no longer mounted. The FIFO is left
+/// open to be closed on execution of the user process.
+fn wait_for_start(dir_fd: RawFd, hooks: &[Hook]) -> Result<(), Error> {
+    let fifo = nix::fcntl::openat(
+        dir_fd,
+        EXEC_FIFO,
+        OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    nix::unistd::write(fifo, &[0])?;
+    if !hooks.is_empty() {
+        let state = nix::fcntl::openat(dir_fd, STATE_FILE, OFlag::O_RDONLY, Mode::empty())?;
+        let mut state: State = serde_json::from_reader(unsafe { File::from_raw_fd(state) })?;
+        state.set_status(Status::Created);
+        for hook in hooks {
+            if let Err(e) = hook.run(&state) {
+                error!("{}", e);
+                nix::unistd::write(fifo, &[1])?;
+                return Err(e.into());
+            }
+        }
+    }
+    nix::unistd::close(dir_fd)?;
+    Ok(())
+}
+
+/// Cgroup of the container in `dir`, if it has one of its own.
+fn load_cgroup(dir: &Path) -> Option<Cgroup> {
+    let path = std::fs::read(dir.join(CGROUP_FILE)).ok()?;
+    Some(Cgroup::new(PathBuf::from(OsString::from_vec(path))))
+}
+
+/// Hooks saved in the container directory `dir` by `create`.
+fn load_hooks(dir: &Path) -> Result<Hooks, Error> {
+    let content = std::fs::read(dir.join(HOOKS_FILE))?;
+    Ok(serde_json::from_slice(&content)?)
+}
+
+/// Replace the state file in `dir` atomically, with the `start_time` of
+/// the container process.
+fn save_state(dir: &Path, state: &State, start_time: Option<u64>) -> Result<(), Error> {
+    let mut value = serde_json::to_value(state)?;
+    if let Some(start_time) = start_time {
+        value[START_TIME_FIELD] = start_time.into();
+    }
+    let tmp = dir.join(format!(".{}", STATE_FILE));
+    std::fs::write(&tmp, serde_json::to_vec(&value)?)?;
+    std::fs::rename(tmp, dir.join(STATE_FILE))?;
+    Ok(())
+}
+
+/// Fields of `/proc/<pid>/stat` after the command name, which is in
+/// parentheses, starting with the state.
+fn stat_fields(pid: i32) -> Option<Vec<String>> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    Some(fields.split_whitespace().map(String::from).collect())
+}
+
+/// Start time of `pid` in clock ticks after boot, field 22 of
+/// `/proc/<pid>/stat`.
+fn start_time(pid: i32) -> Option<u64> {
+    stat_fields(pid)?.get(19)?.parse().ok()
+}
+
+/// Whether `pid` is a process that has not exited, and that started at
+/// `start_time` if it is known, so a later process reusing the pid is not
+/// taken for it.
+///
+/// A zombie is considered exited, as the container process may still be
+/// a child of the caller that never reaps it.
+fn is_alive(pid: i32, start_time: Option<u64>) -> bool {
+    let Some(fields) = stat_fields(pid) else {
+        return false;
+    };
+    match fields.first().map(String::as_str) {
+        Some("Z") | Some("X") | None => false,
+        _ => start_time.is_none() || fields.get(19).and_then(|t| t.parse().ok()) == start_time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a bundle whose process runs `script`, with `/usr` of the host.
+    fn make_bundle(name: &str, script: &str) -> PathBuf {
+        let bundle = Path::new("/tmp/petbox.test.oci").join(name);
+        let _ = std::fs::remove_dir_all(&bundle);
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        let mut mounts = vec![serde_json::json!(
+            {"destination": "/usr", "type": "bind", "source": "/usr", "options": ["rbind", "ro"]}
+        )];
+        for dir in ["bin", "sbin", "lib", "lib32", "lib64"] {
+            let path = Path::new("/").join(dir);
+            match std::fs::read_link(&path) {
+                Ok(target) => {
+                    std::os::unix::fs::symlink(target, bundle.join("rootfs").join(dir)).unwrap()
+                }
+                Err(_) if path.is_dir() => mounts.push(serde_json::json!(
+                    {"destination": path, "type": "bind", "source": path, "options": ["rbind", "ro"]}
+                )),
+                Err(_) => (),
+            }
+        }
+        let config = serde_json::json!({
+            "ociVersion": "1.0.2",
+            "root": {"path": "rootfs"},
+            "process": {"args": ["/usr/bin/sh", "-c", script], "cwd": "/"},
+            "mounts": mounts,
+            "linux": {"namespaces": [{"type": "mount"}, {"type": "pid"}]},
+        });
+        std::fs::write(bundle.join("config.json"), config.to_string()).unwrap();
+        bundle
+    }
+
+    fn runtime(name: &str) -> Runtime {
+        let root = Path::new("/tmp/petbox.test.oci.root").join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        Runtime::new(root)
+    }
+
+    #[test]
+    fn lifecycle() {
+        let bundle = make_bundle("lifecycle", "exit 0");
+        let runtime = runtime("lifecycle");
+        let state = runtime.create("c1", &bundle, None).unwrap();
+        assert_eq!(*state.status(), Status::Created);
+        assert!(matches!(
+            runtime.create("c1", &bundle, None),
+            Err(Error::ContainerExists(_))
+        ));
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Created);
+        assert!(matches!(
+            runtime.delete("c1", false),
+            Err(Error::InvalidState(_, Status::Created))
+        ));
+
+        runtime.start("c1").unwrap();
+        let pid = runtime.state("c1").unwrap().pid().unwrap();
+        while is_alive(pid, None) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Stopped);
+        assert!(runtime.start("c1").is_err());
+        runtime.delete("c1", false).unwrap();
+        assert!(matches!(
+            runtime.state("c1"),
+            Err(Error::ContainerNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn kill_running() {
+        let bundle = make_bundle("kill", "sleep 60");
+        let runtime = runtime("kill");
+        runtime.create("c1", &bundle, None).unwrap();
+        runtime.start("c1").unwrap();
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Running);
+        runtime.kill("c1", parse_signal("KILL").unwrap()).unwrap();
+        let pid = runtime.state("c1").unwrap().pid().unwrap();
+        while is_alive(pid, None) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        runtime.delete("c1", false).unwrap();
+    }
+
+    #[test]
+    fn reused_pid() {
+        let bundle = make_bundle("reused", "sleep 60");
+        let runtime = runtime("reused");
+        let state = runtime.create("c1", &bundle, None).unwrap();
+        let dir = runtime.container_dir("c1").unwrap();
+        let start_time = start_time(state.pid().unwrap()).unwrap();
+
+        // Another process with the pid of the container is not signalled
+        save_state(&dir, &state, Some(start_time + 1)).unwrap();
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Stopped);
+        assert!(matches!(
+            runtime.kill("c1", Signal::SIGKILL),
+            Err(Error::InvalidState(_, Status::Stopped))
+        ));
+
+        save_state(&dir, &state, Some(start_time)).unwrap();
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Created);
+        runtime.delete("c1", true).unwrap();
+    }
+
+    #[test]
+    fn pause() {
+        let bundle = make_bundle("pause", "sleep 60");
+        let runtime = runtime("pause");
+        runtime.create("c1", &bundle, None).unwrap();
+        assert!(runtime.pause("c1").is_err());
+        runtime.start("c1").unwrap();
+        let cgroup = load_cgroup(&runtime.container_dir("c1").unwrap());
+
+        runtime.pause("c1").unwrap();
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Paused);
+        assert!(runtime.pause("c1").is_err());
+        runtime.unpause("c1").unwrap();
+        assert_eq!(*runtime.state("c1").unwrap().status(), Status::Running);
+
+        runtime.pause("c1").unwrap();
+        runtime.delete("c1", true).unwrap();
+        assert!(runtime.state("c1").is_err());
+        if let Some(cgroup) = cgroup {
+            assert!(!cgroup.exists());
+        }
+    }
+
+    #[test]
+    fn force_delete_created() {
+        let bundle = make_bundle("force", "exit 0");
+        let runtime = runtime("force");
+        runtime.create("c1", &bundle, None).unwrap();
+        runtime.delete("c1", true).unwrap();
+        assert!(runtime.state("c1").is_err());
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("term").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(parse_signal("NOPE").is_err());
+        assert!(runtime("signals")
+            .create("../x", Path::new("/"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn hooks() {
+        let out = Path::new("/tmp/petbox.test.oci/hooks.out");
+        let _ = std::fs::remove_dir_all(out);
+        std::fs::create_dir_all(out).unwrap();
+        let record = |name: &str| {
+            serde_json::json!({
+                "path": "/bin/sh",
+                "args": ["sh", "-c", format!("cat > {}/{}.json", out.display(), name)],
+            })
+        };
+        let bundle = make_bundle("hooks", "exit 0");
+        let config_path = bundle.join("config.json");
+        let mut config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&config_path).unwrap()).unwrap();
+        config["hooks"] = serde_json::json!({
+            "poststart": [record("poststart")],
+            "poststop": [record("poststop")],
+        });
+        std::fs::write(&config_path, config.to_string()).unwrap();
+
+        let runtime = runtime("hooks");
+        runtime.create("c1", &bundle, None).unwrap();
+        runtime.start("c1").unwrap();
+        runtime.delete("c1", true).unwrap();
+        let state = |name: &str| -> serde_json::Value {
+            serde_json::from_slice(&std::fs::read(out.join(name)).unwrap()).unwrap()
+        };
+        assert_eq!(state("poststart.json")["status"], "running");
+        assert_eq!(state("poststop.json")["status"], "stopped");
+
+        // A failed startContainer hook fails `start`
+        config["hooks"] = serde_json::json!({"startContainer": [{"path": "/usr/bin/false"}]});
+        std::fs::write(&config_path, config.to_string()).unwrap();
+        runtime.create("c2", &bundle, None).unwrap();
+        assert!(runtime.start("c2").is_err());
+        runtime.delete("c2", true).unwrap();
+    }
+
+    #[test]
+    fn console_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let bundle = make_bundle("console", "test -t 0 && echo hi");
+        let config_path = bundle.join("config.json");
+        let mut config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&config_path).unwrap()).unwrap();
+        config["process"]["terminal"] = serde_json::json!(true);
+        let mounts = config["mounts"].as_array_mut().unwrap();
+        mounts.push(serde_json::json!({"destination": "/dev", "type": "tmpfs", "source": "tmpfs"}));
+        mounts.push(serde_json::json!({
+            "destination": "/dev/pts",
+            "type": "devpts",
+            "source": "devpts",
+            "options": ["newinstance", "ptmxmode=0666", "mode=0620"],
+        }));
+        std::fs::write(&config_path, config.to_string()).unwrap();
+
+        let runtime = runtime("console");
+        assert!(matches!(
+            runtime.create("c1", &bundle, None),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let path = Path::new("/tmp/petbox.test.oci/console.sock");
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        runtime.create("c1", &bundle, Some(path)).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let master = nswrap::util::recv_fd(stream.as_raw_fd(), &mut [0; 64])
+            .unwrap()
+            .unwrap();
+        runtime.start("c1").unwrap();
+        let mut output = Vec::new();
+        let _ = File::from(master).read_to_end(&mut output);
+        assert_eq!(output, b"hi\r\n");
+        runtime.delete("c1", true).unwrap();
+    }
+}
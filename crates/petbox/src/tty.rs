@@ -0,0 +1,263 @@
+//! Proxy between the terminal of the user and the pseudo-terminal of a
+//! container, allocated by `nswrap::Wrap::tty`.
+
+use crate::error::Error;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use std::os::fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+
+/// Key sequence to detach from a container, like the one of docker.
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+
+/// Why `proxy` returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProxyExit {
+    /// The container closed its terminal, usually by exiting.
+    Exited,
+    /// The user typed the detach key sequence.
+    Detached,
+}
+
+/// Parse a comma separated key sequence, like `ctrl-p,ctrl-q`.
+///
+/// Each key is either a single character, or `ctrl-` followed by a
+/// letter or one of `@[\]^_`. An empty sequence disables detaching.
+pub fn parse_detach_keys(keys: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::InvalidArgument(format!("invalid detach keys: {}", keys));
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    keys.split(',')
+        .map(|key| {
+            let lower = key.to_ascii_lowercase();
+            let byte = match lower.strip_prefix("ctrl-") {
+                Some(c) if c.len() == 1 => match c.as_bytes()[0] {
+                    c @ b'a'..=b'z' => c - b'a' + 1,
+                    c @ (b'@' | b'[' | b'\\' | b']' | b'^' | b'_') => c - b'@',
+                    _ => return Err(invalid()),
+                },
+                None if key.len() == 1 && key.is_ascii() => key.as_bytes()[0],
+                _ => return Err(invalid()),
+            };
+            Ok(byte)
+        })
+        .collect()
+}
+
+/// Copy stdin to `master` and `master` to stdout until the container
+/// closes the terminal or the user types `detach_keys`.
+///
+/// If stdin is a terminal, it is put into raw mode meanwhile, and its
+/// window size is propagated to `master` on `SIGWINCH`.
+pub fn proxy(master: &OwnedFd, detach_keys: &[u8]) -> Result<ProxyExit, Error> {
+    let master = master.as_raw_fd();
+    let _raw = RawMode::enter(STDIN)?;
+    copy_winsize(STDIN, master);
+
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    mask.thread_block()?;
+    let result = proxy_loop(master, detach_keys, &mask);
+    mask.thread_unblock()?;
+    result
+}
+
+fn proxy_loop(master: RawFd, detach_keys: &[u8], mask: &SigSet) -> Result<ProxyExit, Error> {
+    let mut sfd = SignalFd::with_flags(mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK)?;
+    let mut detach = DetachKeys::new(detach_keys);
+    let mut stdin_open = true;
+    let mut buf = [0; 4096];
+    loop {
+        let mut fds = vec![
+            PollFd::new(master, PollFlags::POLLIN),
+            PollFd::new(sfd.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        if stdin_open {
+            fds.push(PollFd::new(STDIN, PollFlags::POLLIN));
+        }
+        match poll(&mut fds, -1) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            r => r?,
+        };
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+
+        if ready(&fds[0]) {
+            // Reading fails with EIO once every slave fd is closed
+            match nix::unistd::read(master, &mut buf) {
+                Ok(0) | Err(nix::errno::Errno::EIO) => return Ok(ProxyExit::Exited),
+                Ok(n) => write_all(STDOUT, &buf[..n])?,
+                Err(nix::errno::Errno::EINTR) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if ready(&fds[1]) {
+            while sfd.read_signal()?.is_some() {}
+            copy_winsize(STDIN, master);
+        }
+        if stdin_open && ready(&fds[2]) {
+            match nix::unistd::read(STDIN, &mut buf)? {
+                0 => stdin_open = false,
+                n => {
+                    let mut out = Vec::with_capacity(n);
+                    let detached = detach.feed(&buf[..n], &mut out);
+                    write_all(master, &out)?;
+                    if detached {
+                        return Ok(ProxyExit::Detached);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Keep the terminal open in a background process after detaching, so
+/// the container does not get a hangup when the caller exits.
+///
+/// The output of the container is discarded.
+pub fn detach(master: OwnedFd) -> Result<(), Error> {
+    match unsafe { nix::unistd::fork() }? {
+        nix::unistd::ForkResult::Parent { .. } => Ok(()),
+        nix::unistd::ForkResult::Child => {
+            let _ = nix::unistd::setsid();
+            // Do not hold the stdio of the caller open
+            if let Ok(null) = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .open("/dev/null")
+            {
+                for fd in [STDIN, STDOUT, 2] {
+                    let _ = nix::unistd::dup2(null.as_raw_fd(), fd);
+                }
+            }
+            let master = master.into_raw_fd();
+            let mut buf = [0; 4096];
+            while let Ok(1..) | Err(nix::errno::Errno::EINTR) = nix::unistd::read(master, &mut buf)
+            {
+            }
+            unsafe { libc::_exit(0) }
+        }
+    }
+}
+
+fn write_all(fd: RawFd, mut buf: &[u8]) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match nix::unistd::write(fd, buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(nix::errno::Errno::EINTR) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Copy the window size of the terminal `from` to `to`, if `from` is one.
+fn copy_winsize(from: RawFd, to: RawFd) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(from, libc::TIOCGWINSZ, &mut size) } == 0 {
+        unsafe { libc::ioctl(to, libc::TIOCSWINSZ, &size) };
+    }
+}
+
+/// Raw mode of a terminal, restored on drop.
+struct RawMode {
+    fd: RawFd,
+    saved: Option<Termios>,
+}
+
+impl RawMode {
+    fn enter(fd: RawFd) -> Result<Self, Error> {
+        let saved = match nix::unistd::isatty(fd) {
+            Ok(true) => {
+                let saved = tcgetattr(fd)?;
+                let mut raw = saved.clone();
+                cfmakeraw(&mut raw);
+                tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+                Some(saved)
+            }
+            _ => None,
+        };
+        Ok(Self { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            let _ = tcsetattr(self.fd, SetArg::TCSANOW, saved);
+        }
+    }
+}
+
+/// Matcher of the detach key sequence in the input.
+///
+/// Bytes that may be part of the sequence are held back until it is
+/// known whether they are.
+struct DetachKeys<'a> {
+    keys: &'a [u8],
+    matched: usize,
+}
+
+impl<'a> DetachKeys<'a> {
+    fn new(keys: &'a [u8]) -> Self {
+        Self { keys, matched: 0 }
+    }
+
+    /// Append bytes of `input` to forward to `out`, returns `true` when
+    /// the whole sequence is typed.
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) -> bool {
+        for &b in input {
+            if self.keys.is_empty() {
+                out.push(b);
+                continue;
+            }
+            if self.matched > 0 && b != self.keys[self.matched] {
+                out.extend_from_slice(&self.keys[..self.matched]);
+                self.matched = 0;
+            }
+            if b == self.keys[self.matched] {
+                self.matched += 1;
+                if self.matched == self.keys.len() {
+                    return true;
+                }
+            } else {
+                out.push(b);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detach_keys() {
+        assert_eq!(parse_detach_keys(DEFAULT_DETACH_KEYS).unwrap(), [16, 17]);
+        assert_eq!(parse_detach_keys("ctrl-@,x,Ctrl-_").unwrap(), [0, b'x', 31]);
+        assert!(parse_detach_keys("").unwrap().is_empty());
+        assert!(parse_detach_keys("ctrl-1").is_err());
+        assert!(parse_detach_keys("ab").is_err());
+
+        let keys = parse_detach_keys(DEFAULT_DETACH_KEYS).unwrap();
+        let mut detach = DetachKeys::new(&keys);
+        let mut out = Vec::new();
+        assert!(!detach.feed(b"ls\x10", &mut out));
+        assert_eq!(out, b"ls");
+        // A held key is forwarded when the sequence breaks
+        assert!(!detach.feed(b"\x10x\x10", &mut out));
+        assert_eq!(out, b"ls\x10\x10x");
+        assert!(detach.feed(b"\x11", &mut out));
+        assert_eq!(out, b"ls\x10\x10x");
+
+        let mut detach = DetachKeys::new(&[]);
+        let mut out = Vec::new();
+        assert!(!detach.feed(b"\x10\x11", &mut out));
+        assert_eq!(out, b"\x10\x11");
+    }
+}
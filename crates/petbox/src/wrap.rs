@@ -0,0 +1,480 @@
+//! Command line front end of `petbox wrap`.
+//!
+//! The options are compatible with `bwrap(1)`, so scripts written for
+//! bubblewrap can run unchanged. Options are handled in the order they
+//! are given, just like bubblewrap does.
+
+use crate::error::Error;
+use crate::tty;
+use nswrap::{config, probe, util, Wrap};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+usage: petbox wrap [OPTIONS...] [--] COMMAND [ARGS...]
+
+    --help                       Print this help
+    --args FD                    Parse NUL-separated args from FD
+    --unshare-all                Unshare every namespace we support by default
+    --share-net                  Retain the network namespace (can only combine with --unshare-all)
+    --unshare-user               Create new user namespace
+    --unshare-user-try           Create new user namespace if possible else continue by skipping it
+    --unshare-ipc                Create new ipc namespace
+    --unshare-pid                Create new pid namespace
+    --unshare-net                Create new network namespace
+    --unshare-uts                Create new uts namespace
+    --unshare-cgroup             Create new cgroup namespace
+    --unshare-cgroup-try         Create new cgroup namespace if possible else continue by skipping it
+    --uid UID                    Custom uid in the sandbox (requires --unshare-user)
+    --gid GID                    Custom gid in the sandbox (requires --unshare-user)
+    --hostname NAME              Custom hostname in the sandbox (requires --unshare-uts)
+    --chdir DIR                  Change directory to DIR
+    --clearenv                   Unset all environment variables
+    --setenv VAR VALUE           Set an environment variable
+    --unsetenv VAR               Unset an environment variable
+    --bind SRC DEST              Bind mount the host path SRC on DEST
+    --bind-try SRC DEST          Equal to --bind but ignores non-existent SRC
+    --dev-bind SRC DEST          Bind mount the host path SRC on DEST, allowing device access
+    --dev-bind-try SRC DEST      Equal to --dev-bind but ignores non-existent SRC
+    --ro-bind SRC DEST           Bind mount the host path SRC readonly on DEST
+    --ro-bind-try SRC DEST       Equal to --ro-bind but ignores non-existent SRC
+    --proc DEST                  Mount new procfs on DEST
+    --dev DEST                   Mount new dev on DEST
+    --tmpfs DEST                 Mount new tmpfs on DEST
+    --dir DEST                   Create dir at DEST
+    --symlink SRC DEST           Create symlink at DEST with target SRC
+    --die-with-parent            Kills with SIGKILL child process (COMMAND) when bwrap or bwrap's parent dies.
+    --new-session                Create a new terminal session
+
+petbox extensions:
+    --tty                        Allocate a pseudo-terminal in the sandbox (requires --dev)
+    --detach-keys KEYS           Key sequence to detach from --tty, default ctrl-p,ctrl-q
+    --sysctl KEY VALUE           Set a kernel parameter of an unshared namespace, like net.ipv4.ping_group_range
+    --report                     Report how COMMAND exited and the resources it used
+    --dry-run                    Print the setup of the sandbox instead of running COMMAND
+    --json                       Print the setup of --dry-run as JSON
+";
+
+/// Options of `petbox wrap`.
+#[derive(Default, Debug)]
+pub struct WrapOptions {
+    /// `--help` is given
+    pub help: bool,
+    /// Namespaces to unshare
+    pub unshare: Vec<config::NamespaceType>,
+    /// Keep the network namespace, wherever `--share-net` is given
+    pub share_net: bool,
+    /// Mounts, directories and symlinks, in the order they were given
+    pub mounts: Vec<config::Mount>,
+    /// Start from an empty environment, see `Wrap::env_clear`
+    pub clear_env: bool,
+    /// Environment variables to set, or to unset if the value is `None`
+    pub env: Vec<(OsString, Option<OsString>)>,
+    pub chdir: Option<PathBuf>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub hostname: Option<OsString>,
+    /// Kernel parameters to set, see `Wrap::sysctl`
+    pub sysctls: Vec<(String, String)>,
+    pub die_with_parent: bool,
+    pub new_session: bool,
+    /// Proxy a pseudo-terminal of the sandbox to the terminal of the user
+    pub tty: bool,
+    /// Key sequence to detach from `tty`, see `tty::parse_detach_keys`
+    pub detach_keys: Option<Vec<u8>>,
+    /// Log the `nswrap::ExitStatus` of the command
+    pub report: bool,
+    /// Print the `nswrap::plan::Plan` of the sandbox instead of running it
+    pub dry_run: bool,
+    /// Print the plan of `dry_run` as JSON
+    pub json: bool,
+    /// The program and its arguments
+    pub command: Vec<OsString>,
+}
+
+/// Parse `bwrap(1)` compatible arguments.
+pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<WrapOptions, Error> {
+    use config::NamespaceType;
+
+    let mut args: VecDeque<OsString> = args.into_iter().collect();
+    let mut opts = WrapOptions::default();
+    while let Some(arg) = args.pop_front() {
+        // Options are ASCII, anything else is the command
+        let opt = arg.to_string_lossy().into_owned();
+        match opt.as_str() {
+            "--help" => {
+                opts.help = true;
+                return Ok(opts);
+            }
+            "--" => break,
+            "--args" => {
+                let fd: RawFd = parse_num(&opt, next_str(&mut args, &opt)?)?;
+                let mut buf = Vec::new();
+                File::from(borrow_fd(fd)?).read_to_end(&mut buf)?;
+                let mut parts: Vec<_> = buf.split(|b| *b == 0).collect();
+                // Arguments end with a NUL, empty ones are kept
+                if parts.last().is_some_and(|a| a.is_empty()) {
+                    parts.pop();
+                }
+                for a in parts.into_iter().rev() {
+                    args.push_front(OsStr::from_bytes(a).to_os_string());
+                }
+            }
+            // Like `--unshare-user-try` and `--unshare-cgroup-try` for
+            // these two, as bwrap does
+            "--unshare-all" => {
+                unshare_try(&mut opts.unshare, NamespaceType::User);
+                opts.unshare.extend([
+                    NamespaceType::Ipc,
+                    NamespaceType::Pid,
+                    NamespaceType::Network,
+                    NamespaceType::Uts,
+                ]);
+                unshare_try(&mut opts.unshare, NamespaceType::Cgroup);
+            }
+            "--share-net" => opts.share_net = true,
+            "--unshare-user" => opts.unshare.push(NamespaceType::User),
+            "--unshare-user-try" => unshare_try(&mut opts.unshare, NamespaceType::User),
+            "--unshare-ipc" => opts.unshare.push(NamespaceType::Ipc),
+            "--unshare-pid" => opts.unshare.push(NamespaceType::Pid),
+            "--unshare-net" => opts.unshare.push(NamespaceType::Network),
+            "--unshare-uts" => opts.unshare.push(NamespaceType::Uts),
+            "--unshare-cgroup" => opts.unshare.push(NamespaceType::Cgroup),
+            "--unshare-cgroup-try" => unshare_try(&mut opts.unshare, NamespaceType::Cgroup),
+            "--uid" => opts.uid = Some(parse_num(&opt, next_str(&mut args, &opt)?)?),
+            "--gid" => opts.gid = Some(parse_num(&opt, next_str(&mut args, &opt)?)?),
+            "--hostname" => opts.hostname = Some(next(&mut args, &opt)?),
+            "--sysctl" => {
+                let key = next_str(&mut args, &opt)?;
+                let value = next_str(&mut args, &opt)?;
+                opts.sysctls.push((key, value));
+            }
+            "--chdir" => opts.chdir = Some(next(&mut args, &opt)?.into()),
+            "--clearenv" => opts.clear_env = true,
+            "--setenv" => {
+                let var = next(&mut args, &opt)?;
+                let value = next(&mut args, &opt)?;
+                opts.env.push((var, Some(value)));
+            }
+            "--unsetenv" => opts.env.push((next(&mut args, &opt)?, None)),
+            "--bind" | "--bind-try" | "--dev-bind" | "--dev-bind-try" | "--ro-bind"
+            | "--ro-bind-try" => {
+                let src = next(&mut args, &opt)?;
+                let dest = next(&mut args, &opt)?;
+                if opt.ends_with("-try") && !Path::new(&src).exists() {
+                    continue;
+                }
+                let mut options = vec!["rbind"];
+                if !opt.starts_with("--dev-bind") {
+                    options.push("nodev");
+                }
+                if opt.starts_with("--ro-bind") {
+                    options.push("ro");
+                }
+                opts.mounts.push(mount(dest, Some(src), "bind", &options));
+            }
+            "--proc" => {
+                let dest = next(&mut args, &opt)?;
+                opts.mounts
+                    .push(mount(dest, None, "proc", &["nosuid", "nodev", "noexec"]));
+            }
+            "--dev" => opts
+                .mounts
+                .push(mount(next(&mut args, &opt)?, None, "dev", &[])),
+            "--tmpfs" => {
+                let dest = next(&mut args, &opt)?;
+                opts.mounts.push(mount(
+                    dest,
+                    None,
+                    "tmpfs",
+                    &["nosuid", "nodev", "mode=0755"],
+                ));
+            }
+            "--dir" => opts
+                .mounts
+                .push(mount(next(&mut args, &opt)?, None, "dir", &[])),
+            "--symlink" => {
+                let src = next(&mut args, &opt)?;
+                let dest = next(&mut args, &opt)?;
+                opts.mounts.push(mount(dest, Some(src), "symlink", &[]));
+            }
+            "--die-with-parent" => opts.die_with_parent = true,
+            "--new-session" => opts.new_session = true,
+            "--tty" => opts.tty = true,
+            "--report" => opts.report = true,
+            "--dry-run" => opts.dry_run = true,
+            "--json" => opts.json = true,
+            "--detach-keys" => {
+                opts.detach_keys = Some(tty::parse_detach_keys(&next_str(&mut args, &opt)?)?)
+            }
+            other if other.starts_with("--") => {
+                return Err(Error::InvalidArgument(format!("Unknown option {}", other)))
+            }
+            _ => {
+                opts.command.push(arg);
+                break;
+            }
+        }
+    }
+    opts.command.extend(args);
+    if opts.share_net {
+        opts.unshare.retain(|ns| *ns != NamespaceType::Network);
+    }
+    if opts.command.is_empty() {
+        return Err(Error::InvalidArgument("No command specified".into()));
+    }
+    Ok(opts)
+}
+
+impl WrapOptions {
+    /// Translate options into a `nswrap::Wrap`.
+    ///
+    /// Like unprivileged `bwrap`, a user namespace and a mount namespace
+    /// with a tmpfs root are always created.
+    pub fn build(&self) -> Wrap<'static> {
+        use config::NamespaceType;
+
+        let mut wrap = Wrap::new_cmd(&self.command[0]);
+        wrap.args(self.command[1..].iter().cloned())
+            .unshare(NamespaceType::User)
+            .unshare(NamespaceType::Mount)
+            .sandbox_mnt(true)
+            .uid_map(util::get_uid(), self.uid.unwrap_or(util::get_uid()), 1)
+            .gid_map(util::get_gid(), self.gid.unwrap_or(util::get_gid()), 1);
+        for ns in &self.unshare {
+            wrap.unshare(*ns);
+        }
+        for mnt in &self.mounts {
+            wrap.mount(mnt.clone());
+        }
+        if self.clear_env {
+            wrap.env_clear();
+        }
+        for (var, value) in &self.env {
+            match value {
+                Some(value) => wrap.env(var, value),
+                None => wrap.env_remove(var),
+            };
+        }
+        if let Some(dir) = &self.chdir {
+            wrap.current_dir(dir);
+        }
+        if let Some(hostname) = &self.hostname {
+            wrap.hostname(hostname);
+        }
+        for (key, value) in &self.sysctls {
+            wrap.sysctl(key, value);
+        }
+        if self.die_with_parent {
+            wrap.die_with_parent();
+        }
+        if self.new_session {
+            unsafe {
+                wrap.callback(|| {
+                    libc::setsid();
+                    0
+                })
+            };
+        }
+        wrap.tty(self.tty);
+        wrap
+    }
+}
+
+fn mount(dest: OsString, src: Option<OsString>, typ: &str, options: &[&str]) -> config::Mount {
+    let mut mnt = config::Mount::default();
+    mnt.set_destination(dest.into())
+        .set_source(src.map(PathBuf::from))
+        .set_typ(Some(typ.into()))
+        .set_options(Some(options.iter().map(|o| o.to_string()).collect()));
+    mnt
+}
+
+/// Unshare `ns` if the kernel and its settings allow it.
+fn unshare_try(unshare: &mut Vec<config::NamespaceType>, ns: config::NamespaceType) {
+    if probe::can_unshare(ns) {
+        unshare.push(ns);
+    }
+}
+
+fn next(args: &mut VecDeque<OsString>, opt: &str) -> Result<OsString, Error> {
+    args.pop_front()
+        .ok_or_else(|| Error::InvalidArgument(format!("{} takes more arguments", opt)))
+}
+
+/// Like `next`, for values which must be UTF-8, like numbers.
+fn next_str(args: &mut VecDeque<OsString>, opt: &str) -> Result<String, Error> {
+    next(args, opt)?.into_string().map_err(|a| {
+        Error::InvalidArgument(format!("Invalid UTF-8 in argument {:?} of {}", a, opt))
+    })
+}
+
+/// Duplicate the descriptor `fd` of `--args`, after checking it is open, so
+/// that a wrong number neither reads nor closes a descriptor owned by
+/// something else.
+fn borrow_fd(fd: RawFd) -> Result<std::os::fd::OwnedFd, Error> {
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid file descriptor {} of --args",
+            fd
+        )));
+    }
+    Ok(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?)
+}
+
+fn parse_num<T: std::str::FromStr>(opt: &str, value: String) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidArgument(format!("Invalid value {} of {}", value, opt)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &[&str]) -> Result<WrapOptions, Error> {
+        parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn mounts_in_order() {
+        let opts = parse_str(&[
+            "--ro-bind",
+            "/usr",
+            "/usr",
+            "--symlink",
+            "usr/lib",
+            "/lib",
+            "--proc",
+            "/proc",
+            "--dev",
+            "/dev",
+            "--tmpfs",
+            "/tmp",
+            "--dir",
+            "/run",
+            "/bin/sh",
+        ])
+        .unwrap();
+        let types: Vec<_> = opts
+            .mounts
+            .iter()
+            .map(|m| m.typ().clone().unwrap())
+            .collect();
+        assert_eq!(types, ["bind", "symlink", "proc", "dev", "tmpfs", "dir"]);
+        assert_eq!(
+            opts.mounts[0].options().as_deref().unwrap(),
+            ["rbind", "nodev", "ro"]
+        );
+        assert_eq!(
+            opts.mounts[1].source().as_deref(),
+            Some(Path::new("usr/lib"))
+        );
+        assert_eq!(opts.command, ["/bin/sh"]);
+    }
+
+    #[test]
+    fn command_after_separator() {
+        let opts = parse_str(&[
+            "--unshare-pid",
+            "--clearenv",
+            "--report",
+            "--dry-run",
+            "--setenv",
+            "A",
+            "1",
+            "--sysctl",
+            "kernel.msgmax",
+            "16384",
+            "--",
+            "--help",
+            "-x",
+        ])
+        .unwrap();
+        assert!(!opts.help);
+        assert!(opts.clear_env);
+        assert!(opts.report);
+        assert!(opts.dry_run);
+        assert_eq!(opts.unshare, [config::NamespaceType::Pid]);
+        assert_eq!(opts.env, [("A".into(), Some("1".into()))]);
+        assert_eq!(
+            opts.sysctls,
+            [("kernel.msgmax".to_string(), "16384".to_string())]
+        );
+        assert_eq!(opts.command, ["--help", "-x"]);
+    }
+
+    #[test]
+    fn args_from_fd() {
+        use std::io::Write;
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let mut w = unsafe { <File as std::os::fd::FromRawFd>::from_raw_fd(write_end) };
+        w.write_all(b"--chdir\0/tmp\0--hostname\0pet\0").unwrap();
+        drop(w);
+        let fd = read_end.to_string();
+        let opts = parse_str(&["--args", &fd, "true"]).unwrap();
+        assert_eq!(opts.chdir.as_deref(), Some(Path::new("/tmp")));
+        assert_eq!(opts.hostname.as_deref(), Some(OsStr::new("pet")));
+        assert_eq!(opts.command, ["true"]);
+
+        // Empty arguments are kept
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let mut w = unsafe { <File as std::os::fd::FromRawFd>::from_raw_fd(write_end) };
+        w.write_all(b"--setenv\0FOO\0\0--chdir\0/tmp\0true\0\0")
+            .unwrap();
+        drop(w);
+        let opts = parse_str(&["--args", &read_end.to_string()]).unwrap();
+        assert_eq!(opts.env, [("FOO".into(), Some("".into()))]);
+        assert_eq!(opts.chdir.as_deref(), Some(Path::new("/tmp")));
+        assert_eq!(opts.command, ["true", ""]);
+    }
+
+    #[test]
+    fn unshare_all() {
+        use config::NamespaceType;
+
+        let opts = parse_str(&["--share-net", "--unshare-all", "true"]).unwrap();
+        let mut expected = vec![];
+        unshare_try(&mut expected, NamespaceType::User);
+        expected.extend([NamespaceType::Ipc, NamespaceType::Pid, NamespaceType::Uts]);
+        unshare_try(&mut expected, NamespaceType::Cgroup);
+        assert_eq!(opts.unshare, expected);
+
+        let opts = parse_str(&["--unshare-net", "--share-net", "true"]).unwrap();
+        assert!(opts.unshare.is_empty());
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(parse_str(&["--bind", "/usr"]).is_err());
+        assert!(parse_str(&["--no-such-option", "true"]).is_err());
+        assert!(parse_str(&["--uid", "root", "true"]).is_err());
+        assert!(parse_str(&["--unshare-pid"]).is_err());
+        assert!(parse_str(&["--sysctl", "kernel.msgmax"]).is_err());
+        assert!(parse_str(&["--args", "-1", "true"]).is_err());
+    }
+
+    #[test]
+    fn arguments_not_utf8() {
+        let name = OsStr::from_bytes(b"caf\xe9");
+        let opts = parse([
+            "--setenv".into(),
+            name.into(),
+            name.into(),
+            "--hostname".into(),
+            name.into(),
+            "echo".into(),
+            name.into(),
+        ])
+        .unwrap();
+        assert_eq!(opts.env, [(name.into(), Some(name.into()))]);
+        assert_eq!(opts.hostname.as_deref(), Some(name));
+        assert_eq!(opts.command, [OsStr::new("echo"), name]);
+    }
+}